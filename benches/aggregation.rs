@@ -0,0 +1,51 @@
+// compares summing balances over the client map with the columnar layout.
+// run with `cargo bench --bench aggregation`
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use transaction_tool::{amount::Amount, columns::ClientColumns, types::Client};
+
+const CLIENTS: u16 = u16::MAX;
+const ROUNDS: u32 = 200;
+
+fn clients() -> HashMap<u16, Client> {
+    (0..CLIENTS)
+        .map(|id| {
+            let mut client = Client::new(id);
+            client.available = Amount::from_units(id as i64 * 50_000_000);
+            client.held = Amount::from_units(125_000_000);
+            client.total = client.available + client.held;
+            (id, client)
+        })
+        .collect()
+}
+
+fn time<F: FnMut() -> Amount>(mut f: F) -> (Duration, Amount) {
+    let start = Instant::now();
+    let mut sum = Amount::ZERO;
+    for _ in 0..ROUNDS {
+        sum = f();
+    }
+    (start.elapsed(), sum)
+}
+
+fn main() {
+    let clients = clients();
+    let columns = ClientColumns::from_clients(&clients);
+
+    let (map, map_sum) = time(|| {
+        clients.values().map(|c| c.total).sum::<Amount>()
+            + clients.values().map(|c| c.held).sum::<Amount>()
+    });
+
+    let (columnar, columnar_sum) =
+        time(|| columns.total.iter().sum::<Amount>() + columns.held.iter().sum::<Amount>());
+
+    assert_eq!(map_sum, columnar_sum);
+
+    println!("{} clients, {} rounds", CLIENTS, ROUNDS);
+    println!("hash map: {:?}", map);
+    println!("columnar: {:?}", columnar);
+}
@@ -0,0 +1,50 @@
+// compares row-by-row processing with the batched deposit fast path.
+// run with `cargo bench --bench deposits`
+use std::time::{Duration, Instant};
+
+use transaction_tool::{
+    amount::Amount,
+    processor,
+    types::{State, Transaction},
+};
+
+const ROWS: u32 = 1_000_000;
+const BATCH_SIZE: usize = 8192;
+
+fn deposits() -> Vec<Transaction> {
+    (1..=ROWS)
+        .map(|id| Transaction::deposit((id % 5000) as u16, id, Amount::from_units(150_000_000)))
+        .collect()
+}
+
+fn time<F: FnOnce() -> State>(f: F) -> (Duration, State) {
+    let start = Instant::now();
+    let state = f();
+    (start.elapsed(), state)
+}
+
+fn main() {
+    let (row_by_row, serial_state) = time(|| {
+        deposits()
+            .into_iter()
+            .fold(State::new(), processor::process_transaction)
+    });
+
+    let (batched, batched_state) = time(|| {
+        let mut txs = deposits().into_iter();
+        let mut state = State::new();
+        loop {
+            let batch: Vec<Transaction> = txs.by_ref().take(BATCH_SIZE).collect();
+            if batch.is_empty() {
+                return state;
+            }
+            state = processor::process_batch(state, batch);
+        }
+    });
+
+    assert_eq!(serial_state.clients, batched_state.clients);
+
+    println!("{} deposits", ROWS);
+    println!("row by row: {:?}", row_by_row);
+    println!("batched:    {:?}", batched);
+}
@@ -0,0 +1,69 @@
+// compares serial processing with client shards on a thread pool, over a synthetic file.
+// run with `cargo bench --features parallel --bench parallel`
+use std::{
+    env,
+    fs::{self, File},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use csv::{ReaderBuilder, Trim};
+use transaction_tool::{
+    generate::SyntheticInput,
+    parallel::{ShardedState, PARALLEL_BATCH_SIZE},
+    processor,
+    types::{State, Transaction},
+};
+
+const ROWS: u32 = 10_000_000;
+
+fn time<F: FnOnce() -> State>(f: F) -> (Duration, State) {
+    let start = Instant::now();
+    let state = f();
+    (start.elapsed(), state)
+}
+
+fn parallel(path: &Path, threads: usize) -> State {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_path(path)
+        .unwrap();
+    let mut sharded = ShardedState::new(State::new(), threads).unwrap();
+    let mut batch: Vec<Transaction> = Vec::with_capacity(PARALLEL_BATCH_SIZE);
+
+    for record in reader.deserialize() {
+        batch.push(record.unwrap());
+
+        if batch.len() == PARALLEL_BATCH_SIZE {
+            sharded.process(std::mem::take(&mut batch));
+        }
+    }
+    sharded.process(batch);
+
+    sharded.into_state()
+}
+
+fn main() {
+    let path = env::temp_dir().join(format!("parallel-bench-{}.csv", std::process::id()));
+    let input = SyntheticInput {
+        rows: ROWS,
+        clients: u16::MAX,
+        ..SyntheticInput::default()
+    };
+    input.write(File::create(&path).unwrap()).unwrap();
+
+    let (serial, serial_state) =
+        time(|| processor::process_transaction_file(&path, State::new()).unwrap());
+
+    println!("{} rows", ROWS);
+    println!("serial:    {:?}", serial);
+
+    for threads in [2, 4, 8] {
+        let (elapsed, state) = time(|| parallel(&path, threads));
+        assert_eq!(state.clients, serial_state.clients);
+
+        println!("{} threads: {:?}", threads, elapsed);
+    }
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,543 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign, Neg, Sub, SubAssign},
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::types::MAX_PRECISION;
+
+// units per whole currency unit
+const SCALE: i128 = 10i128.pow(MAX_PRECISION);
+
+// a money amount as a whole number of the smallest unit the ledger can track, i.e.
+// hundred-millionths, so balances add, subtract and compare exactly. EngineConfig::precision
+// decides how many of those decimal places are actually used. it is held in an i128, so while
+// a transaction's amount is kept within an i64, see within_transaction_limit, no number of
+// them added up can overflow a balance
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i128);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const ONE: Amount = Amount(SCALE);
+
+    pub const fn from_units(units: i64) -> Self {
+        Self(units as i128)
+    }
+
+    pub const fn units(self) -> i128 {
+        self.0
+    }
+
+    // the nearest float, for ratios and float-typed outputs. never fed back into balances
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    // amounts written as floats, e.g. `1e3`, arrive already parsed as floats. a float prints as
+    // the shortest decimal that reads back as the same float, which is the decimal that was
+    // written for any amount of up to 15 significant digits, so that is parsed instead of
+    // scaling the float and rounding
+    pub fn from_f64(value: f64) -> Result<Self, String> {
+        if !value.is_finite() {
+            return Err(format!("amount {} is not a finite number", value));
+        }
+
+        value.to_string().parse()
+    }
+
+    // self, if a transaction may carry it, i.e. at most i64::MAX units either side of zero,
+    // about 92 billion
+    pub fn within_transaction_limit(self) -> Result<Self, String> {
+        if self.0.unsigned_abs() > i64::MAX as u128 {
+            return Err(format!("amount {} is out of range", self));
+        }
+
+        Ok(self)
+    }
+
+    pub fn from_whole(value: i64) -> Result<Self, String> {
+        value
+            .checked_mul(SCALE as i64)
+            .map(Self::from_units)
+            .ok_or_else(|| format!("amount {} is out of range", value))
+    }
+
+    // the nearest amount with at most this many decimal places, halves rounding away from zero
+    pub fn round_to(self, precision: u32) -> Self {
+        self.round_excess_to(Excess::default(), precision, false)
+    }
+
+    // like round_to, but halves round to the nearest even last digit, so a run of them
+    // doesn't drift in one direction
+    pub fn round_half_even_to(self, precision: u32) -> Self {
+        self.round_excess_to(Excess::default(), precision, true)
+    }
+
+    // the nearest amount with at most this many decimal places to the one read as self and
+    // excess, see Amount::parse_with_excess. halves round to the even last digit if half_even
+    // is set, and away from zero otherwise. the digits as read are rounded once, so e.g.
+    // `1.000049999` rounds down to four places, where rounding it to eight first would give
+    // `1.00005` and round that up
+    pub fn round_excess_to(self, excess: Excess, precision: u32, half_even: bool) -> Self {
+        let step = 10i128.pow(MAX_PRECISION.saturating_sub(precision));
+        let remainder = self.0 % step;
+        let truncated = self.0 - remainder;
+
+        // the dropped part, in the twentieths of a unit the excess counts, against half a step.
+        // the excess only decides between digits the remainder leaves equal, since it is less
+        // than one unit
+        let dropped = remainder * 20 + excess.0 as i128;
+        let away = match dropped.abs().cmp(&(step * 10)) {
+            Ordering::Less => false,
+            Ordering::Greater => true,
+            Ordering::Equal => !half_even || (truncated / step) % 2 != 0,
+        };
+
+        if away {
+            Self(truncated + dropped.signum() * step)
+        } else {
+            Self(truncated)
+        }
+    }
+
+    // a plain decimal, e.g. `1.5`, `-0.25` or `.5`, to MAX_PRECISION places, with whatever it
+    // had past them as the Excess, so it can be rounded once to a coarser precision
+    pub fn parse_with_excess(s: &str) -> Result<(Self, Excess), String> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+            return Err(format!("invalid amount '{}'", s));
+        }
+
+        let out_of_range = || format!("amount {} is out of range", s);
+        let push_digit = |units: i128, digit: u8| {
+            units
+                .checked_mul(10)
+                .and_then(|u| u.checked_add((digit - b'0') as i128))
+                .ok_or_else(out_of_range)
+        };
+
+        let mut units = 0;
+        for digit in whole.bytes() {
+            units = push_digit(units, digit)?;
+        }
+
+        let precision = MAX_PRECISION as usize;
+        let fraction = fraction.as_bytes();
+        for i in 0..precision {
+            units = push_digit(units, fraction.get(i).copied().unwrap_or(b'0'))?;
+        }
+
+        // with room to round up a whole unit, the most rounding to any precision can add
+        units.checked_add(SCALE).ok_or_else(out_of_range)?;
+
+        let excess = match fraction.get(precision..) {
+            Some([first, rest @ ..]) => {
+                (first - b'0') as i8 * 2 + rest.iter().any(|&digit| digit != b'0') as i8
+            }
+            _ => 0,
+        };
+
+        Ok(match negative {
+            true => (Self(-units), Excess(-excess)),
+            false => (Self(units), Excess(excess)),
+        })
+    }
+}
+
+// what an amount had past MAX_PRECISION decimal places, which Amount can't hold, kept only as
+// far as rounding needs: twice the first of those digits, plus one if any digit after it isn't
+// zero, with the amount's sign. `1.000000005` leaves 10, exactly half a unit, and
+// `-0.0000000051` leaves -11 on an Amount of zero
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Excess(i8);
+
+// a plain decimal, see Amount::parse_with_excess. digits past MAX_PRECISION round the last unit
+// half away from zero
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (amount, excess) = Self::parse_with_excess(s)?;
+        Ok(amount.round_excess_to(excess, MAX_PRECISION, false))
+    }
+}
+
+// always at least one fractional digit and never trailing zeros past it, like f64 prints
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let whole = (self.0 / SCALE).unsigned_abs();
+        let fraction = format!(
+            "{:0width$}",
+            (self.0 % SCALE).unsigned_abs(),
+            width = MAX_PRECISION as usize
+        );
+        let fraction = match fraction.trim_end_matches('0') {
+            "" => "0",
+            trimmed => trimmed,
+        };
+
+        write!(f, "{}{}.{}", sign, whole, fraction)
+    }
+}
+
+// written as its exact decimal, e.g. `1.5`, so nothing is lost to a float on the way out or
+// in a saved State. json has it as a string, and types::clients_to_json turns a client's
+// amounts back into numbers for the json reports
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// how amounts are written in the input. Plain is a bare decimal like `1000.50`, and the other
+// formats also take a currency symbol and thousands separators, e.g. `$1,000.50` or
+// `1.000,50 €`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmountFormat {
+    Plain,
+    // `,` between thousands and `.` before the fraction
+    Us,
+    // `.` or a space between thousands and `,` before the fraction
+    European,
+}
+
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+impl AmountFormat {
+    // (decimal mark, thousands separators)
+    fn separators(self) -> (char, &'static [char]) {
+        match self {
+            AmountFormat::Plain => ('.', &[]),
+            AmountFormat::Us => ('.', &[',']),
+            AmountFormat::European => (',', &['.', ' ', '\u{a0}']),
+        }
+    }
+
+    // the amount as a plain decimal, e.g. `-1000.50` for `-$1,000.50`, without the currency
+    // symbol or thousands separators. None if it isn't written in this format. thousands must
+    // be grouped in threes, so a US `1,5` isn't read as fifteen
+    pub fn to_plain(self, s: &str) -> Option<String> {
+        let (decimal, thousands) = self.separators();
+        let is_symbol = |c: char| CURRENCY_SYMBOLS.contains(&c) || c.is_whitespace();
+
+        let text = s.trim();
+        let (mut negative, mut text) = match text.strip_prefix('-') {
+            Some(text) => (true, text),
+            None => (false, text),
+        };
+        if self != AmountFormat::Plain {
+            text = text.trim_matches(is_symbol);
+            if let Some(rest) = text.strip_prefix('-').filter(|_| !negative) {
+                (negative, text) = (true, rest);
+            }
+        }
+
+        let (whole, fraction) = match text.split_once(decimal) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (text, None),
+        };
+
+        let mut plain = String::with_capacity(text.len() + 1);
+        if negative {
+            plain.push('-');
+        }
+
+        if whole.contains(thousands) {
+            for (i, group) in whole.split(thousands).enumerate() {
+                let expected = if i == 0 { 1..=3 } else { 3..=3 };
+                if !expected.contains(&group.len()) || !group.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                plain.push_str(group);
+            }
+        } else {
+            plain.push_str(whole);
+        }
+
+        if let Some(fraction) = fraction {
+            plain.push('.');
+            plain.push_str(fraction);
+        }
+
+        Some(plain)
+    }
+
+    pub fn parse(self, s: &str) -> Result<Amount, String> {
+        self.to_plain(s)
+            .ok_or_else(|| format!("invalid amount '{}'", s))?
+            .parse()
+            .map_err(|_| format!("invalid amount '{}'", s))
+    }
+}
+
+impl FromStr for AmountFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(AmountFormat::Plain),
+            "us" => Ok(AmountFormat::Us),
+            "european" => Ok(AmountFormat::European),
+            _ => Err(format!("Unsupported amount format '{}'", s)),
+        }
+    }
+}
+
+// read back from either form, e.g. from a saved State. transaction rows have their own
+// reader in types.rs, which also copes with blank and malformed amounts
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl<'de> de::Visitor<'de> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an amount as a number or a string")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Amount, E> {
+                s.parse().map_err(E::custom)
+            }
+
+            fn visit_f64<E: de::Error>(self, f: f64) -> Result<Amount, E> {
+                Amount::from_f64(f).map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, i: i64) -> Result<Amount, E> {
+                Amount::from_whole(i).map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, u: u64) -> Result<Amount, E> {
+                i64::try_from(u)
+                    .map_err(|_| format!("amount {} is out of range", u))
+                    .and_then(Amount::from_whole)
+                    .map_err(E::custom)
+            }
+
+            // serde_json hands over a number that no float holds exactly as a map of one
+            // entry, whose value is the number's text
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Amount, A::Error> {
+                match map.next_entry::<String, String>()? {
+                    Some((_, text)) => text.parse().map_err(de::Error::custom),
+                    None => Err(de::Error::custom("expected an amount, found an empty map")),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+impl Add for Amount {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Self) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self(-self.0)
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Amount> for Amount {
+    fn sum<I: Iterator<Item = &'a Self>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!(amount("1.5"), Amount::from_units(150_000_000));
+        assert_eq!(amount("-0.25"), Amount::from_units(-25_000_000));
+        assert_eq!(amount(".5"), Amount::from_units(50_000_000));
+        assert_eq!(amount("7"), Amount::from_units(700_000_000));
+        assert_eq!(amount("1.5").to_string(), "1.5");
+        assert_eq!(amount("2").to_string(), "2.0");
+        assert_eq!(amount("-0.0001").to_string(), "-0.0001");
+        assert_eq!(amount("0.00000001").to_string(), "0.00000001");
+
+        assert!("".parse::<Amount>().is_err());
+        assert!("1.2.3".parse::<Amount>().is_err());
+        assert!("1e3".parse::<Amount>().is_err());
+        assert!("9999999999999999999999999999999".parse::<Amount>().is_err());
+        assert!(amount("99999999999999999999")
+            .within_transaction_limit()
+            .is_err());
+        assert!(amount("-92233720368.54775807")
+            .within_transaction_limit()
+            .is_ok());
+    }
+
+    #[test]
+    fn extra_digits_round_half_away_from_zero() {
+        assert_eq!(amount("1.000000004"), amount("1.0"));
+        assert_eq!(amount("1.000000005"), amount("1.00000001"));
+        assert_eq!(amount("-1.000000005"), amount("-1.00000001"));
+
+        assert_eq!(amount("1.00004").round_to(4), amount("1.0"));
+        assert_eq!(amount("1.00005").round_to(4), amount("1.0001"));
+        assert_eq!(amount("-1.00005").round_to(4), amount("-1.0001"));
+        assert_eq!(amount("2.5").round_to(0), amount("3"));
+        assert_eq!(amount("1.23456789").round_to(8), amount("1.23456789"));
+    }
+
+    #[test]
+    fn digits_past_max_precision_kept_for_one_rounding() {
+        let parsed = |s: &str| Amount::parse_with_excess(s).unwrap();
+
+        assert_eq!(parsed("1.000000001"), (amount("1.0"), Excess(2)));
+        assert_eq!(parsed("1.0000000050"), (amount("1.0"), Excess(10)));
+        assert_eq!(parsed("1.0000000051"), (amount("1.0"), Excess(11)));
+        assert_eq!(parsed("-0.000000009"), (amount("0"), Excess(-18)));
+        assert_eq!(parsed("1.5"), (amount("1.5"), Excess::default()));
+
+        let (units, excess) = parsed("1.000049999");
+        assert_eq!(units.round_excess_to(excess, 4, false), amount("1.0"));
+        let (units, excess) = parsed("1.000050001");
+        assert_eq!(units.round_excess_to(excess, 4, true), amount("1.0001"));
+        let (units, excess) = parsed("-1.000050001");
+        assert_eq!(units.round_excess_to(excess, 4, true), amount("-1.0001"));
+        let (units, excess) = parsed("1.0000000050");
+        assert_eq!(units.round_excess_to(excess, 8, true), amount("1.0"));
+        assert_eq!(
+            units.round_excess_to(excess, 8, false),
+            amount("1.00000001")
+        );
+    }
+
+    #[test]
+    fn half_even_rounds_ties_to_even_digit() {
+        assert_eq!(amount("1.00005").round_half_even_to(4), amount("1.0"));
+        assert_eq!(amount("1.00015").round_half_even_to(4), amount("1.0002"));
+        assert_eq!(amount("-1.00015").round_half_even_to(4), amount("-1.0002"));
+        assert_eq!(amount("1.000051").round_half_even_to(4), amount("1.0001"));
+        assert_eq!(amount("2.5").round_half_even_to(0), amount("2"));
+        assert_eq!(amount("3.5").round_half_even_to(0), amount("4"));
+        assert_eq!(
+            amount("1.23456789").round_half_even_to(8),
+            amount("1.23456789")
+        );
+    }
+
+    #[test]
+    fn formatted_amounts_parsed() {
+        assert_eq!(AmountFormat::Us.parse("$1,000.50"), Ok(amount("1000.5")));
+        assert_eq!(
+            AmountFormat::Us.parse("-$1,234,567"),
+            Ok(amount("-1234567"))
+        );
+        assert_eq!(AmountFormat::Us.parse("2.5"), Ok(amount("2.5")));
+        assert_eq!(
+            AmountFormat::European.parse("1.000,50"),
+            Ok(amount("1000.5"))
+        );
+        assert_eq!(
+            AmountFormat::European.parse("1 000,50 €"),
+            Ok(amount("1000.5"))
+        );
+        assert_eq!(AmountFormat::European.parse("-0,25"), Ok(amount("-0.25")));
+
+        assert!(AmountFormat::Us.parse("1,5").is_err());
+        assert!(AmountFormat::Us.parse("1,0000.5").is_err());
+        assert!(AmountFormat::European.parse("1,000.50").is_err());
+        assert!(AmountFormat::Plain.parse("$1,000.50").is_err());
+        assert!(AmountFormat::Plain.parse("1,000.50").is_err());
+        assert_eq!(AmountFormat::Plain.parse("1000.50"), Ok(amount("1000.5")));
+    }
+
+    #[test]
+    fn sums_are_exact() {
+        assert_eq!(amount("0.1") + amount("0.2"), amount("0.3"));
+        assert_eq!(
+            [amount("0.7"), -amount("0.05"), -amount("0.65")]
+                .iter()
+                .sum::<Amount>(),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn serialized_as_exact_decimal() {
+        let large = amount("90000000000.00000001");
+        let json = serde_json::to_string(&large).unwrap();
+
+        assert_eq!(json, "\"90000000000.00000001\"");
+        assert_eq!(serde_json::from_str::<Amount>(&json).unwrap(), large);
+    }
+
+    #[test]
+    fn long_json_numbers_read_exactly() {
+        // 19 significant digits, past what a float holds
+        let json = "92233720368.12345678";
+
+        assert_eq!(serde_json::from_str::<Amount>(json).unwrap(), amount(json));
+        assert_eq!(
+            serde_json::from_str::<Amount>("1.5").unwrap(),
+            amount("1.5")
+        );
+        assert_eq!(serde_json::from_str::<Amount>("-3").unwrap(), amount("-3"));
+    }
+
+    #[test]
+    fn floats_read_as_their_decimal() {
+        assert_eq!(Amount::from_f64(0.1).unwrap(), amount("0.1"));
+        assert_eq!(
+            Amount::from_f64(123456789.1234).unwrap(),
+            amount("123456789.1234")
+        );
+        assert!(Amount::from_f64(f64::INFINITY).is_err());
+        assert!(Amount::from_f64(1e300).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::types::{RejectReason, TransactionType};
+
+// what the processor did with one input row
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditOutcome {
+    Applied,
+    Rejected(RejectReason),
+    // an early dispute held until its deposit arrives, see EngineConfig::defer_early_disputes
+    Deferred,
+    // skipped without a rejection, for a client outside EngineConfig::only_clients
+    Filtered,
+}
+
+// one entry of the audit log, kept in State::audit_log when it is enabled
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub id: u32,
+    pub client_id: Option<u16>,
+    pub transaction_type: TransactionType,
+    pub outcome: AuditOutcome,
+}
+
+// written as a flat row, with the reject reason in its own column, so the log reads as
+// one csv with the same header on every line
+impl Serialize for AuditEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (outcome, reason) = match self.outcome {
+            AuditOutcome::Applied => ("applied", String::new()),
+            AuditOutcome::Rejected(reason) => ("rejected", reason.to_string()),
+            AuditOutcome::Deferred => ("deferred", String::new()),
+            AuditOutcome::Filtered => ("filtered", String::new()),
+        };
+
+        let mut s = serializer.serialize_struct("AuditEvent", 5)?;
+        s.serialize_field("tx", &self.id)?;
+        s.serialize_field("client", &self.client_id)?;
+        s.serialize_field("type", &self.transaction_type)?;
+        s.serialize_field("outcome", outcome)?;
+        s.serialize_field("reason", &reason)?;
+        s.end()
+    }
+}
+
+// counts of the rows processed, by what became of them, kept in State::stats. a deferred
+// dispute is only counted once it is finally applied or rejected
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub rows: u64,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub transfers: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    // applied freezes and thaws
+    pub admin: u64,
+    // rejected, filtered out, or not readable as a transaction
+    pub skipped: u64,
+    // of the skipped, those reusing the id of an identical earlier transaction
+    pub duplicates: u64,
+}
+
+impl Stats {
+    pub fn record(&mut self, transaction_type: TransactionType, outcome: AuditOutcome) {
+        if outcome == AuditOutcome::Rejected(RejectReason::DuplicateTransaction) {
+            self.duplicates += 1;
+        }
+
+        let count = match outcome {
+            AuditOutcome::Deferred => return,
+            AuditOutcome::Rejected(_) | AuditOutcome::Filtered => &mut self.skipped,
+            AuditOutcome::Applied => match transaction_type {
+                TransactionType::Deposit => &mut self.deposits,
+                TransactionType::Withdrawal => &mut self.withdrawals,
+                TransactionType::Transfer => &mut self.transfers,
+                TransactionType::Dispute => &mut self.disputes,
+                TransactionType::Resolve => &mut self.resolves,
+                TransactionType::Chargeback => &mut self.chargebacks,
+                TransactionType::Freeze | TransactionType::Thaw => &mut self.admin,
+            },
+        };
+
+        *count += 1;
+        self.rows += 1;
+    }
+
+    // a row that couldn't be read as a transaction, so never reached the processor
+    pub fn record_unreadable(&mut self) {
+        self.skipped += 1;
+        self.rows += 1;
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.rows += other.rows;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.transfers += other.transfers;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+        self.admin += other.admin;
+        self.skipped += other.skipped;
+        self.duplicates += other.duplicates;
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use crate::{amount::Amount, types::Client};
+
+// a struct-of-arrays copy of the client map, ordered by client id. scanning one balance
+// column at a time touches far less memory than walking a HashMap of whole clients, which
+// pays off when aggregating or writing out very large numbers of clients
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientColumns {
+    pub ids: Vec<u16>,
+    pub available: Vec<Amount>,
+    pub held: Vec<Amount>,
+    pub total: Vec<Amount>,
+    pub locked: Vec<bool>,
+    pub peak_total: Vec<Amount>,
+    pub escrow: Vec<Amount>,
+    pub transaction_count: Vec<u64>,
+    pub last_tx_id: Vec<Option<u32>>,
+    pub overdraft_limit: Vec<Amount>,
+    pub max_deposit: Vec<Amount>,
+    pub max_withdrawal: Vec<Amount>,
+}
+
+impl ClientColumns {
+    pub fn from_clients(clients: &HashMap<u16, Client>) -> Self {
+        let mut ids: Vec<u16> = clients.keys().copied().collect();
+        ids.sort_unstable();
+
+        let mut columns = Self {
+            available: Vec::with_capacity(ids.len()),
+            held: Vec::with_capacity(ids.len()),
+            total: Vec::with_capacity(ids.len()),
+            locked: Vec::with_capacity(ids.len()),
+            peak_total: Vec::with_capacity(ids.len()),
+            escrow: Vec::with_capacity(ids.len()),
+            transaction_count: Vec::with_capacity(ids.len()),
+            last_tx_id: Vec::with_capacity(ids.len()),
+            overdraft_limit: Vec::with_capacity(ids.len()),
+            max_deposit: Vec::with_capacity(ids.len()),
+            max_withdrawal: Vec::with_capacity(ids.len()),
+            ids: Vec::new(),
+        };
+
+        for id in &ids {
+            let client = &clients[id];
+            columns.available.push(client.available);
+            columns.held.push(client.held);
+            columns.total.push(client.total);
+            columns.locked.push(client.locked);
+            columns.peak_total.push(client.peak_total);
+            columns.escrow.push(client.escrow);
+            columns.transaction_count.push(client.transaction_count);
+            columns.last_tx_id.push(client.last_tx_id);
+            columns.overdraft_limit.push(client.overdraft_limit);
+            columns.max_deposit.push(client.max_deposit);
+            columns.max_withdrawal.push(client.max_withdrawal);
+        }
+        columns.ids = ids;
+
+        columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    // rebuilds the clients row by row, in id order, for output
+    pub fn rows(&self) -> impl Iterator<Item = Client> + '_ {
+        (0..self.len()).map(move |i| Client {
+            id: self.ids[i],
+            available: self.available[i],
+            held: self.held[i],
+            total: self.total[i],
+            locked: self.locked[i],
+            peak_total: self.peak_total[i],
+            escrow: self.escrow[i],
+            transaction_count: self.transaction_count[i],
+            last_tx_id: self.last_tx_id[i],
+            overdraft_limit: self.overdraft_limit[i],
+            max_deposit: self.max_deposit[i],
+            max_withdrawal: self.max_withdrawal[i],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_match_clients_in_id_order() {
+        let mut clients = HashMap::new();
+        for id in [9, 3, 5] {
+            let mut client = Client::new(id);
+            client.available = Amount::from_whole(id as i64).unwrap();
+            client.held = Amount::from_whole(1).unwrap();
+            client.total = client.available + client.held;
+            client.locked = id == 5;
+            clients.insert(id, client);
+        }
+
+        let columns = ClientColumns::from_clients(&clients);
+        let rows: Vec<Client> = columns.rows().collect();
+
+        assert_eq!(columns.ids, vec![3, 5, 9]);
+        assert_eq!(
+            columns.total,
+            [4, 6, 10].map(|total| Amount::from_whole(total).unwrap())
+        );
+        assert_eq!(rows.len(), 3);
+        for row in &rows {
+            assert_eq!(row, &clients[&row.id]);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::{error::Error, fmt, io};
+
+use csv::ErrorKind;
+
+// why processing a transaction file failed, so callers can tell a file that couldn't be
+// read from one with a bad row in it
+#[derive(Debug)]
+pub enum ProcessError {
+    Io(io::Error),
+    Csv(csv::Error),
+    // a row that couldn't be read as a transaction. line numbers start at 1 for the header
+    MalformedRow { line: usize, reason: String },
+    // the input had more distinct clients than EngineConfig::max_clients allows
+    TooManyClients { max: usize },
+    // the input was empty, without even a header row
+    MissingHeader,
+    // more of the input's deposits, withdrawals and transfers than
+    // EngineConfig::max_duplicate_fraction allows repeated earlier ones
+    LikelyDuplicateInput { duplicates: u64, rows: u64 },
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::Io(err) => write!(f, "{}", err),
+            ProcessError::Csv(err) => write!(f, "{}", err),
+            ProcessError::MalformedRow { line, reason } => write!(f, "line {}: {}", line, reason),
+            ProcessError::TooManyClients { max } => {
+                write!(f, "input has more than {} distinct clients", max)
+            }
+            ProcessError::MissingHeader => write!(f, "input is empty, expected a header row"),
+            ProcessError::LikelyDuplicateInput { duplicates, rows } => write!(
+                f,
+                "{} of {} deposits, withdrawals and transfers repeat earlier ones, so the input \
+                 was likely processed twice",
+                duplicates, rows
+            ),
+        }
+    }
+}
+
+impl Error for ProcessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProcessError::Io(err) => Some(err),
+            ProcessError::Csv(err) => Some(err),
+            ProcessError::MalformedRow { .. }
+            | ProcessError::TooManyClients { .. }
+            | ProcessError::MissingHeader
+            | ProcessError::LikelyDuplicateInput { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProcessError {
+    fn from(err: io::Error) -> Self {
+        ProcessError::Io(err)
+    }
+}
+
+// errors tied to a row of the input become MalformedRow, and io errors keep their own variant
+impl From<csv::Error> for ProcessError {
+    fn from(err: csv::Error) -> Self {
+        let line = err.position().map_or(0, |p| p.line() as usize);
+
+        let reason = match err.kind() {
+            ErrorKind::Deserialize { err, .. } => err.to_string(),
+            ErrorKind::UnequalLengths { .. } | ErrorKind::Utf8 { .. } => err.to_string(),
+            ErrorKind::Io(_) => match err.into_kind() {
+                ErrorKind::Io(err) => return ProcessError::Io(err),
+                _ => unreachable!(),
+            },
+            _ => return ProcessError::Csv(err),
+        };
+
+        ProcessError::MalformedRow { line, reason }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+    use crate::types::Transaction;
+
+    #[test]
+    fn csv_errors_classified() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nteleport, 1, 2, 1.0\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let err: ProcessError = reader
+            .deserialize::<Transaction>()
+            .find_map(Result::err)
+            .unwrap()
+            .into();
+
+        match err {
+            ProcessError::MalformedRow { line, .. } => assert_eq!(line, 3),
+            other => panic!("expected a malformed row, got {:?}", other),
+        }
+
+        let err: ProcessError = ReaderBuilder::new()
+            .from_path("test-data/does-not-exist.csv")
+            .unwrap_err()
+            .into();
+
+        assert!(matches!(err, ProcessError::Io(_)));
+    }
+}
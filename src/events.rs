@@ -0,0 +1,111 @@
+use std::{error::Error, fs::File, path::Path};
+
+use csv::Writer;
+use transaction_tool::{
+    error::ProcessError,
+    input::{InputReader, TransactionRows},
+    processor,
+    types::{State, Transaction},
+};
+
+// an append-only log of every transaction the processor applied, in order. since rejected
+// transactions are left out, replaying the log with the same options rebuilds the same state
+pub struct EventLog {
+    writer: Writer<File>,
+}
+
+impl EventLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            writer: Writer::from_path(path)?,
+        })
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+// processes a transaction, appending it to the event log if one is open and the
+// transaction was applied
+pub fn apply(
+    state: State,
+    transaction: Transaction,
+    events: &mut Option<EventLog>,
+) -> Result<State, ProcessError> {
+    let events = match events {
+        Some(events) => events,
+        None => return Ok(processor::process_transaction(state, transaction)),
+    };
+
+    let event = transaction.clone();
+    let rejected = state.rejected;
+    let state = processor::process_transaction(state, transaction);
+
+    if state.rejected == rejected {
+        events.writer.serialize(&event)?;
+    }
+
+    Ok(state)
+}
+
+// rebuilds state from an event log. every event was applied when it was recorded, so
+// one being rejected now means the log or the processing options don't match the original run,
+// and so does one that can't be read. the log is always written as plain csv, whatever the
+// input options
+pub fn replay_events(path: &str, state: State) -> Result<State, Box<dyn Error>> {
+    let mut rows = TransactionRows::open(&InputReader::default(), path)?;
+
+    rows.try_fold(state, |s, r| {
+        let transaction = r.map_err(ProcessError::from)?;
+        let id = transaction.id;
+        let rejected = s.rejected;
+        let s = processor::process_transaction(s, transaction);
+
+        if s.rejected > rejected {
+            let reason = s
+                .recent_rejects()
+                .last()
+                .map_or(String::from("transaction rejected"), |(_, r)| r.to_string());
+            return Err(format!(
+                "event for transaction {} was rejected on replay: {}",
+                id, reason
+            )
+            .into());
+        }
+
+        Ok(s)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn replayed_event_log_matches_state() {
+        let log_path = env::temp_dir().join(format!("events-{}.csv", std::process::id()));
+        let mut events = Some(EventLog::create(&log_path).unwrap());
+
+        let rows = TransactionRows::open(&InputReader::default(), "test-data/test.csv").unwrap();
+
+        let mut state = State::new();
+        for record in rows {
+            state = apply(state, record.unwrap(), &mut events).unwrap();
+        }
+        events.unwrap().flush().unwrap();
+
+        let log = log_path.to_string_lossy().to_string();
+        let replayed = replay_events(&log, State::new()).unwrap();
+
+        assert_eq!(replayed.clients, state.clients);
+        assert_eq!(replayed.transfers, state.transfers);
+        assert_eq!(replayed.rejected, 0);
+
+        std::fs::remove_file(log_path).unwrap();
+    }
+}
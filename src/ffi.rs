@@ -0,0 +1,100 @@
+use std::{ffi::CString, os::raw::c_char, ptr, slice};
+
+use crate::{
+    processor,
+    types::{self, Client, State},
+};
+
+/// Processes a JSON array of transactions, e.g.
+/// `[{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}]`, and returns the resulting
+/// client report as a NUL-terminated JSON array of clients ordered by client id.
+///
+/// Memory ownership: the input buffer stays owned by the caller and is only read during the
+/// call. The returned string is allocated by this library and owned by the caller from then on,
+/// and must be released with `free_report_json` exactly once, never with the host's own `free`.
+/// A null pointer is returned, with nothing to free, if the input is not a valid JSON array of
+/// transactions.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn process_transactions_json(ptr: *const u8, len: usize) -> *mut c_char {
+    if ptr.is_null() {
+        return ptr::null_mut();
+    }
+
+    let input = slice::from_raw_parts(ptr, len);
+
+    match process_json(input).map(CString::new) {
+        Ok(Ok(report)) => report.into_raw(),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Releases a report returned by `process_transactions_json`. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `report` must be null or a pointer returned by `process_transactions_json` that has not
+/// already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn free_report_json(report: *mut c_char) {
+    if !report.is_null() {
+        drop(CString::from_raw(report));
+    }
+}
+
+fn process_json(input: &[u8]) -> Result<String, serde_json::Error> {
+    let transactions = types::transactions_from_json(input)?;
+    let state = transactions
+        .into_iter()
+        .fold(State::new(), processor::process_transaction);
+
+    let mut clients: Vec<&Client> = state.clients.values().collect();
+    clients.sort_by_key(|c| c.id);
+
+    serde_json::to_string(&types::clients_to_json(clients)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    #[test]
+    fn process_and_free_report() {
+        let input = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": 2.5},
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": "1.0"},
+            {"type": "deposit", "client": 2, "tx": 3, "amount": 4},
+            {"type": "dispute", "client": 2, "tx": 3}
+        ]"#;
+
+        unsafe {
+            let report = process_transactions_json(input.as_ptr(), input.len());
+            assert!(!report.is_null());
+
+            let json = CStr::from_ptr(report).to_str().unwrap().to_owned();
+            free_report_json(report);
+
+            let clients: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(clients[0]["client"], 1);
+            assert_eq!(clients[0]["available"], 1.5);
+            assert_eq!(clients[1]["held"], 4.0);
+            assert_eq!(clients[1]["available"], 0.0);
+        }
+    }
+
+    #[test]
+    fn invalid_input_returns_null() {
+        let input = "not json";
+
+        unsafe {
+            assert!(process_transactions_json(input.as_ptr(), input.len()).is_null());
+            free_report_json(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufWriter, Write},
+};
+
+// the share of rows that try to be withdrawals, when they aren't disputes
+const WITHDRAWAL_RATE: f64 = 0.2;
+// how many rows after a dispute it is resolved
+const RESOLVE_AFTER: u32 = 100;
+// deposits and withdrawals are between 0.01 and this, in hundredths
+const MAX_CENTS: u64 = 50_000;
+
+// a synthetic input file of `rows` transactions over clients 1 to `clients`, for benchmarks
+// and stress tests. it is mostly deposits, with withdrawals and disputes mixed in, and every
+// row is one the engine applies under the default config. a withdrawal or a dispute the
+// client's available funds couldn't cover is written as a deposit instead, each deposit is
+// disputed at most once, and each dispute is resolved `RESOLVE_AFTER` rows later, or left open
+// at the end of the file. the same seed always gives the same file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticInput {
+    pub rows: u32,
+    // at least 1
+    pub clients: u16,
+    // the share of rows, between 0 and 1, that dispute an earlier deposit
+    pub dispute_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for SyntheticInput {
+    fn default() -> Self {
+        Self {
+            rows: 1000,
+            clients: 100,
+            dispute_rate: 0.01,
+            seed: 1,
+        }
+    }
+}
+
+// what the generator has written for one client so far, in hundredths
+#[derive(Default)]
+struct ClientFunds {
+    available: u64,
+    // the latest deposit not yet disputed, with its amount
+    last_deposit: Option<(u32, u64)>,
+}
+
+struct OpenDispute {
+    client_id: u16,
+    tx_id: u32,
+    cents: u64,
+    row: u32,
+}
+
+impl SyntheticInput {
+    // writes the file as csv, header first, in the same `type, client, tx, amount` layout the
+    // processor reads
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        let mut rng = Rng::new(self.seed);
+        let mut funds: HashMap<u16, ClientFunds> = HashMap::new();
+        let mut open_disputes: VecDeque<OpenDispute> = VecDeque::new();
+
+        writeln!(writer, "type, client, tx, amount")?;
+
+        for row in 1..=self.rows {
+            if matches!(open_disputes.front(), Some(d) if row - d.row >= RESOLVE_AFTER) {
+                let dispute = open_disputes.pop_front().unwrap();
+                let client = funds.entry(dispute.client_id).or_default();
+                client.available += dispute.cents;
+
+                writeln!(writer, "resolve, {}, {},", dispute.client_id, dispute.tx_id)?;
+                continue;
+            }
+
+            let roll = rng.chance();
+            let client_id = rng.below(u64::from(self.clients)) as u16 + 1;
+            let client = funds.entry(client_id).or_default();
+
+            if roll < self.dispute_rate {
+                if let Some((tx_id, cents)) = client.last_deposit {
+                    if client.available >= cents {
+                        client.available -= cents;
+                        client.last_deposit = None;
+                        open_disputes.push_back(OpenDispute {
+                            client_id,
+                            tx_id,
+                            cents,
+                            row,
+                        });
+
+                        writeln!(writer, "dispute, {}, {},", client_id, tx_id)?;
+                        continue;
+                    }
+                }
+            }
+
+            let cents = rng.below(MAX_CENTS) + 1;
+
+            if roll < self.dispute_rate + WITHDRAWAL_RATE && client.available >= cents {
+                client.available -= cents;
+
+                writeln!(
+                    writer,
+                    "withdrawal, {}, {}, {}",
+                    client_id,
+                    row,
+                    decimal(cents)
+                )?;
+                continue;
+            }
+
+            client.available += cents;
+            client.last_deposit = Some((row, cents));
+
+            writeln!(
+                writer,
+                "deposit, {}, {}, {}",
+                client_id,
+                row,
+                decimal(cents)
+            )?;
+        }
+
+        writer.flush()
+    }
+}
+
+// e.g. `12.05` for 1205
+fn decimal(cents: u64) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+// xorshift64*, which is plenty for picking rows, and keeps the generator free of dependencies
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves zero, so a zero state is nudged off it
+        Self(seed.wrapping_add(0x9E37_79B9_7F4A_7C15).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // from 0 up to, but not including, `n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    // between 0 and 1
+    fn chance(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        processor::process_batch,
+        types::{State, Transaction},
+    };
+    use csv::{ReaderBuilder, Trim};
+
+    #[test]
+    fn synthetic_input_processes_without_rejects() {
+        let input = SyntheticInput {
+            rows: 5000,
+            clients: 20,
+            dispute_rate: 0.05,
+            seed: 7,
+        };
+        let mut bytes = Vec::new();
+        input.write(&mut bytes).unwrap();
+
+        let mut again = Vec::new();
+        input.write(&mut again).unwrap();
+        assert_eq!(bytes, again);
+
+        let transactions: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(bytes.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let state = process_batch(State::new(), transactions);
+
+        assert_eq!(state.stats.rows, 5000);
+        assert_eq!(state.stats.skipped, 0);
+        assert_eq!(state.rejected, 0);
+        assert!(state.stats.withdrawals > 0);
+        assert!(state.stats.disputes > 0);
+        assert!(state.stats.resolves > 0);
+        assert_eq!(state.clients.len(), 20);
+        assert!(state.verify_invariants().is_ok());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+
+use crate::{amount::AmountFormat, error::ProcessError, types::Transaction};
+
+// the input path that reads transactions from stdin
+pub const STDIN_PATH: &str = "-";
+
+// the settings shared by every input file: how the csv is read, and how its amounts are written
+pub struct InputReader {
+    csv: ReaderBuilder,
+    pub amounts: AmountFormat,
+}
+
+// rows may be longer or shorter than the header, see TransactionRows. a utf-8 byte order mark
+// at the start of a file is always skipped by the csv reader
+impl Default for InputReader {
+    fn default() -> Self {
+        let mut csv = ReaderBuilder::new();
+        csv.trim(Trim::All).flexible(true);
+
+        Self {
+            csv,
+            amounts: AmountFormat::Plain,
+        }
+    }
+}
+
+impl InputReader {
+    // the field separator, a comma unless set
+    pub fn delimiter(&mut self, delimiter: u8) -> &mut Self {
+        self.csv.delimiter(delimiter);
+        self
+    }
+}
+
+// the transactions of one input, row by row. fields past the header are ignored, and a row
+// shorter than the header is padded with blank fields, so a dispute can leave out its amount
+pub struct TransactionRows {
+    reader: Reader<Box<dyn Read>>,
+    headers: StringRecord,
+    record: StringRecord,
+    amounts: AmountFormat,
+}
+
+impl TransactionRows {
+    // the rows of the file at `path`, or of stdin when the path is STDIN_PATH
+    pub fn open(input: &InputReader, path: &str) -> Result<Self, ProcessError> {
+        let source: Box<dyn Read> = match path {
+            STDIN_PATH => Box::new(io::stdin()),
+            path => Box::new(File::open(path)?),
+        };
+
+        Self::from_reader(input, source)
+    }
+
+    // a header with no rows after it is an input without transactions, but one with no
+    // header at all, e.g. a zero-byte file, is refused
+    pub fn from_reader(input: &InputReader, source: Box<dyn Read>) -> Result<Self, ProcessError> {
+        let mut reader = input.csv.from_reader(source);
+        let headers = reader.headers()?.clone();
+        if headers.is_empty() {
+            return Err(ProcessError::MissingHeader);
+        }
+
+        Ok(Self {
+            reader,
+            headers,
+            record: StringRecord::new(),
+            amounts: input.amounts,
+        })
+    }
+
+    // the line the last row read started on. the header is line 1
+    pub fn line(&self) -> u64 {
+        self.record.position().map_or(0, |position| position.line())
+    }
+}
+
+impl Iterator for TransactionRows {
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => return Some(Err(err)),
+        }
+
+        prepare_record(&mut self.record, &self.headers, self.amounts);
+        Some(self.record.deserialize(Some(&self.headers)))
+    }
+}
+
+// pads the record out to the header's length, and rewrites a formatted amount, e.g.
+// `$1,000.50`, as the plain decimal the transaction reader expects. an amount that isn't in
+// the format is left as it is, for the reader to report
+fn prepare_record(record: &mut StringRecord, headers: &StringRecord, amounts: AmountFormat) {
+    for _ in record.len()..headers.len() {
+        record.push_field("");
+    }
+
+    if amounts == AmountFormat::Plain {
+        return;
+    }
+
+    let Some(column) = headers.iter().position(|header| header == "amount") else {
+        return;
+    };
+    let Some(plain) = amounts.to_plain(&record[column]) else {
+        return;
+    };
+
+    let position = record.position().cloned();
+    let mut fields: StringRecord = record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| if i == column { plain.as_str() } else { field })
+        .collect();
+    fields.set_position(position);
+    *record = fields;
+}
+
+// where a row that couldn't be read as a transaction was, e.g. `line 3 of input.csv`, and
+// why, so it can be skipped with a warning rather than failing the run. an error reading the
+// input itself still fails it
+pub fn unreadable_row(path: &str, err: csv::Error) -> Result<(String, String), ProcessError> {
+    match ProcessError::from(err) {
+        ProcessError::MalformedRow { line, reason } => {
+            let source = if path == STDIN_PATH { "stdin" } else { path };
+            Ok((format!("line {} of {}", line, source), reason))
+        }
+        err => Err(err),
+    }
+}
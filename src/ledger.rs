@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use crate::types::Transaction;
+
+// the ledger a State uses by default, with every transaction in memory
+pub type InMemoryLedger = HashMap<u32, Transaction>;
+
+/// Where a `State` keeps the deposits, withdrawals and transfers it has applied, by id, so
+/// later disputes can find them. `State` uses a `HashMap` unless it is built over another
+/// ledger with `State::with_ledger`, e.g. one backed by a store on disk for ledgers too big
+/// to keep in memory. The processors only reach the transactions through these methods,
+/// which behave as the `HashMap` methods of the same name do.
+pub trait Ledger {
+    fn get(&self, tx_id: &u32) -> Option<&Transaction>;
+
+    fn get_mut(&mut self, tx_id: &u32) -> Option<&mut Transaction>;
+
+    /// Stores `transaction` under `tx_id`, returning the one it replaced, if any.
+    fn insert(&mut self, tx_id: u32, transaction: Transaction) -> Option<Transaction>;
+
+    fn contains_key(&self, tx_id: &u32) -> bool;
+
+    /// Removes the transaction under `tx_id`, e.g. one charged back in streaming mode, or
+    /// one applied by a row that is rolled back.
+    fn remove(&mut self, tx_id: &u32) -> Option<Transaction>;
+
+    /// A hint that about `additional` more transactions are about to be inserted. It does
+    /// nothing unless the ledger can use it.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+impl Ledger for InMemoryLedger {
+    fn get(&self, tx_id: &u32) -> Option<&Transaction> {
+        HashMap::get(self, tx_id)
+    }
+
+    fn get_mut(&mut self, tx_id: &u32) -> Option<&mut Transaction> {
+        HashMap::get_mut(self, tx_id)
+    }
+
+    fn insert(&mut self, tx_id: u32, transaction: Transaction) -> Option<Transaction> {
+        HashMap::insert(self, tx_id, transaction)
+    }
+
+    fn contains_key(&self, tx_id: &u32) -> bool {
+        HashMap::contains_key(self, tx_id)
+    }
+
+    fn remove(&mut self, tx_id: &u32) -> Option<Transaction> {
+        HashMap::remove(self, tx_id)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        HashMap::reserve(self, additional)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        amount::Amount,
+        processor::{process_batch, process_transaction},
+        types::{State, TransactionStatus},
+    };
+
+    // a ledger kept in a sorted map instead, standing in for any other store
+    #[derive(Default)]
+    struct SortedLedger(BTreeMap<u32, Transaction>);
+
+    impl Ledger for SortedLedger {
+        fn get(&self, tx_id: &u32) -> Option<&Transaction> {
+            self.0.get(tx_id)
+        }
+
+        fn get_mut(&mut self, tx_id: &u32) -> Option<&mut Transaction> {
+            self.0.get_mut(tx_id)
+        }
+
+        fn insert(&mut self, tx_id: u32, transaction: Transaction) -> Option<Transaction> {
+            self.0.insert(tx_id, transaction)
+        }
+
+        fn contains_key(&self, tx_id: &u32) -> bool {
+            self.0.contains_key(tx_id)
+        }
+
+        fn remove(&mut self, tx_id: &u32) -> Option<Transaction> {
+            self.0.remove(tx_id)
+        }
+    }
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn state_processes_over_another_ledger() {
+        let transactions = vec![
+            Transaction::deposit(1, 1, amount("5.0")),
+            Transaction::deposit(2, 2, amount("3.0")),
+            Transaction::withdrawal(1, 3, amount("1.0")),
+            Transaction::deposit(1, 1, amount("5.0")),
+            Transaction::dispute(2, 2),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1),
+        ];
+
+        let mut state = process_batch(
+            State::with_ledger(SortedLedger::default()),
+            transactions.clone(),
+        );
+        state = process_transaction(state, Transaction::chargeback(2, 2));
+        let expected = process_transaction(
+            process_batch(State::new(), transactions),
+            Transaction::chargeback(2, 2),
+        );
+
+        assert_eq!(state.clients, expected.clients);
+        assert_eq!(state.rejected, 1);
+        assert_eq!(state.transfers.0.len(), 3);
+        assert_eq!(state.transfers.0[&2].status, TransactionStatus::ChargedBack);
+        assert!(state.clients[&2].locked);
+    }
+}
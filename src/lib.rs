@@ -0,0 +1,51 @@
+//! The transaction processing engine, shared by the command-line tool and the C ABI in `ffi`.
+//!
+//! Build a `State`, then feed it transactions with `process_transaction`, or a whole CSV file
+//! with `process_transaction_file`. The state's `clients` then hold the balances.
+//! `Transaction::deposit`, `Transaction::dispute` and the like build transactions in code.
+//! `State::apply` applies one transaction in place instead, and returns whether it was
+//! applied, with the updated client, or why it was rejected.
+//! `process_transactions` streams rows from any iterator instead, keeping only what can still
+//! be disputed. `input::TransactionRows` reads the rows of a CSV input as the command line, its
+//! HTTP server and `process_transaction_file` all do, tolerating ragged rows, and `input::unreadable_row` says
+//! where and why a row it couldn't read was, so it can be skipped with a warning. The engine
+//! keeps these invariants:
+//!
+//! - a client's `total` is always `available + held`, and `held` is never negative. `escrow`
+//!   is kept apart from both. `State::verify_invariants` checks these
+//! - a transaction breaking a rule is rejected and changes no balance
+//! - a locked client rejects every later transaction, apart from admin freeze and thaw rows,
+//!   and deposits, which are credited when `EngineConfig::deposits_allowed_when_locked` is
+//!   set, or held in escrow when `EngineConfig::escrow_locked_deposits` is
+//! - deposit and withdrawal ids are unique. disputes, resolves and chargebacks refer to them
+//!   by id, and must come from the same client
+//! - a withdrawal of more than the client's `available` plus its `overdraft_limit` is rejected
+//! - amounts are exact to `EngineConfig::precision` decimal places, four by default, see
+//!   `amount::Amount`. `EngineConfig::excess_decimals` says whether one with more is rounded
+//!   or rejected
+//!
+//! Every processing policy lives in `EngineConfig`, whose defaults give the strict rules
+//! above. Pass one to `State::with_config` to process with other rules.
+//!
+//! A `State` keeps the transactions it has applied in a `HashMap` by default. To keep them
+//! somewhere else, e.g. on disk, implement `Ledger` for the store and build the state with
+//! `State::with_ledger`.
+
+pub mod amount;
+pub mod audit;
+pub mod columns;
+pub mod error;
+pub mod ffi;
+pub mod generate;
+pub mod input;
+pub mod ledger;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod processor;
+pub mod timestamp;
+pub mod types;
+
+pub use error::ProcessError;
+pub use ledger::Ledger;
+pub use processor::{process_transaction, process_transaction_file, process_transactions};
+pub use types::{Client, EngineConfig, State, Transaction, TransactionType};
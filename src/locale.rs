@@ -0,0 +1,123 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use transaction_tool::{amount::Amount, types::Client};
+
+// locales whose number formatting can be applied to the output balances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale {
+    EnUs,
+    DeDe,
+    FrFr,
+}
+
+impl Locale {
+    // (decimal mark, grouping separator)
+    fn separators(self) -> (char, char) {
+        match self {
+            Locale::EnUs => ('.', ','),
+            Locale::DeDe => (',', '.'),
+            Locale::FrFr => (',', ' '),
+        }
+    }
+
+    // swaps in the locale's decimal mark, grouping the whole part in thousands
+    pub fn format_amount(self, value: Amount) -> String {
+        let (decimal, grouping) = self.separators();
+
+        let formatted = value.to_string();
+        let (whole, fraction) = formatted.split_once('.').unwrap();
+
+        let mut result = String::new();
+        let whole = match whole.strip_prefix('-') {
+            Some(digits) => {
+                result.push('-');
+                digits
+            }
+            None => whole,
+        };
+
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                result.push(grouping);
+            }
+            result.push(digit);
+        }
+
+        result.push(decimal);
+        result.push_str(fraction);
+
+        result
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('_', "-").as_str() {
+            "en-us" => Ok(Locale::EnUs),
+            "de-de" => Ok(Locale::DeDe),
+            "fr-fr" => Ok(Locale::FrFr),
+            _ => Err(format!("Unsupported locale '{}'", s)),
+        }
+    }
+}
+
+// a client row with its balances rendered for display in a locale
+#[derive(Serialize, Debug)]
+pub struct LocalizedClient {
+    client: u16,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    peak_total: String,
+    escrow: String,
+    transaction_count: u64,
+    last_tx_id: Option<u32>,
+}
+
+impl LocalizedClient {
+    pub fn new(client: &Client, locale: Locale) -> Self {
+        Self {
+            client: client.id,
+            available: locale.format_amount(client.available),
+            held: locale.format_amount(client.held),
+            total: locale.format_amount(client.total),
+            locked: client.locked,
+            peak_total: locale.format_amount(client.peak_total),
+            escrow: locale.format_amount(client.escrow),
+            transaction_count: client.transaction_count,
+            last_tx_id: client.last_tx_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn de_de_versus_en_us() {
+        assert_eq!(Locale::DeDe.format_amount(amount("1234.56")), "1.234,56");
+        assert_eq!(Locale::EnUs.format_amount(amount("1234.56")), "1,234.56");
+        assert_eq!(
+            Locale::DeDe.format_amount(amount("-1234567.0")),
+            "-1.234.567,0"
+        );
+        assert_eq!(Locale::EnUs.format_amount(amount("0.1234")), "0.1234");
+    }
+
+    #[test]
+    fn parse_locale() {
+        assert_eq!("de-DE".parse::<Locale>().unwrap(), Locale::DeDe);
+        assert_eq!("en_us".parse::<Locale>().unwrap(), Locale::EnUs);
+        assert!("xx-XX".parse::<Locale>().is_err());
+    }
+}
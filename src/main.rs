@@ -1,47 +1,2736 @@
-use std::{collections::HashMap, env, error::Error, io, process};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    process,
+    str::FromStr,
+};
 
-use csv::{ReaderBuilder, Trim, Writer};
-use types::{Client, State};
+use csv::Writer;
+use events::EventLog;
+use locale::{Locale, LocalizedClient};
+use merge::MergedTransactions;
+use output_columns::{OutputColumn, SelectedClient, EXTENDED_COLUMNS};
+use progress::Progress;
+use rejects::RejectsFile;
+use snapshots::Snapshots;
+use transaction_tool::{
+    amount::{Amount, AmountFormat},
+    audit::AuditEvent,
+    columns::ClientColumns,
+    error::ProcessError,
+    generate::SyntheticInput,
+    input::{unreadable_row, InputReader, TransactionRows, STDIN_PATH},
+    processor::{self, BATCH_SIZE},
+    types::{
+        self, Client, EngineConfig, ErrorBudget, ExcessDecimals, MissingClientPolicy, RejectReason,
+        ShadowLedger, State, Transaction, TransactionType, DEFAULT_PRECISION, MAX_PRECISION,
+    },
+};
 
-mod processor;
-mod types;
+#[cfg(feature = "parallel")]
+use transaction_tool::parallel::{ShardedState, PARALLEL_BATCH_SIZE};
+
+mod events;
+mod locale;
+mod merge;
+mod output_columns;
+#[cfg(feature = "parquet")]
+mod parquet_output;
+mod progress;
+mod rejects;
+#[cfg(feature = "server")]
+mod server;
+mod snapshots;
+
+// bumped whenever the columns of the client output, or their meaning, change
+const OUTPUT_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    #[default]
+    Csv,
+    Parquet,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unsupported output format '{}'", s)),
+        }
+    }
+}
+
+// the order the client rows are written in
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ClientOrder {
+    #[default]
+    Id,
+    FirstSeen,
+}
+
+impl FromStr for ClientOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(ClientOrder::Id),
+            "first-seen" => Ok(ClientOrder::FirstSeen),
+            _ => Err(format!("Unsupported client order '{}'", s)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    paths: Vec<String>,
+    merge_by_time: bool,
+    sort_by_time: bool,
+    max_held_fraction: Option<Amount>,
+    max_file_size: Option<u64>,
+    max_tx_per_client: Option<usize>,
+    max_clients: Option<usize>,
+    max_duplicate_fraction: Option<f64>,
+    duplicate_withdrawal_window: Option<usize>,
+    reject_duplicate_withdrawals: bool,
+    min_balance: Option<Amount>,
+    chargebacks_before_lock: Option<u32>,
+    precision: Option<u32>,
+    excess_decimals: ExcessDecimals,
+    dump_transfers: Option<String>,
+    audit_log: Option<String>,
+    threads: Option<usize>,
+    locale: Option<Locale>,
+    escrow_locked_deposits: bool,
+    accept_locked_deposits: bool,
+    allow_admin_tx: bool,
+    check_dispute_amount: bool,
+    missing_client: MissingClientPolicy,
+    defer_early_disputes: bool,
+    include_seen_clients: bool,
+    shadow_ledger: bool,
+    error_budget: bool,
+    streaming: bool,
+    check_timestamp_order: bool,
+    verbose: bool,
+    progress: bool,
+    stats: bool,
+    verify: bool,
+    first_error_only: bool,
+    check: bool,
+    delimiter: Option<u8>,
+    amount_format: Option<AmountFormat>,
+    event_log: Option<String>,
+    rejects_file: Option<String>,
+    snapshot_every: Option<usize>,
+    snapshot_dir: Option<String>,
+    only_clients: Option<HashSet<u16>>,
+    overdraft_limits: Vec<(u16, Amount)>,
+    balance_sheet: bool,
+    totals: bool,
+    columnar: bool,
+    replay_events: bool,
+    diff: bool,
+    generate: Option<SyntheticInput>,
+    schema_header: bool,
+    output: OutputFormat,
+    columns: Option<Vec<OutputColumn>>,
+    extended: bool,
+    order: ClientOrder,
+    serve: Option<String>,
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() <= 1 {
-        eprintln!("Missing filename argument");
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = try_main(&options) {
+        eprintln!(
+            "Failed to process '{}': {}",
+            options.paths.join("', '"),
+            err
+        );
         process::exit(1);
     }
+}
 
-    if let Err(err) = try_main(&args[1]) {
-        eprintln!("Failed to process '{}': {}", &args[1], err);
-        process::exit(1);
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    let mut args = args.iter().peekable();
+    match args.peek().map(|a| a.as_str()) {
+        Some("replay-events") => options.replay_events = true,
+        Some("diff") => options.diff = true,
+        Some("gen") => {
+            options.generate = Some(parse_gen_args(&mut args.skip(1))?);
+            return Ok(options);
+        }
+        _ => {}
+    }
+    if options.replay_events || options.diff {
+        args.next();
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--merge-by-time" => options.merge_by_time = true,
+            "--sort-by-time" => options.sort_by_time = true,
+            "--escrow-locked-deposits" => options.escrow_locked_deposits = true,
+            "--accept-locked-deposits" => options.accept_locked_deposits = true,
+            // amounts are always rounded to the ledger precision as they are processed, so this
+            // is only accepted to keep existing scripts working
+            "--round-input" => {}
+            "--allow-admin-tx" => options.allow_admin_tx = true,
+            "--check-dispute-amount" => options.check_dispute_amount = true,
+            "--missing-client" => options.missing_client = flag_value(arg, args.next())?,
+            "--defer-early-disputes" => options.defer_early_disputes = true,
+            "--include-seen-clients" => options.include_seen_clients = true,
+            "--streaming" => options.streaming = true,
+            "--check-timestamp-order" => options.check_timestamp_order = true,
+            "--verbose" => options.verbose = true,
+            "--progress" => options.progress = true,
+            "--stats" => options.stats = true,
+            "--verify" => options.verify = true,
+            "--first-error-only" => options.first_error_only = true,
+            "--check" => options.check = true,
+            // rows longer than the header are always accepted now, so this is only accepted to
+            // keep existing scripts working
+            "--flexible" => {}
+            "--shadow-ledger" => options.shadow_ledger = true,
+            "--error-budget" => options.error_budget = true,
+            "--delimiter" => options.delimiter = Some(delimiter(arg, args.next())?),
+            "--amount-format" => options.amount_format = Some(flag_value(arg, args.next())?),
+            "--schema-header" => options.schema_header = true,
+            "--balance-sheet" => options.balance_sheet = true,
+            "--totals" => options.totals = true,
+            "--columnar" => options.columnar = true,
+            "--max-held-fraction" => {
+                options.max_held_fraction = Some(flag_value(arg, args.next())?)
+            }
+            "--max-tx-per-client" => {
+                options.max_tx_per_client = Some(flag_value(arg, args.next())?)
+            }
+            "--max-clients" => options.max_clients = Some(flag_value(arg, args.next())?),
+            "--max-duplicate-fraction" => {
+                let fraction: f64 = flag_value(arg, args.next())?;
+                if !(0.0..=1.0).contains(&fraction) {
+                    return Err(String::from(
+                        "--max-duplicate-fraction must be between 0 and 1",
+                    ));
+                }
+                options.max_duplicate_fraction = Some(fraction);
+            }
+            "--duplicate-withdrawal-window" => {
+                options.duplicate_withdrawal_window = Some(flag_value(arg, args.next())?)
+            }
+            "--reject-duplicate-withdrawals" => options.reject_duplicate_withdrawals = true,
+            "--min-balance" => {
+                let min: Amount = flag_value(arg, args.next())?;
+                if min < Amount::ZERO {
+                    return Err(String::from("--min-balance can't be negative"));
+                }
+                options.min_balance = Some(min);
+            }
+            "--chargebacks-before-lock" => {
+                let count: u32 = flag_value(arg, args.next())?;
+                if count == 0 {
+                    return Err(String::from("--chargebacks-before-lock must be at least 1"));
+                }
+                options.chargebacks_before_lock = Some(count);
+            }
+            "--precision" => options.precision = Some(flag_value(arg, args.next())?),
+            "--excess-decimals" => options.excess_decimals = flag_value(arg, args.next())?,
+            "--max-file-size" => options.max_file_size = Some(flag_value(arg, args.next())?),
+            "--dump-transfers" | "--dump-ledger" => {
+                options.dump_transfers = Some(flag_value(arg, args.next())?)
+            }
+            "--audit-log" => options.audit_log = Some(flag_value(arg, args.next())?),
+            "--threads" => options.threads = Some(flag_value(arg, args.next())?),
+            "--locale" => options.locale = Some(flag_value(arg, args.next())?),
+            "--output" | "--format" => options.output = flag_value(arg, args.next())?,
+            "--order" => options.order = flag_value(arg, args.next())?,
+            "--columns" => {
+                let list: String = flag_value(arg, args.next())?;
+                options.columns = Some(output_columns::parse_columns(&list)?);
+            }
+            "--extended" => options.extended = true,
+            "--serve" => options.serve = Some(flag_value(arg, args.next())?),
+            "--event-log" => options.event_log = Some(flag_value(arg, args.next())?),
+            "--rejects-file" => options.rejects_file = Some(flag_value(arg, args.next())?),
+            "--snapshot-every" => options.snapshot_every = Some(flag_value(arg, args.next())?),
+            "--snapshot-dir" => options.snapshot_dir = Some(flag_value(arg, args.next())?),
+            "--only-clients" => options.only_clients = Some(client_list(arg, args.next())?),
+            "--overdraft-limits" => options.overdraft_limits = limit_list(arg, args.next())?,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'", flag)),
+            path => options.paths.push(path.to_string()),
+        }
+    }
+
+    if matches!(options.precision, Some(precision) if precision > MAX_PRECISION) {
+        return Err(format!(
+            "--precision can be at most {} decimal places",
+            MAX_PRECISION
+        ));
+    }
+
+    if options.serve.is_some() {
+        if !cfg!(feature = "server") {
+            return Err(String::from(
+                "--serve requires building with --features server",
+            ));
+        }
+
+        if !options.paths.is_empty() || options.replay_events {
+            return Err(String::from("--serve does not take input files"));
+        }
+
+        return Ok(options);
+    }
+
+    // with no path, input piped in is read from stdin, but a terminal is never waited on
+    if options.paths.is_empty() && !options.replay_events && !io::stdin().is_terminal() {
+        options.paths.push(String::from(STDIN_PATH));
+    }
+
+    if options.paths.is_empty() {
+        return Err(String::from("Missing filename argument"));
+    }
+
+    if options
+        .paths
+        .iter()
+        .filter(|path| *path == STDIN_PATH)
+        .count()
+        > 1
+    {
+        return Err(String::from("stdin ('-') can only be read once"));
+    }
+
+    if options.replay_events && options.paths[0] == STDIN_PATH {
+        return Err(String::from(
+            "replay-events reads the event log from a file",
+        ));
+    }
+
+    if options.paths.len() > 1 && options.replay_events {
+        return Err(String::from("replay-events takes a single event log"));
+    }
+
+    if options.diff && options.paths.len() != 2 {
+        return Err(String::from("diff takes two input files"));
+    }
+
+    // each side is processed file by file into a state of its own, with nothing logged
+    if options.diff
+        && (options.check
+            || options.event_log.is_some()
+            || options.rejects_file.is_some()
+            || options.audit_log.is_some()
+            || options.snapshot_every.is_some()
+            || options.progress
+            || options.first_error_only
+            || options.merge_by_time
+            || options.sort_by_time
+            || options.threads.is_some())
+    {
+        return Err(String::from(
+            "diff does not support --check, --event-log, --rejects-file, --audit-log, \
+             --snapshot-every, --progress, --first-error-only, --merge-by-time, --sort-by-time \
+             or --threads",
+        ));
+    }
+
+    if options.replay_events && (options.merge_by_time || options.first_error_only) {
+        return Err(String::from(
+            "replay-events does not support --merge-by-time or --first-error-only",
+        ));
+    }
+
+    if options.replay_events && options.check {
+        return Err(String::from("--check is not supported with replay-events"));
+    }
+
+    if options.progress && (options.replay_events || options.check) {
+        return Err(String::from(
+            "--progress is not supported with --check or replay-events",
+        ));
+    }
+
+    if options.duplicate_withdrawal_window == Some(0) {
+        return Err(String::from(
+            "--duplicate-withdrawal-window must be at least 1",
+        ));
+    }
+
+    if options.reject_duplicate_withdrawals && options.duplicate_withdrawal_window.is_none() {
+        return Err(String::from(
+            "--reject-duplicate-withdrawals requires --duplicate-withdrawal-window",
+        ));
+    }
+
+    if options.snapshot_every == Some(0) {
+        return Err(String::from("--snapshot-every must be at least 1"));
+    }
+
+    if options.snapshot_dir.is_some() && options.snapshot_every.is_none() {
+        return Err(String::from("--snapshot-dir requires --snapshot-every"));
+    }
+
+    if options.snapshot_every.is_some() && (options.replay_events || options.check) {
+        return Err(String::from(
+            "--snapshot-every is not supported with --check or replay-events",
+        ));
+    }
+
+    if (options.stats || options.totals || options.verify) && options.check {
+        return Err(String::from(
+            "--stats, --totals and --verify are not supported with --check",
+        ));
+    }
+
+    // a deferred dispute is logged where it arrived but applied with its deposit, which the
+    // log can't express, so a replay could reject it
+    if options.defer_early_disputes && (options.event_log.is_some() || options.replay_events) {
+        return Err(String::from(
+            "--defer-early-disputes is not supported with --event-log or replay-events",
+        ));
+    }
+
+    if options.accept_locked_deposits && options.escrow_locked_deposits {
+        return Err(String::from(
+            "--accept-locked-deposits is not supported with --escrow-locked-deposits",
+        ));
+    }
+
+    if options.first_error_only && options.merge_by_time {
+        return Err(String::from(
+            "--first-error-only is not supported with --merge-by-time",
+        ));
+    }
+
+    if options.sort_by_time
+        && (options.merge_by_time || options.first_error_only || options.replay_events)
+    {
+        return Err(String::from(
+            "--sort-by-time is not supported with --merge-by-time, --first-error-only or \
+             replay-events",
+        ));
+    }
+
+    if options.threads.is_some() {
+        if !cfg!(feature = "parallel") {
+            return Err(String::from(
+                "--threads requires building with --features parallel",
+            ));
+        }
+
+        // shards finish out of input order, so nothing that logs rows in order can follow them
+        if options.event_log.is_some()
+            || options.rejects_file.is_some()
+            || options.audit_log.is_some()
+            || options.first_error_only
+            || options.merge_by_time
+            || options.sort_by_time
+            || options.snapshot_every.is_some()
+            || options.replay_events
+        {
+            return Err(String::from(
+                "--threads is not supported with --event-log, --rejects-file, --audit-log, \
+                 --first-error-only, --merge-by-time, --sort-by-time, --snapshot-every or \
+                 replay-events",
+            ));
+        }
+
+        // each shard only sees its own clients and their rows, so none of them can count them all
+        if options.max_clients.is_some() || options.max_duplicate_fraction.is_some() {
+            return Err(String::from(
+                "--threads is not supported with --max-clients or --max-duplicate-fraction",
+            ));
+        }
+    }
+
+    if options.output == OutputFormat::Parquet {
+        if !cfg!(feature = "parquet") {
+            return Err(String::from(
+                "--output parquet requires building with --features parquet",
+            ));
+        }
+
+        if options.locale.is_some() || options.schema_header {
+            return Err(String::from(
+                "--output parquet does not support --locale or --schema-header",
+            ));
+        }
+    }
+
+    if options.output == OutputFormat::Json && (options.locale.is_some() || options.schema_header) {
+        return Err(String::from(
+            "--output json does not support --locale or --schema-header",
+        ));
+    }
+
+    // --extended is shorthand for every column, the largest deposit and withdrawal included
+    if options.extended {
+        if options.columns.is_some() {
+            return Err(String::from(
+                "--extended can't be combined with --columns, which can list max_deposit and \
+                 max_withdrawal itself",
+            ));
+        }
+        if options.output != OutputFormat::Csv {
+            return Err(String::from("--extended is only supported with csv output"));
+        }
+        options.columns = Some(EXTENDED_COLUMNS.to_vec());
+    }
+
+    if options.columns.is_some() && options.output != OutputFormat::Csv {
+        return Err(String::from("--columns is only supported with csv output"));
+    }
+
+    // the other outputs, the columnar copy and the shards all list the clients by id
+    if options.order == ClientOrder::FirstSeen
+        && (options.output != OutputFormat::Csv || options.columnar || options.threads.is_some())
+    {
+        return Err(String::from(
+            "--order first-seen is only supported with csv output, and not with --columnar or \
+             --threads",
+        ));
+    }
+
+    Ok(options)
+}
+
+// `gen ROWS`, with its own few options, as nothing is processed
+fn parse_gen_args<'a, I: Iterator<Item = &'a String>>(
+    args: &mut I,
+) -> Result<SyntheticInput, String> {
+    let mut input = SyntheticInput::default();
+    let mut rows = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clients" => input.clients = flag_value(arg, args.next())?,
+            "--dispute-rate" => input.dispute_rate = flag_value(arg, args.next())?,
+            "--seed" => input.seed = flag_value(arg, args.next())?,
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option '{}' for gen", flag))
+            }
+            _ if rows.is_none() => rows = Some(flag_value("gen", Some(arg))?),
+            _ => return Err(String::from("gen takes a single row count")),
+        }
+    }
+
+    input.rows = rows.ok_or("gen takes the number of rows to write")?;
+
+    if input.clients == 0 {
+        return Err(String::from("--clients must be at least 1"));
+    }
+
+    if !(0.0..=1.0).contains(&input.dispute_rate) {
+        return Err(String::from("--dispute-rate must be between 0 and 1"));
+    }
+
+    Ok(input)
+}
+
+fn flag_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, flag))
+}
+
+// a single ascii character, e.g. `;`, or `tab`
+fn delimiter(flag: &str, value: Option<&String>) -> Result<u8, String> {
+    let value: String = flag_value(flag, value)?;
+    match value.as_str() {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        _ => Err(format!("Invalid delimiter '{}' for {}", value, flag)),
+    }
+}
+
+// a comma separated list of client ids, e.g. 1,2,3
+fn client_list(flag: &str, value: Option<&String>) -> Result<HashSet<u16>, String> {
+    let value: String = flag_value(flag, value)?;
+    value
+        .split(',')
+        .map(|id| {
+            id.trim()
+                .parse()
+                .map_err(|_| format!("Invalid client id '{}' for {}", id, flag))
+        })
+        .collect()
+}
+
+// a comma separated list of CLIENT:LIMIT pairs, e.g. `1:50.0,7:10`
+fn limit_list(flag: &str, value: Option<&String>) -> Result<Vec<(u16, Amount)>, String> {
+    let value: String = flag_value(flag, value)?;
+    value
+        .split(',')
+        .map(|pair| {
+            let invalid = || format!("Invalid overdraft limit '{}' for {}", pair, flag);
+            let (id, limit) = pair.trim().split_once(':').ok_or_else(invalid)?;
+            let id = id.parse().map_err(|_| invalid())?;
+            let limit: Amount = limit.parse().map_err(|_| invalid())?;
+
+            if limit < Amount::ZERO {
+                return Err(invalid());
+            }
+
+            Ok((id, limit))
+        })
+        .collect()
+}
+
+// the state a run starts from, configured by the options
+fn initial_state(options: &Options) -> State {
+    // rejects are read back from the state after each batch, so it has to remember a whole batch
+    let mut initial_state = match options.rejects_file {
+        Some(_) => State::with_recent_rejects_capacity(BATCH_SIZE),
+        None => State::new(),
+    };
+    initial_state.config = engine_config(options);
+    for &(client_id, limit) in &options.overdraft_limits {
+        // a filtered out client mustn't enter the state, and can't withdraw anyway
+        if initial_state.accepts_client(client_id) {
+            initial_state.set_overdraft_limit(client_id, limit);
+        }
+    }
+    if options.include_seen_clients {
+        initial_state.seen_clients = Some(HashSet::new());
+    }
+    if options.audit_log.is_some() {
+        initial_state.audit_log = Some(Vec::new());
+    }
+    if options.order == ClientOrder::FirstSeen {
+        initial_state.first_seen = Some(HashMap::new());
     }
+    if options.shadow_ledger {
+        initial_state.shadow_ledger = Some(ShadowLedger::default());
+    }
+    if options.error_budget {
+        initial_state.error_budget = Some(ErrorBudget::default());
+    }
+
+    initial_state
 }
 
-fn try_main(path: &String) -> Result<(), Box<dyn Error>> {
-    let state = process_transaction_file(path)?;
-    print_client_state(&state.clients)?;
+fn try_main(options: &Options) -> Result<(), Box<dyn Error>> {
+    if let Some(input) = &options.generate {
+        return Ok(input.write(io::stdout().lock())?);
+    }
+
+    let initial_state = initial_state(options);
+
+    if let Some(max_size) = options.max_file_size {
+        check_file_sizes(&options.paths, max_size)?;
+    }
+
+    let mut input = InputReader::default();
+    if let Some(delimiter) = options.delimiter {
+        input.delimiter(delimiter);
+    }
+    if let Some(amounts) = options.amount_format {
+        input.amounts = amounts;
+    }
+
+    if let Some(addr) = &options.serve {
+        return serve(addr, &input, initial_state);
+    }
+
+    // validation only reads the input, so no log or output file is created
+    if options.check {
+        return check_input_files(&options.paths, &input, &initial_state);
+    }
+
+    if options.diff {
+        return diff_files(options, &input);
+    }
+
+    let mut sinks = Sinks {
+        events: match &options.event_log {
+            Some(path) => Some(EventLog::create(path)?),
+            None => None,
+        },
+        rejects: match &options.rejects_file {
+            Some(path) => Some(RejectsFile::create(path)?),
+            None => None,
+        },
+        progress: options.progress.then(Progress::stderr),
+        snapshots: options.snapshot_every.map(|every| {
+            let dir = options.snapshot_dir.as_deref().unwrap_or(".");
+            Snapshots::new(PathBuf::from(dir), every)
+        }),
+    };
+
+    let result = if options.replay_events {
+        events::replay_events(&options.paths[0], initial_state)
+    } else if options.merge_by_time {
+        process_merged_files(&options.paths, &input, initial_state, &mut sinks)
+    } else if options.sort_by_time {
+        process_sorted_files(&options.paths, &input, initial_state, &mut sinks)
+    } else if let Some(threads) = options.threads {
+        process_input_files_parallel(
+            &options.paths,
+            &input,
+            initial_state,
+            threads,
+            &mut sinks.progress,
+        )
+    } else if options.first_error_only {
+        options.paths.iter().try_fold(initial_state, |state, path| {
+            process_until_first_error(path, &input, state, &mut sinks)
+                .map_err(|err| with_path(&options.paths, path, err))
+        })
+    } else {
+        process_input_files(&options.paths, &input, initial_state, &mut sinks)
+    };
+
+    if let Some(progress) = &mut sinks.progress {
+        progress.finish();
+    }
+
+    // disputes still waiting for their deposit only turn out to be unmatched at the end
+    let result = result.and_then(|state| reject_deferred_disputes(state, &mut sinks.rejects));
+
+    // flushed before any error is returned, so the reject that stopped the run is kept
+    if let Some(rejects) = &mut sinks.rejects {
+        rejects.flush()?;
+    }
+
+    let mut state = result?;
+
+    if let Some(events) = &mut sinks.events {
+        events.flush()?;
+    }
+
+    if options.verbose {
+        for warning in state.warnings() {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+
+    if options.stats {
+        eprintln!("{}", stats_summary(&state));
+    }
+
+    report_precision_loss(&state);
+
+    if options.verify {
+        verify_ledger(&state)?;
+    }
+
+    if let Some(max_fraction) = options.max_held_fraction {
+        check_held_fraction(&state.clients, max_fraction)?;
+    }
+
+    add_seen_clients(&mut state);
+
+    // built once, after processing, for the read-only output phase
+    let columns = options
+        .columnar
+        .then(|| ClientColumns::from_clients(&state.clients));
+
+    // stdout carries the client rows, so the rollup goes to stderr
+    if options.balance_sheet {
+        let sheet = match &columns {
+            Some(columns) => BalanceSheet::from_columns(columns),
+            None => BalanceSheet::new(&state.clients),
+        };
+        eprint!("{}", sheet);
+    }
+
+    if options.totals {
+        eprintln!("totals: {}", state.aggregate());
+    }
+
+    if let Some(path) = &options.dump_transfers {
+        state.write_ledger(File::create(path)?)?;
+    }
+
+    if let (Some(path), Some(audit_log)) = (&options.audit_log, &state.audit_log) {
+        write_audit_log(audit_log, File::create(path)?)?;
+    }
+
+    match options.output {
+        // nothing reads the clients after this, so they are freed as they are written
+        OutputFormat::Csv => match &columns {
+            Some(columns) => {
+                write_client_state(&state.clients, Some(columns), io::stdout(), options)?
+            }
+            None => write_and_drain_client_state(&mut state, io::stdout(), options)?,
+        },
+        OutputFormat::Parquet => write_parquet_report(&state.clients, state.config.precision)?,
+        OutputFormat::Json => {
+            write_json_report(&state.clients, state.config.precision, io::stdout())?
+        }
+    }
 
     Ok(())
 }
 
-fn process_transaction_file(path: &String) -> Result<State, Box<dyn Error>> {
-    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(path)?;
+// the processing policies chosen on the command line
+fn engine_config(options: &Options) -> EngineConfig {
+    EngineConfig {
+        escrow_locked_deposits: options.escrow_locked_deposits,
+        deposits_allowed_when_locked: options.accept_locked_deposits,
+        allow_admin_transactions: options.allow_admin_tx,
+        precision: options.precision.unwrap_or(DEFAULT_PRECISION),
+        excess_decimals: options.excess_decimals,
+        check_dispute_amount: options.check_dispute_amount,
+        missing_client: options.missing_client,
+        only_clients: options.only_clients.clone(),
+        max_tx_per_client: options.max_tx_per_client,
+        defer_early_disputes: options.defer_early_disputes,
+        streaming: options.streaming,
+        check_timestamp_order: options.check_timestamp_order,
+        max_clients: options.max_clients,
+        max_duplicate_fraction: options.max_duplicate_fraction,
+        duplicate_withdrawal_window: options.duplicate_withdrawal_window,
+        reject_duplicate_withdrawals: options.reject_duplicate_withdrawals,
+        min_balance: options.min_balance,
+        chargebacks_before_lock: options.chargebacks_before_lock.unwrap_or(1),
+    }
+}
+
+// what a run writes as it goes, besides the final state, each only if its option is set
+#[derive(Default)]
+struct Sinks {
+    events: Option<EventLog>,
+    rejects: Option<RejectsFile>,
+    progress: Option<Progress>,
+    snapshots: Option<Snapshots>,
+}
+
+fn process_input_file(
+    path: &str,
+    input: &InputReader,
+    state: State,
+    sinks: &mut Sinks,
+) -> Result<State, ProcessError> {
+    let rows = TransactionRows::open(input, path)?;
+    let mut state = state;
+
+    // the event log needs to see each transaction's outcome, and snapshots are taken between
+    // rows, so either goes row by row
+    if sinks.events.is_some() || sinks.snapshots.is_some() {
+        for record in rows {
+            match record {
+                Ok(transaction) => state = apply(state, transaction, sinks)?,
+                Err(err) => {
+                    let (location, reason) = unreadable_row(path, err)?;
+                    state.skip_unreadable_row(&location, &reason);
+                }
+            }
+        }
+
+        return Ok(state);
+    }
+
+    let Sinks {
+        rejects, progress, ..
+    } = sinks;
+    let mut rejected = state.rejected;
 
-    reader.deserialize().try_fold(State::new(), |s, r| {
-        Ok(processor::process_transaction(s, r?))
+    processor::process_transaction_rows(rows, path, state, |state, batch| {
+        if let Some(rejects) = rejects {
+            rejects.record(state, rejected)?;
+            rejected = state.rejected;
+        }
+
+        if let Some(progress) = progress {
+            progress.add(batch);
+        }
+
+        Ok(())
     })
 }
 
-fn print_client_state(client_state: &HashMap<u16, Client>) -> Result<(), Box<dyn Error>> {
-    let mut writer = Writer::from_writer(io::stdout());
+// processes a single transaction, recording it in the event log, rejects file and progress
+// as needed
+fn apply(state: State, transaction: Transaction, sinks: &mut Sinks) -> Result<State, ProcessError> {
+    let rejected = state.rejected;
+    let state = events::apply(state, transaction, &mut sinks.events)?;
 
-    for client in client_state.values() {
-        writer.serialize(client)?;
+    if let Some(rejects) = &mut sinks.rejects {
+        rejects.record(&state, rejected)?;
     }
 
-    writer.flush()?;
+    if let Some(progress) = &mut sinks.progress {
+        progress.add(1);
+    }
+
+    if let Some(snapshots) = &mut sinks.snapshots {
+        snapshots.add(&state)?;
+    }
+
+    state.check_client_limit()?;
+
+    Ok(state)
+}
+
+// the deferred disputes are rejected a batch at a time, since the rejects file reads them
+// back from the state's bounded log of recent rejects
+fn reject_deferred_disputes(
+    mut state: State,
+    rejects: &mut Option<RejectsFile>,
+) -> Result<State, Box<dyn Error>> {
+    for batch in state.drain_deferred_disputes().chunks(BATCH_SIZE) {
+        let rejected = state.rejected;
+
+        for &(tx_id, client_id) in batch {
+            state.record_reject(tx_id, RejectReason::UnknownTransaction);
+            state.record_audit(tx_id, Some(client_id), TransactionType::Dispute);
+        }
+
+        if let Some(rejects) = rejects {
+            rejects.record(&state, rejected)?;
+        }
+    }
+
+    Ok(state)
+}
+
+// like process_input_file, but stops at the first row that fails to parse or is
+// rejected by the processor, reporting its line number and the reason
+fn process_until_first_error(
+    path: &str,
+    input: &InputReader,
+    mut state: State,
+    sinks: &mut Sinks,
+) -> Result<State, Box<dyn Error>> {
+    let mut rows = TransactionRows::open(input, path)?;
+
+    while let Some(record) = rows.next() {
+        let line = rows.line();
+        let transaction = record.map_err(ProcessError::from)?;
+
+        let rejected = state.rejected;
+        state = apply(state, transaction, sinks)?;
+
+        if state.rejected > rejected {
+            let reason = state
+                .recent_rejects()
+                .last()
+                .map_or(String::from("transaction rejected"), |(_, r)| r.to_string());
+            return Err(format!("line {}: {}", line, reason).into());
+        }
+    }
+
+    Ok(state)
+}
+
+// applies the files one after another into the same state, so transaction ids stay unique
+// across all of them
+fn process_input_files(
+    paths: &[String],
+    input: &InputReader,
+    state: State,
+    sinks: &mut Sinks,
+) -> Result<State, Box<dyn Error>> {
+    // each file is checked for repeating earlier ones on its own, so one fed in twice is
+    // caught however many files came before it
+    paths.iter().try_fold(state, |state, path| {
+        let before = state.stats.clone();
+        process_input_file(path, input, state, sinks)
+            .and_then(|state| state.check_duplicate_input(&before).map(|_| state))
+            .map_err(|err| with_path(paths, path, err.into()))
+    })
+}
+
+// like process_input_files, with the clients split into shards processed on `threads` threads
+#[cfg(feature = "parallel")]
+fn process_input_files_parallel(
+    paths: &[String],
+    input: &InputReader,
+    state: State,
+    threads: usize,
+    progress: &mut Option<Progress>,
+) -> Result<State, Box<dyn Error>> {
+    let mut sharded = ShardedState::new(state, threads)?;
+
+    for path in paths {
+        let rows =
+            TransactionRows::open(input, path).map_err(|err| with_path(paths, path, err.into()))?;
+        let mut batch = Vec::with_capacity(PARALLEL_BATCH_SIZE);
+
+        for record in rows {
+            match record {
+                Ok(transaction) => batch.push(transaction),
+                Err(err) => {
+                    let (location, reason) = unreadable_row(path, err)
+                        .map_err(|err| with_path(paths, path, err.into()))?;
+                    sharded.skip_unreadable_row(&location, &reason);
+                    continue;
+                }
+            }
+
+            if batch.len() == PARALLEL_BATCH_SIZE {
+                sharded.process(std::mem::replace(
+                    &mut batch,
+                    Vec::with_capacity(PARALLEL_BATCH_SIZE),
+                ));
+
+                if let Some(progress) = progress {
+                    progress.add(PARALLEL_BATCH_SIZE);
+                }
+            }
+        }
+
+        let rows = batch.len();
+        sharded.process(batch);
+
+        if let Some(progress) = progress {
+            progress.add(rows);
+        }
+    }
+
+    Ok(sharded.into_state())
+}
+
+// parse_args refuses --threads without the feature, so this is never reached
+#[cfg(not(feature = "parallel"))]
+fn process_input_files_parallel(
+    _: &[String],
+    _: &InputReader,
+    _: State,
+    _: usize,
+    _: &mut Option<Progress>,
+) -> Result<State, Box<dyn Error>> {
+    Err("parallel processing requires building with --features parallel".into())
+}
+
+// reads every row of the input without applying any, printing a line-numbered report of
+// the rows that aren't valid transactions to stderr. fails once they are all reported
+fn check_input_files(
+    paths: &[String],
+    input: &InputReader,
+    state: &State,
+) -> Result<(), Box<dyn Error>> {
+    let mut invalid = 0;
+
+    for path in paths {
+        let problems =
+            check_input_file(path, input, state).map_err(|err| with_path(paths, path, err))?;
+
+        for problem in &problems {
+            eprintln!("{}", with_path(paths, path, problem.as_str().into()));
+        }
+        invalid += problems.len();
+    }
+
+    match invalid {
+        0 => Ok(()),
+        1 => Err("1 invalid row".into()),
+        n => Err(format!("{} invalid rows", n).into()),
+    }
+}
+
+// the problems with each row of one file, in order. only an io error stops the check early
+fn check_input_file(
+    path: &str,
+    input: &InputReader,
+    state: &State,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut rows = TransactionRows::open(input, path)?;
+    let mut problems = Vec::new();
+
+    while let Some(record) = rows.next() {
+        match record.map_err(ProcessError::from) {
+            Ok(transaction) => {
+                if let Err(reason) = processor::validate_transaction(state, &transaction) {
+                    problems.push(format!(
+                        "line {}: transaction {}: {}",
+                        rows.line(),
+                        transaction.id,
+                        reason
+                    ));
+                }
+            }
+            Err(err @ ProcessError::MalformedRow { .. }) => problems.push(err.to_string()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(problems)
+}
+
+// names the file an error came from when there are several, since main only lists them all
+fn with_path(paths: &[String], path: &str, err: Box<dyn Error>) -> Box<dyn Error> {
+    if paths.len() == 1 {
+        return err;
+    }
+
+    format!("{}: {}", path, err).into()
+}
+
+// applies the transactions of every file in timestamp order, rather than file by file
+fn process_merged_files(
+    paths: &[String],
+    input: &InputReader,
+    state: State,
+    sinks: &mut Sinks,
+) -> Result<State, Box<dyn Error>> {
+    let mut merged = MergedTransactions::from_paths(paths, input)?;
+    let before = state.stats.clone();
+    let mut state = state;
+
+    loop {
+        // rows the merge passed over while reading ahead
+        for (source, err) in merged.take_unreadable() {
+            let (location, reason) = unreadable_row(&paths[source], err)?;
+            state.skip_unreadable_row(&location, &reason);
+        }
+
+        match merged.next() {
+            Some(transaction) => state = apply(state, transaction?, sinks)?,
+            None => {
+                // the files are interleaved, so they are checked for duplicates as one input
+                state.check_duplicate_input(&before)?;
+                return Ok(state);
+            }
+        }
+    }
+}
+
+// applies the transactions of every file in timestamp order, like process_merged_files, but
+// sorts them all in memory first, so the files needn't be sorted themselves. equal
+// timestamps go by tx id, and a dispute with the same timestamp as its deposit keeps the
+// order the two were read in
+fn process_sorted_files(
+    paths: &[String],
+    input: &InputReader,
+    state: State,
+    sinks: &mut Sinks,
+) -> Result<State, Box<dyn Error>> {
+    let before = state.stats.clone();
+    let mut state = state;
+    let mut transactions = Vec::new();
+
+    for path in paths {
+        let rows =
+            TransactionRows::open(input, path).map_err(|err| with_path(paths, path, err.into()))?;
+
+        for record in rows {
+            match record {
+                Ok(transaction) if transaction.timestamp.is_none() => {
+                    let err = format!("transaction {} is missing a timestamp", transaction.id);
+                    return Err(with_path(paths, path, err.into()));
+                }
+                Ok(transaction) => transactions.push(transaction),
+                Err(err) => {
+                    let (location, reason) = unreadable_row(path, err)?;
+                    state.skip_unreadable_row(&location, &reason);
+                }
+            }
+        }
+    }
+
+    // a stable sort, which keeps the read order of rows with the same timestamp and id
+    transactions.sort_by_key(|transaction| (transaction.timestamp, transaction.id));
+
+    for transaction in transactions {
+        state = apply(state, transaction, sinks)?;
+    }
+
+    // like merged files, sorted ones are checked for duplicates as one input
+    state.check_duplicate_input(&before)?;
+
+    Ok(state)
+}
+
+// gives every client id that only appeared in rejected transactions a zero-balance row,
+// so the output confirms it was seen
+// the shadow ledger's drift and the error budget, on stderr, for the clients they apply to
+fn report_precision_loss(state: &State) {
+    let precision = state.config.precision;
+
+    if let Some(shadow_ledger) = &state.shadow_ledger {
+        for (client_id, shadow_total) in shadow_ledger.drift(&state.clients, precision) {
+            eprintln!(
+                "Warning: client {} total {} drifted to {} in the floating point shadow ledger",
+                client_id, state.clients[&client_id].total, shadow_total
+            );
+        }
+    }
+
+    if let Some(error_budget) = &state.error_budget {
+        let mut clients: Vec<&Client> = state.clients.values().collect();
+        clients.sort_by_key(|client| client.id);
+
+        for client in clients {
+            eprintln!(
+                "client {}: rounding error up to {:e} after {} rounded amounts",
+                client.id,
+                error_budget.budget(client.id, precision),
+                error_budget.rounded(client.id)
+            );
+        }
+    }
+}
+
+fn add_seen_clients(state: &mut State) {
+    if let Some(seen_clients) = state.seen_clients.take() {
+        for client_id in seen_clients {
+            state
+                .clients
+                .entry(client_id)
+                .or_insert_with(|| Client::new(client_id));
+        }
+    }
+}
+
+// refuses to start on an input larger than expected, which is usually the wrong file
+fn check_file_sizes(paths: &[String], max_size: u64) -> Result<(), Box<dyn Error>> {
+    // stdin's size isn't known up front
+    for path in paths.iter().filter(|path| *path != STDIN_PATH) {
+        let size = fs::metadata(path)?.len();
+
+        if size > max_size {
+            return Err(format!(
+                "'{}' is {} bytes, over the --max-file-size limit of {} bytes",
+                path, size, max_size
+            )
+            .into());
+        }
+    }
 
     Ok(())
 }
+
+// whole-system totals across every client
+#[derive(Debug, PartialEq)]
+struct BalanceSheet {
+    assets: Amount,
+    held: Amount,
+    available: Amount,
+}
+
+impl BalanceSheet {
+    fn new(client_state: &HashMap<u16, Client>) -> Self {
+        Self {
+            assets: client_state.values().map(|c| c.total).sum(),
+            held: client_state.values().map(|c| c.held).sum(),
+            available: client_state.values().map(|c| c.available).sum(),
+        }
+    }
+
+    fn from_columns(columns: &ClientColumns) -> Self {
+        Self {
+            assets: columns.total.iter().sum(),
+            held: columns.held.iter().sum(),
+            available: columns.available.iter().sum(),
+        }
+    }
+}
+
+impl fmt::Display for BalanceSheet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "total assets: {}", self.assets)?;
+        writeln!(f, "total held: {}", self.held)?;
+        writeln!(f, "total available: {}", self.available)
+    }
+}
+
+// the counts for --stats, on one line. clients are those in the output, before any that
+// were only seen are added
+fn stats_summary(state: &State) -> String {
+    let stats = &state.stats;
+
+    format!(
+        "{} rows: {} deposits, {} withdrawals, {} transfers, {} disputes, {} resolves, \
+         {} chargebacks and {} admin rows applied, {} skipped. {} clients, {} locked",
+        stats.rows,
+        stats.deposits,
+        stats.withdrawals,
+        stats.transfers,
+        stats.disputes,
+        stats.resolves,
+        stats.chargebacks,
+        stats.admin,
+        stats.skipped,
+        state.clients.len(),
+        state.locked_clients().count()
+    )
+}
+
+// processes the two files into separate states with the same options, and prints each client
+// whose balances differ, one per line. like diff(1), the run fails if any do
+fn diff_files(options: &Options, input: &InputReader) -> Result<(), Box<dyn Error>> {
+    let process = |path: &String| {
+        process_input_file(path, input, initial_state(options), &mut Sinks::default())
+            .map_err(|err| with_path(&options.paths, path, err.into()))
+    };
+    let (ours, theirs) = (process(&options.paths[0])?, process(&options.paths[1])?);
+
+    let diffs = ours.diff(&theirs);
+    let mut out = io::stdout().lock();
+    for diff in &diffs {
+        writeln!(out, "{}", diff)?;
+    }
+
+    match diffs.len() {
+        0 => Ok(()),
+        1 => Err("1 client differs".into()),
+        n => Err(format!("{} clients differ", n).into()),
+    }
+}
+
+// fails the run, before anything is written, if any client's balances don't add up
+fn verify_ledger(state: &State) -> Result<(), String> {
+    state.verify_invariants().map_err(|violations| {
+        let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+        format!("ledger invariants violated: {}", violations.join("; "))
+    })
+}
+
+// an unusual share of held funds across the whole system points to a wave of open disputes
+fn check_held_fraction(
+    client_state: &HashMap<u16, Client>,
+    max_fraction: Amount,
+) -> Result<(), String> {
+    let held: Amount = client_state.values().map(|c| c.held).sum();
+    let total: Amount = client_state.values().map(|c| c.total).sum();
+
+    // held / total > max_fraction, cross-multiplied in whole units so a run right at the
+    // threshold isn't tipped either way by rounding
+    let exceeded = held.units() * Amount::ONE.units() > max_fraction.units() * total.units();
+    if held > Amount::ZERO && exceeded {
+        return Err(format!(
+            "held funds {} exceed {} of total system funds {}",
+            held, max_fraction, total
+        ));
+    }
+
+    Ok(())
+}
+
+// balances are written in the plain machine format unless a display locale is requested,
+// rounded to --precision. rows come from the columnar copy of the clients when there is one,
+// which is in id order already
+fn write_client_state<W: Write>(
+    client_state: &HashMap<u16, Client>,
+    columns: Option<&ClientColumns>,
+    writer: W,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = client_writer(writer, options)?;
+    let precision = options.precision.unwrap_or(DEFAULT_PRECISION);
+
+    if let Some(columns) = columns {
+        for client in columns.rows() {
+            write_client_row(&mut writer, &client.rounded(precision), options)?;
+        }
+    } else {
+        // sorted by id, so the same final state always produces the same bytes, however it was built
+        let mut clients: Vec<&Client> = client_state.values().collect();
+        clients.sort_by_key(|c| c.id);
+
+        for client in clients {
+            write_client_row(&mut writer, &client.rounded(precision), options)?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+// writes the same bytes as write_client_state, or with --order first-seen the same rows in
+// that order, removing each client from the state as its row is written, so the map's memory
+// is given back while the output is produced rather than held until the end. the state has
+// no clients left afterwards
+fn write_and_drain_client_state<W: Write>(
+    state: &mut State,
+    writer: W,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = client_writer(writer, options)?;
+    let precision = options.precision.unwrap_or(DEFAULT_PRECISION);
+
+    // by id, unless first_seen was set for --order first-seen
+    for id in state.client_ids_in_first_seen_order() {
+        let client = state.clients.remove(&id).unwrap();
+        write_client_row(&mut writer, &client.rounded(precision), options)?;
+
+        // removing an entry doesn't free the table, so it is shrunk whenever it is down to a
+        // quarter full, which keeps the whole drain linear
+        if state.clients.len() < state.clients.capacity() / 4 {
+            state.clients.shrink_to_fit();
+        }
+    }
+
+    state.index_locked_clients();
+    writer.flush()?;
+
+    Ok(())
+}
+
+// a csv writer for client rows, after the schema line if one was asked for
+fn client_writer<W: Write>(mut writer: W, options: &Options) -> Result<Writer<W>, Box<dyn Error>> {
+    if options.schema_header {
+        writeln!(writer, "# schema: v{}", OUTPUT_SCHEMA_VERSION)?;
+    }
+
+    Ok(Writer::from_writer(writer))
+}
+
+// every column, or the ones chosen with --columns. either way the header is written with
+// the first row
+fn write_client_row<W: Write>(
+    writer: &mut Writer<W>,
+    client: &Client,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    // a mismatch here means a processor bug, so warn but still write the row
+    if let Some(warning) = balance_warning(client) {
+        eprintln!("{}", warning);
+    }
+
+    match (&options.columns, options.locale) {
+        (Some(columns), locale) => {
+            writer.serialize(SelectedClient::new(client, columns, locale))?
+        }
+        (None, Some(locale)) => writer.serialize(LocalizedClient::new(client, locale))?,
+        (None, None) => writer.serialize(client)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn serve(addr: &str, input: &InputReader, state: State) -> Result<(), Box<dyn Error>> {
+    server::serve(addr, input, state)
+}
+
+// parse_args refuses --serve without the feature, so this is never reached
+#[cfg(not(feature = "server"))]
+fn serve(_: &str, _: &InputReader, _: State) -> Result<(), Box<dyn Error>> {
+    Err("serving requires building with --features server".into())
+}
+
+// a json array of clients sorted by id, with the same fields as the csv output. amounts are
+// exact numbers with at most `precision` decimal places, see types::clients_to_json
+fn write_json_report<W: Write>(
+    client_state: &HashMap<u16, Client>,
+    precision: u32,
+    mut writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut clients: Vec<Client> = client_state
+        .values()
+        .map(|client| client.rounded(precision))
+        .collect();
+    clients.sort_by_key(|c| c.id);
+
+    serde_json::to_writer(&mut writer, &types::clients_to_json(&clients)?)?;
+    writeln!(writer)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet_report(
+    client_state: &HashMap<u16, Client>,
+    precision: u32,
+) -> Result<(), Box<dyn Error>> {
+    let clients: Vec<Client> = client_state
+        .values()
+        .map(|client| client.rounded(precision))
+        .collect();
+    parquet_output::write_parquet(clients.iter(), precision, io::stdout())
+}
+
+// parse_args refuses --output parquet without the feature, so this is never reached
+#[cfg(not(feature = "parquet"))]
+fn write_parquet_report(_: &HashMap<u16, Client>, _: u32) -> Result<(), Box<dyn Error>> {
+    Err("parquet output requires building with --features parquet".into())
+}
+
+fn write_audit_log<W: Write>(audit_log: &[AuditEvent], writer: W) -> Result<(), Box<dyn Error>> {
+    let mut writer = Writer::from_writer(writer);
+    for event in audit_log {
+        writer.serialize(event)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn balance_warning(client: &Client) -> Option<String> {
+    if client.total == client.available + client.held {
+        return None;
+    }
+
+    Some(format!(
+        "Warning: client {} total {} does not equal available {} plus held {}",
+        client.id, client.total, client.available, client.held
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn processed_state_has_no_balance_warnings() {
+        let mut state = State::new();
+        let txs = vec![
+            (TransactionType::Deposit, 1, 1, amount("1.0")),
+            (TransactionType::Deposit, 2, 2, amount("2.0")),
+            (TransactionType::Deposit, 1, 3, amount("2.0")),
+            (TransactionType::Withdrawal, 1, 4, amount("1.5")),
+            (TransactionType::Dispute, 2, 2, Amount::ZERO),
+            (TransactionType::Dispute, 1, 3, Amount::ZERO),
+            (TransactionType::Chargeback, 1, 3, Amount::ZERO),
+        ];
+
+        for (transaction_type, client_id, id, amount) in txs {
+            let tx = Transaction::new(transaction_type, client_id, id, amount);
+            state = processor::process_transaction(state, tx);
+        }
+
+        assert_eq!(state.clients.len(), 2);
+        assert!(state.clients.values().all(|c| balance_warning(c).is_none()));
+    }
+
+    #[test]
+    fn sorted_files_apply_in_timestamp_order() {
+        let paths = vec![
+            String::from("test-data/unsorted_a.csv"),
+            String::from("test-data/unsorted_b.csv"),
+        ];
+        let process = |paths: &[String]| {
+            process_sorted_files(
+                paths,
+                &InputReader::default(),
+                State::new(),
+                &mut Sinks::default(),
+            )
+        };
+
+        // the dispute is read before its deposit, but timestamped after it
+        let state = process(&paths).unwrap();
+        let client = &state.clients[&1];
+
+        assert_eq!(client.held, amount("5.0"));
+        assert_eq!(client.available, amount("1.0"));
+        assert_eq!(state.clients[&2].available, amount("2.0"));
+        assert_eq!(state.rejected, 0);
+
+        let err = process(&[String::from("test-data/test.csv")]).unwrap_err();
+        assert_eq!(err.to_string(), "transaction 1 is missing a timestamp");
+
+        let args = vec![
+            String::from("--sort-by-time"),
+            String::from("--merge-by-time"),
+            String::from("a.csv"),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn merged_files_apply_in_timestamp_order() {
+        let paths = vec![
+            String::from("test-data/merge_a.csv"),
+            String::from("test-data/merge_b.csv"),
+        ];
+
+        let state = process_merged_files(
+            &paths,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+        let client = state.clients.get(&1).unwrap();
+
+        // the withdrawal only succeeds if both deposits were applied before it
+        assert_eq!(client.available, Amount::ZERO);
+        assert_eq!(client.total, Amount::ZERO);
+        assert_eq!(state.clients.get(&2).unwrap().total, amount("5.0"));
+    }
+
+    #[test]
+    fn input_files_share_state() {
+        let paths = vec![
+            String::from("test-data/daily_1.csv"),
+            String::from("test-data/daily_2.csv"),
+        ];
+
+        let state = process_input_files(
+            &paths,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+
+        // the second file's withdrawal spends the first file's deposit, and its reuse of
+        // tx 1 is skipped
+        assert_eq!(state.clients[&1].available, amount("1.5"));
+        assert_eq!(state.rejected, 1);
+
+        let missing = vec![paths[0].clone(), String::from("test-data/missing.csv")];
+        let err = process_input_files(
+            &missing,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().starts_with("test-data/missing.csv: "));
+    }
+
+    #[test]
+    fn clashing_ids_across_files_reported() {
+        let process = |paths: [&str; 2]| {
+            let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+            process_input_files(
+                &paths,
+                &InputReader::default(),
+                State::new(),
+                &mut Sinks::default(),
+            )
+            .unwrap()
+        };
+
+        // the second file redefines tx 1 with another amount, and the first is kept
+        let state = process(["test-data/daily_1.csv", "test-data/daily_2.csv"]);
+
+        assert_eq!(state.transfers[&1].amount, amount("4.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(1, RejectReason::ConflictingTransaction)]
+        );
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec!["skipped transaction 1: transaction id already used for a different transaction"]
+        );
+
+        // the same file passed twice only repeats its transactions
+        let state = process(["test-data/daily_1.csv", "test-data/daily_1.csv"]);
+
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(1, RejectReason::DuplicateTransaction),
+                &(2, RejectReason::DuplicateTransaction)
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_paths_accepted() {
+        let args = vec![String::from("a.csv"), String::from("b.csv")];
+
+        assert_eq!(parse_args(&args).unwrap().paths.len(), 2);
+
+        let mut merge_args = args.clone();
+        merge_args.push(String::from("--merge-by-time"));
+        let options = parse_args(&merge_args).unwrap();
+
+        assert!(options.merge_by_time);
+        assert_eq!(options.paths.len(), 2);
+
+        let mut replay_args = vec![String::from("replay-events")];
+        replay_args.extend(args);
+
+        assert!(parse_args(&replay_args).is_err());
+    }
+
+    #[test]
+    fn held_fraction_threshold() {
+        let mut normal = Client::new(1);
+        normal.available = amount("9.0");
+        normal.total = amount("9.0");
+
+        let mut disputed = Client::new(2);
+        disputed.held = amount("1.0");
+        disputed.total = amount("1.0");
+
+        let mut clients = HashMap::new();
+        clients.insert(1, normal);
+        clients.insert(2, disputed);
+
+        assert!(check_held_fraction(&clients, amount("0.25")).is_ok());
+
+        clients.get_mut(&2).unwrap().held = amount("8.0");
+        clients.get_mut(&2).unwrap().total = amount("8.0");
+
+        assert!(check_held_fraction(&clients, amount("0.25")).is_err());
+
+        // exactly at the threshold, where 0.3 * 3.0 in floats comes out below 0.9
+        let normal = clients.get_mut(&1).unwrap();
+        normal.available = amount("2.1");
+        normal.total = amount("2.1");
+        let disputed = clients.get_mut(&2).unwrap();
+        disputed.held = amount("0.9");
+        disputed.total = amount("0.9");
+
+        assert!(check_held_fraction(&clients, amount("0.3")).is_ok());
+    }
+
+    #[test]
+    fn verify_lists_every_violation() {
+        let mut state = process_input_file(
+            "test-data/test.csv",
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+
+        assert!(verify_ledger(&state).is_ok());
+
+        state.clients.get_mut(&1).unwrap().held = amount("-1.0");
+
+        let err = verify_ledger(&state).unwrap_err();
+        assert!(err.starts_with("ledger invariants violated: client 1: available"));
+        assert!(err.ends_with("; client 1: held -1.0 is negative"));
+    }
+
+    #[test]
+    fn dumped_transfers_round_trip() {
+        let state = process_input_file(
+            &String::from("test-data/test.csv"),
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+
+        let mut dump = Vec::new();
+        state.write_ledger(&mut dump).unwrap();
+
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(dump.as_slice());
+        let reparsed: HashMap<u32, Transaction> = reader
+            .deserialize()
+            .map(|r| {
+                let tx: Transaction = r.unwrap();
+                (tx.id, tx)
+            })
+            .collect();
+
+        assert!(!reparsed.is_empty());
+        assert_eq!(reparsed, state.transfers);
+    }
+
+    #[test]
+    fn first_error_only_reports_first() {
+        let path = String::from("test-data/errors.csv");
+        let err = process_until_first_error(
+            &path,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "line 3: insufficient available funds");
+    }
+
+    #[test]
+    fn first_error_only_passes_clean_file() {
+        let path = String::from("test-data/merge_b.csv");
+        let state = process_until_first_error(
+            &path,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+
+        assert_eq!(state.clients.len(), 2);
+    }
+
+    #[test]
+    fn schema_line_precedes_header() {
+        let mut clients = HashMap::new();
+        clients.insert(1, Client::new(1));
+
+        let options = Options {
+            schema_header: true,
+            ..Options::default()
+        };
+
+        let mut output = Vec::new();
+        write_client_state(&clients, None, &mut output, &options).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+
+        assert_eq!(lines.next(), Some("# schema: v2"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("client,available,held,total,locked"));
+    }
+
+    #[test]
+    fn unbalanced_client_warns() {
+        let mut client = Client::new(7);
+        client.available = amount("1.0");
+        client.total = amount("2.0");
+
+        let warning = balance_warning(&client).unwrap();
+
+        assert!(warning.contains("client 7"));
+    }
+
+    #[test]
+    fn rejects_flushed_on_early_termination() {
+        let rejects_path =
+            env::temp_dir().join(format!("first-error-rejects-{}.csv", process::id()));
+        let mut sinks = Sinks {
+            rejects: Some(RejectsFile::create(&rejects_path).unwrap()),
+            ..Sinks::default()
+        };
+
+        let path = String::from("test-data/errors.csv");
+        let state = State::with_recent_rejects_capacity(BATCH_SIZE);
+        assert!(
+            process_until_first_error(&path, &InputReader::default(), state, &mut sinks).is_err()
+        );
+        sinks.rejects.unwrap().flush().unwrap();
+
+        let written = std::fs::read_to_string(&rejects_path).unwrap();
+
+        assert_eq!(written, "tx,reason\n2,insufficient available funds\n");
+
+        std::fs::remove_file(rejects_path).unwrap();
+    }
+
+    #[test]
+    fn snapshots_written_every_n_rows() {
+        let dir = env::temp_dir().join(format!("snapshots-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sinks = Sinks {
+            snapshots: Some(Snapshots::new(dir.clone(), 5)),
+            ..Sinks::default()
+        };
+
+        let path = String::from("test-data/test.csv");
+        process_input_file(&path, &InputReader::default(), State::new(), &mut sinks).unwrap();
+
+        let mut written: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort_by_key(|name| name.len());
+
+        assert_eq!(written.len(), 2);
+        assert!(written[0].starts_with("snapshot-") && written[0].ends_with("-5.csv"));
+        assert!(written[1].ends_with("-10.csv"));
+
+        let first = std::fs::read_to_string(dir.join(&written[0])).unwrap();
+        assert!(first.starts_with("client,available,held,total,locked"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_input_refused_but_header_only_accepted() {
+        let process = |path: &str| {
+            process_input_file(
+                path,
+                &InputReader::default(),
+                State::new(),
+                &mut Sinks::default(),
+            )
+        };
+
+        assert!(process("test-data/header_only.csv")
+            .unwrap()
+            .clients
+            .is_empty());
+        assert!(matches!(
+            process("test-data/empty.csv"),
+            Err(ProcessError::MissingHeader)
+        ));
+    }
+
+    #[test]
+    fn only_listed_clients_in_output() {
+        let args: Vec<String> = ["test-data/test.csv", "--only-clients", "2,3"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let options = parse_args(&args).unwrap();
+
+        let mut state = State::new();
+        state.config.only_clients = options.only_clients.clone();
+        let state = process_input_file(
+            &options.paths[0],
+            &InputReader::default(),
+            state,
+            &mut Sinks::default(),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        write_client_state(&state.clients, None, &mut output, &options).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(state.clients.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.lines().nth(1).unwrap().starts_with("2,"));
+        assert_eq!(state.rejected, 1);
+    }
+
+    #[test]
+    fn precision_loss_flags_parsed() {
+        let args: Vec<String> = [
+            "--shadow-ledger",
+            "--error-budget",
+            "--round-input",
+            "a.csv",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        let options = parse_args(&args).unwrap();
+
+        assert_eq!(options.paths, vec!["a.csv"]);
+        assert!(options.shadow_ledger && options.error_budget);
+
+        let state = initial_state(&options);
+        assert_eq!(state.shadow_ledger, Some(ShadowLedger::default()));
+        assert_eq!(state.error_budget, Some(ErrorBudget::default()));
+    }
+
+    #[test]
+    fn overdraft_limits_parsed() {
+        let args: Vec<String> = ["test.csv", "--overdraft-limits", "1:50.0, 7:10"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert_eq!(
+            parse_args(&args).unwrap().overdraft_limits,
+            vec![(1, amount("50.0")), (7, amount("10"))]
+        );
+
+        for bad in ["1", "x:5", "1:-5"] {
+            let args = vec![
+                String::from("test.csv"),
+                String::from("--overdraft-limits"),
+                String::from(bad),
+            ];
+
+            assert!(parse_args(&args).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn invalid_client_list() {
+        let args: Vec<String> = ["test.csv", "--only-clients", "1,x"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert_eq!(
+            parse_args(&args).err(),
+            Some(String::from("Invalid client id 'x' for --only-clients"))
+        );
+    }
+
+    #[test]
+    fn parquet_output_options() {
+        let args: Vec<String> = ["test.csv", "--output", "parquet", "--locale", "de-DE"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert!(parse_args(&args).is_err());
+        assert_eq!(parse_args(&args[..3]).is_ok(), cfg!(feature = "parquet"));
+    }
+
+    #[test]
+    fn json_output() {
+        let mut clients = HashMap::new();
+        for id in [2, 1] {
+            let mut client = Client::new(id);
+            client.available = "1.23456".parse().unwrap();
+            client.total = client.available;
+            client.locked = id == 2;
+            clients.insert(id, client);
+        }
+
+        let mut output = Vec::new();
+        write_json_report(&clients, DEFAULT_PRECISION, &mut output).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let report = report.as_array().unwrap();
+
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].get("client").unwrap().as_u64(), Some(1));
+        assert_eq!(report[0].get("available").unwrap().as_f64(), Some(1.2346));
+        assert_eq!(report[0].get("locked").unwrap().as_bool(), Some(false));
+        assert_eq!(report[1].get("locked").unwrap().as_bool(), Some(true));
+
+        let args: Vec<String> = ["test.csv", "--format", "json", "--schema-header"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert!(parse_args(&args).is_err());
+        assert_eq!(parse_args(&args[..3]).unwrap().output, OutputFormat::Json);
+    }
+
+    #[test]
+    fn long_balances_written_exactly() {
+        let mut state = State::new();
+        for id in 1..=30 {
+            let deposit = Transaction::deposit(1, id, "92233720368.1237".parse().unwrap());
+            state = processor::process_transaction(state, deposit);
+        }
+        let deposit = Transaction::deposit(1, 31, "0.0001".parse().unwrap());
+        state = processor::process_transaction(state, deposit);
+
+        let (mut csv, mut json) = (Vec::new(), Vec::new());
+        write_client_state(&state.clients, None, &mut csv, &Options::default()).unwrap();
+        write_json_report(&state.clients, DEFAULT_PRECISION, &mut json).unwrap();
+
+        // 17 significant digits, which a float would round to 2767011611043.7114
+        assert!(String::from_utf8(csv)
+            .unwrap()
+            .contains("1,2767011611043.7111,0.0,2767011611043.7111,"));
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains("\"total\":2767011611043.7111,"));
+
+        let mut client = Client::new(2);
+        client.available = "92233720368.12345678".parse().unwrap();
+        client.total = client.available;
+        let clients = HashMap::from([(2, client)]);
+        let options = Options {
+            precision: Some(8),
+            ..Options::default()
+        };
+
+        let (mut csv, mut json) = (Vec::new(), Vec::new());
+        write_client_state(&clients, None, &mut csv, &options).unwrap();
+        write_json_report(&clients, 8, &mut json).unwrap();
+
+        assert!(String::from_utf8(csv)
+            .unwrap()
+            .contains("2,92233720368.12345678,0.0,92233720368.12345678,"));
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains("\"available\":92233720368.12345678,"));
+    }
+
+    #[test]
+    fn audit_log_written_as_csv() {
+        let mut state = State::new();
+        state.audit_log = Some(Vec::new());
+        let state = processor::process_transaction_file("test-data/test.csv", state).unwrap();
+        let mut output = Vec::new();
+        write_audit_log(state.audit_log.as_ref().unwrap(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+
+        assert_eq!(lines.next(), Some("tx,client,type,outcome,reason"));
+        assert_eq!(lines.next(), Some("1,1,deposit,applied,"));
+        assert_eq!(lines.count(), state.audit_log.unwrap().len() - 1);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn output_independent_of_thread_count() {
+        let paths = vec![
+            String::from("test-data/test.csv"),
+            String::from("test-data/daily_1.csv"),
+            String::from("test-data/daily_2.csv"),
+        ];
+        let options = Options::default();
+
+        let serial = process_input_files(
+            &paths,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+        let mut expected = Vec::new();
+        write_client_state(&serial.clients, None, &mut expected, &options).unwrap();
+
+        for threads in [1, 2, 4] {
+            let state = process_input_files_parallel(
+                &paths,
+                &InputReader::default(),
+                State::new(),
+                threads,
+                &mut None,
+            )
+            .unwrap();
+            let mut output = Vec::new();
+            write_client_state(&state.clients, None, &mut output, &options).unwrap();
+
+            assert_eq!(output, expected, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn threads_options() {
+        let args: Vec<String> = ["test.csv", "--threads", "4", "--audit-log", "audit.csv"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert!(parse_args(&args).is_err());
+        assert_eq!(parse_args(&args[..3]).is_ok(), cfg!(feature = "parallel"));
+    }
+
+    #[test]
+    fn check_reports_every_invalid_row() {
+        let problems = check_input_file(
+            "test-data/invalid.csv",
+            &InputReader::default(),
+            &State::new(),
+        )
+        .unwrap();
+        let lines: Vec<&str> = problems
+            .iter()
+            .map(|problem| problem.split(':').next().unwrap())
+            .collect();
+
+        // line 6 only has a field past the header, which is ignored
+        assert_eq!(lines, ["line 3", "line 4", "line 5", "line 7", "line 8"]);
+        assert_eq!(problems[0], "line 3: transaction 2: amount is negative");
+        assert!(problems[2].contains("invalid amount 'abc'"));
+
+        let paths = vec![String::from("test-data/invalid.csv")];
+        let err = check_input_files(&paths, &InputReader::default(), &State::new()).unwrap_err();
+        assert_eq!(err.to_string(), "5 invalid rows");
+
+        let paths = vec![String::from("test-data/test.csv")];
+        assert!(check_input_files(&paths, &InputReader::default(), &State::new()).is_ok());
+    }
+
+    #[test]
+    fn check_rejects_digits_past_max_precision() {
+        let path = env::temp_dir().join(format!("check-excess-{}.csv", process::id()));
+        fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.000000001\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut state = State::new();
+        state.config.excess_decimals = ExcessDecimals::Reject;
+        let problems = check_input_file(path, &InputReader::default(), &state).unwrap();
+        assert_eq!(
+            problems,
+            ["line 2: transaction 1: amount has more decimal places than the ledger"]
+        );
+
+        let problems = check_input_file(path, &InputReader::default(), &State::new()).unwrap();
+        assert!(problems.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bom_and_semicolon_files_match_plain_csv() {
+        let output = |path: &str, delimiter: u8| {
+            let mut input = InputReader::default();
+            input.delimiter(delimiter);
+            let paths = vec![path.to_string()];
+            let state =
+                process_input_files(&paths, &input, State::new(), &mut Sinks::default()).unwrap();
+
+            let mut output = Vec::new();
+            write_client_state(&state.clients, None, &mut output, &Options::default()).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        // both hold the first rows of test.csv
+        let expected = "client,available,held,total,locked,peak_total,escrow,\
+                        transaction_count,last_tx_id\n\
+                        1,1.5,0.0,1.5,false,3.0,0.0,3,4\n\
+                        2,2.0,0.0,2.0,false,2.0,0.0,1,2\n";
+        assert_eq!(output("test-data/bom.csv", b','), expected);
+        assert_eq!(output("test-data/semicolons.csv", b';'), expected);
+    }
+
+    #[test]
+    fn formatted_amounts_read_with_their_format() {
+        let mut input = InputReader::default();
+        input.amounts = AmountFormat::Us;
+        let paths = vec![String::from("test-data/us_amounts.csv")];
+        let state =
+            process_input_files(&paths, &input, State::new(), &mut Sinks::default()).unwrap();
+
+        assert_eq!(state.clients[&1].available, amount("750.25"));
+        assert_eq!(state.clients[&2].available, amount("20"));
+
+        // `2,5` isn't grouped in thousands, so its row is skipped
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec!["skipped line 5 of test-data/us_amounts.csv: invalid amount '2,5'"]
+        );
+    }
+
+    #[test]
+    fn diff_compares_two_files() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            parse_args(&args)
+        };
+
+        let options = parse(&["diff", "test-data/bom.csv", "test-data/test.csv"]).unwrap();
+        let input = InputReader::default();
+
+        // bom.csv stops before client 1's deposit is disputed and charged back
+        let err = diff_files(&options, &input).unwrap_err();
+        assert_eq!(err.to_string(), "1 client differs");
+
+        let options = parse(&["diff", "test-data/test.csv", "test-data/test.csv"]).unwrap();
+        assert!(diff_files(&options, &input).is_ok());
+
+        for args in [
+            &["diff", "test-data/test.csv"][..],
+            &["diff", "a.csv", "b.csv", "--event-log", "events.csv"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn gen_writes_input_the_tool_accepts() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            parse_args(&args)
+        };
+
+        let options = parse(&["gen", "500", "--clients", "5", "--dispute-rate", "0.1"]).unwrap();
+        let generate = options.generate.unwrap();
+        assert_eq!((generate.rows, generate.clients), (500, 5));
+
+        let path = env::temp_dir().join(format!("gen-{}.csv", process::id()));
+        generate.write(File::create(&path).unwrap()).unwrap();
+        let state = process_input_file(
+            path.to_str().unwrap(),
+            &InputReader::default(),
+            initial_state(&Options::default()),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(state.stats.rows, 500);
+        assert_eq!(state.stats.skipped, 0);
+
+        for args in [
+            &["gen"][..],
+            &["gen", "10", "20"],
+            &["gen", "10", "--clients", "0"],
+            &["gen", "10", "--dispute-rate", "1.5"],
+            &["gen", "10", "--verbose"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn delimiter_options() {
+        for (value, expected) in [(";", Some(b';')), ("tab", Some(b'\t')), (";;", None)] {
+            let args: Vec<String> = ["test.csv", "--delimiter", value]
+                .iter()
+                .map(|a| a.to_string())
+                .collect();
+
+            assert_eq!(parse_args(&args).ok().and_then(|o| o.delimiter), expected);
+        }
+    }
+
+    #[test]
+    fn no_flags_give_the_default_config() {
+        let args = vec![String::from("test.csv")];
+        let options = parse_args(&args).unwrap();
+
+        assert_eq!(engine_config(&options), EngineConfig::default());
+    }
+
+    #[test]
+    fn stdin_read_at_most_once() {
+        let args: Vec<String> = ["-", "later.csv"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(parse_args(&args).unwrap().paths, args);
+
+        let args: Vec<String> = ["-", "-"].iter().map(|a| a.to_string()).collect();
+        assert!(parse_args(&args).is_err());
+
+        let args: Vec<String> = ["replay-events", "-"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn precision_options() {
+        let args: Vec<String> = ["test.csv", "--precision", "8"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().precision, Some(8));
+
+        let args: Vec<String> = ["test.csv", "--precision", "9"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert!(parse_args(&args).is_err());
+
+        let args: Vec<String> = ["test.csv", "--excess-decimals", "round-half-even"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            parse_args(&args).unwrap().excess_decimals,
+            ExcessDecimals::RoundHalfEven
+        );
+
+        let args: Vec<String> = ["test.csv", "--excess-decimals", "truncate"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn same_input_gives_identical_output() {
+        let paths = vec![String::from("test-data/test.csv")];
+        let options = Options::default();
+
+        // every run hashes with fresh keys, so the client maps iterate in different orders
+        let mut outputs = Vec::new();
+        for _ in 0..2 {
+            let state = process_input_files(
+                &paths,
+                &InputReader::default(),
+                State::new(),
+                &mut Sinks::default(),
+            )
+            .unwrap();
+
+            let mut csv = Vec::new();
+            write_client_state(&state.clients, None, &mut csv, &options).unwrap();
+            let mut json = Vec::new();
+            write_json_report(&state.clients, state.config.precision, &mut json).unwrap();
+            outputs.push((csv, json));
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn client_output_is_canonical() {
+        let ids = [7, 1, 300, 42, 2];
+        let options = Options::default();
+
+        let mut outputs = Vec::new();
+        for order in [ids.to_vec(), ids.iter().rev().copied().collect()] {
+            let mut clients = HashMap::new();
+            for id in order {
+                let mut client = Client::new(id);
+                client.available = Amount::from_whole(id as i64).unwrap();
+                client.total = client.available;
+                clients.insert(id, client);
+            }
+
+            let mut output = Vec::new();
+            write_client_state(&clients, None, &mut output, &options).unwrap();
+            outputs.push(output);
+        }
+
+        let output = String::from_utf8(outputs[0].clone()).unwrap();
+        let ids: Vec<&str> = output
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').next().unwrap())
+            .collect();
+
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(ids, vec!["1", "2", "7", "42", "300"]);
+    }
+
+    #[test]
+    fn oversized_file_aborts() {
+        let paths = vec![
+            String::from("test-data/merge_b.csv"),
+            String::from("test-data/test.csv"),
+        ];
+        let size = fs::metadata(&paths[1]).unwrap().len();
+
+        let err = check_file_sizes(&paths, size - 1).unwrap_err();
+
+        assert!(err.to_string().starts_with("'test-data/test.csv' is"));
+        assert!(check_file_sizes(&paths, size).is_ok());
+    }
+
+    #[test]
+    fn balance_sheet_totals() {
+        let mut first = Client::new(1);
+        first.available = amount("3.0");
+        first.held = amount("1.5");
+        first.total = amount("4.5");
+
+        let mut second = Client::new(2);
+        second.available = amount("2.0");
+        second.total = amount("2.0");
+
+        let mut locked = Client::new(3);
+        locked.locked = true;
+
+        let clients: HashMap<u16, Client> = [first, second, locked]
+            .into_iter()
+            .map(|c| (c.id, c))
+            .collect();
+        let sheet = BalanceSheet::new(&clients);
+
+        assert_eq!(
+            sheet,
+            BalanceSheet {
+                assets: amount("6.5"),
+                held: amount("1.5"),
+                available: amount("5.0"),
+            }
+        );
+        assert_eq!(
+            sheet.to_string(),
+            "total assets: 6.5\ntotal held: 1.5\ntotal available: 5.0\n"
+        );
+    }
+
+    #[test]
+    fn missing_file_is_io_error() {
+        let result = process_input_file(
+            &String::from("test-data/does-not-exist.csv"),
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        );
+
+        assert!(matches!(result, Err(ProcessError::Io(_))));
+    }
+
+    #[test]
+    fn ragged_rows_read_and_unreadable_rows_skipped() {
+        let process = |path: &str| {
+            process_input_file(
+                path,
+                &InputReader::default(),
+                State::new(),
+                &mut Sinks::default(),
+            )
+            .unwrap()
+        };
+
+        let state = process("test-data/trailing_commas.csv");
+
+        assert_eq!(state.clients[&1].available, amount("1.5"));
+        assert_eq!(state.clients[&2].held, amount("1.5"));
+        assert_eq!(state.rejected, 0);
+
+        // an extra trailing column is ignored, a missing amount column reads as blank, and
+        // rows without a tx are skipped rather than failing the file
+        let state = process("test-data/ragged.csv");
+
+        assert_eq!(state.clients[&1].available, amount("3.0"));
+        assert_eq!(state.clients[&2].held, amount("3.0"));
+        assert_eq!(state.rejected, 0);
+        assert_eq!(state.stats.skipped, 2);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "skipped line 5 of test-data/ragged.csv: field 2: cannot parse integer from empty string",
+                "skipped line 7 of test-data/ragged.csv: field 2: cannot parse integer from empty string",
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_range_client_ids_skipped() {
+        let path = "test-data/client_out_of_range.csv";
+        let state = process_input_file(
+            path,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+
+        assert_eq!(state.clients.len(), 2);
+        assert_eq!(state.clients[&1].available, amount("0.5"));
+        assert_eq!(state.clients[&2].available, amount("2.0"));
+        assert_eq!(state.stats.skipped, 2);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "skipped line 3 of test-data/client_out_of_range.csv: client id 70000 is out \
+                 of range, it must be from 0 to 65535",
+                "skipped line 4 of test-data/client_out_of_range.csv: client id -3 is out of \
+                 range, it must be from 0 to 65535",
+            ]
+        );
+    }
+
+    #[test]
+    fn columnar_output_matches() {
+        let path = String::from("test-data/test.csv");
+        let state = process_input_file(
+            &path,
+            &InputReader::default(),
+            State::new(),
+            &mut Sinks::default(),
+        )
+        .unwrap();
+        let columns = ClientColumns::from_clients(&state.clients);
+
+        for locale in [None, Some(Locale::DeDe)] {
+            let options = Options {
+                locale,
+                ..Options::default()
+            };
+
+            let mut from_map = Vec::new();
+            write_client_state(&state.clients, None, &mut from_map, &options).unwrap();
+            let mut from_columns = Vec::new();
+            write_client_state(&state.clients, Some(&columns), &mut from_columns, &options)
+                .unwrap();
+
+            assert_eq!(from_map, from_columns);
+        }
+
+        assert_eq!(
+            BalanceSheet::from_columns(&columns),
+            BalanceSheet::new(&state.clients)
+        );
+    }
+
+    #[test]
+    fn extended_output_has_largest_deposit_and_withdrawal() {
+        let mut state = State::new();
+        for tx in [
+            Transaction::deposit(1, 1, amount("2.0")),
+            Transaction::deposit(1, 2, amount("7.5")),
+            Transaction::withdrawal(1, 3, amount("3.0")),
+            Transaction::withdrawal(1, 4, amount("20.0")),
+        ] {
+            state = processor::process_transaction(state, tx);
+        }
+
+        let args = vec![String::from("--extended"), String::from("a.csv")];
+        let options = parse_args(&args).unwrap();
+
+        let mut output = Vec::new();
+        write_client_state(&state.clients, None, &mut output, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,peak_total,escrow,transaction_count,last_tx_id,\
+             max_deposit,max_withdrawal\n1,6.5,0.0,6.5,false,9.5,0.0,3,3,7.5,3.0\n"
+        );
+
+        let args = vec![
+            String::from("--extended"),
+            String::from("--columns"),
+            String::from("client"),
+            String::from("a.csv"),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn chosen_columns_written_in_order() {
+        let mut state = State::new();
+        for tx in [
+            Transaction::deposit(2, 1, amount("1234.5")),
+            Transaction::deposit(1, 2, amount("2.0")),
+            Transaction::dispute(1, 2),
+        ] {
+            state = processor::process_transaction(state, tx);
+        }
+
+        let args = vec![
+            String::from("--columns"),
+            String::from("total,client,locked"),
+            String::from("a.csv"),
+        ];
+        let mut options = parse_args(&args).unwrap();
+
+        let mut output = Vec::new();
+        write_client_state(&state.clients, None, &mut output, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "total,client,locked\n2.0,1,false\n1234.5,2,false\n"
+        );
+
+        options.locale = Some(Locale::DeDe);
+        let mut output = Vec::new();
+        write_client_state(&state.clients, None, &mut output, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "total,client,locked\n\"2,0\",1,false\n\"1.234,5\",2,false\n"
+        );
+
+        let args = vec![
+            String::from("--columns"),
+            String::from("client,balance"),
+            String::from("a.csv"),
+        ];
+        match parse_args(&args) {
+            Err(err) => assert!(err.starts_with("Unknown column 'balance'")),
+            Ok(_) => panic!("an unknown column was accepted"),
+        }
+    }
+
+    #[test]
+    fn drained_output_matches() {
+        let mut state = (1..=1000u16)
+            .map(|id| Transaction::deposit(id, id.into(), amount("1.5")))
+            .fold(State::new(), processor::process_transaction);
+        state = [Transaction::dispute(7, 7), Transaction::chargeback(7, 7)]
+            .into_iter()
+            .fold(state, processor::process_transaction);
+
+        let options = Options {
+            schema_header: true,
+            ..Options::default()
+        };
+
+        let mut expected = Vec::new();
+        write_client_state(&state.clients, None, &mut expected, &options).unwrap();
+        let mut drained = Vec::new();
+        write_and_drain_client_state(&mut state, &mut drained, &options).unwrap();
+
+        assert_eq!(drained, expected);
+        assert!(state.clients.is_empty());
+        assert_eq!(state.locked_clients().count(), 0);
+    }
+
+    #[test]
+    fn clients_written_in_first_seen_order() {
+        let args: Vec<String> = ["test.csv", "--order", "first-seen", "--columns", "client"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let options = parse_args(&args).unwrap();
+
+        let mut state = [
+            Transaction::deposit(5, 1, amount("1.0")),
+            Transaction::deposit(2, 2, amount("1.0")),
+            Transaction::withdrawal(5, 3, amount("0.5")),
+            Transaction::deposit(9, 4, amount("1.0")),
+        ]
+        .into_iter()
+        .fold(initial_state(&options), processor::process_transaction);
+
+        let mut output = Vec::new();
+        write_and_drain_client_state(&mut state, &mut output, &options).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "client\n5\n2\n9\n");
+    }
+
+    #[test]
+    fn unmatched_early_disputes_rejected_at_end() {
+        let rejects_path =
+            env::temp_dir().join(format!("early-dispute-rejects-{}.csv", process::id()));
+        let mut sinks = Sinks {
+            rejects: Some(RejectsFile::create(&rejects_path).unwrap()),
+            ..Sinks::default()
+        };
+
+        let path = String::from("test-data/early_dispute.csv");
+        let mut state = State::with_recent_rejects_capacity(BATCH_SIZE);
+        state.config.defer_early_disputes = true;
+
+        let state = process_input_file(&path, &InputReader::default(), state, &mut sinks).unwrap();
+        let state = reject_deferred_disputes(state, &mut sinks.rejects).unwrap();
+        sinks.rejects.unwrap().flush().unwrap();
+
+        assert_eq!(state.clients[&1].held, amount("2.0"));
+        assert_eq!(state.clients[&1].available, Amount::ZERO);
+        assert_eq!(state.clients[&2].available, amount("1.0"));
+
+        let written = std::fs::read_to_string(&rejects_path).unwrap();
+
+        assert_eq!(
+            written,
+            "tx,reason\n3,referenced transaction does not exist\n"
+        );
+
+        std::fs::remove_file(rejects_path).unwrap();
+
+        let args = vec![
+            String::from("--defer-early-disputes"),
+            String::from("--event-log"),
+            String::from("events.csv"),
+            String::from("a.csv"),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    fn withdrawal_only_client(include_seen_clients: bool) -> State {
+        let path = String::from("test-data/withdrawal_only.csv");
+        let mut state = State::new();
+        if include_seen_clients {
+            state.seen_clients = Some(HashSet::new());
+        }
+
+        let mut state =
+            process_input_file(&path, &InputReader::default(), state, &mut Sinks::default())
+                .unwrap();
+        add_seen_clients(&mut state);
+
+        state
+    }
+
+    #[test]
+    fn seen_clients_get_placeholder_rows() {
+        let state = withdrawal_only_client(true);
+
+        assert_eq!(state.clients.len(), 2);
+        assert_eq!(state.clients[&2], Client::new(2));
+        assert_eq!(state.clients[&1].available, amount("1.0"));
+
+        let mut output = Vec::new();
+        write_client_state(&state.clients, None, &mut output, &Options::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.lines().any(|line| line.starts_with("2,0")));
+    }
+
+    #[test]
+    fn seen_clients_omitted_by_default() {
+        let state = withdrawal_only_client(false);
+
+        assert_eq!(state.clients.len(), 1);
+        assert!(!state.clients.contains_key(&2));
+        assert_eq!(state.rejected, 1);
+    }
+}
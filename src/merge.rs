@@ -0,0 +1,114 @@
+use std::{cmp::Reverse, collections::BinaryHeap, error::Error, mem};
+
+use csv::ErrorKind;
+
+use transaction_tool::{
+    input::{InputReader, TransactionRows},
+    types::Transaction,
+};
+
+// interleaves the transactions of several timestamped files in chronological order.
+// each file is expected to already be in timestamp order, as with any k-way merge,
+// and transactions with equal timestamps are taken in the order the files were given
+pub struct MergedTransactions {
+    sources: Vec<TransactionRows>,
+    pending: Vec<Option<Transaction>>,
+    heads: BinaryHeap<Reverse<(u64, usize)>>,
+
+    // rows that couldn't be read as transactions, with the source they came from, passed
+    // over until the caller takes them
+    unreadable: Vec<(usize, csv::Error)>,
+}
+
+impl MergedTransactions {
+    pub fn from_paths(paths: &[String], input: &InputReader) -> Result<Self, Box<dyn Error>> {
+        let mut merged = Self {
+            sources: Vec::with_capacity(paths.len()),
+            pending: Vec::with_capacity(paths.len()),
+            heads: BinaryHeap::with_capacity(paths.len()),
+            unreadable: Vec::new(),
+        };
+
+        for path in paths {
+            merged.sources.push(TransactionRows::open(input, path)?);
+            merged.pending.push(None);
+        }
+
+        for source in 0..merged.sources.len() {
+            merged.advance(source)?;
+        }
+
+        Ok(merged)
+    }
+
+    // the rows passed over since the last call
+    pub fn take_unreadable(&mut self) -> Vec<(usize, csv::Error)> {
+        mem::take(&mut self.unreadable)
+    }
+
+    // reads the next transaction of a source and queues it by its timestamp. rows that fail
+    // to deserialize are set aside for take_unreadable, but an error reading the file itself
+    // ends the merge
+    fn advance(&mut self, source: usize) -> Result<(), Box<dyn Error>> {
+        let transaction = loop {
+            match self.sources[source].next() {
+                Some(Ok(transaction)) => break transaction,
+                Some(Err(err)) if matches!(err.kind(), ErrorKind::Io(_)) => return Err(err.into()),
+                Some(Err(err)) => self.unreadable.push((source, err)),
+                None => return Ok(()),
+            }
+        };
+
+        let timestamp = transaction
+            .timestamp
+            .ok_or_else(|| format!("transaction {} is missing a timestamp", transaction.id))?;
+
+        self.heads.push(Reverse((timestamp, source)));
+        self.pending[source] = Some(transaction);
+
+        Ok(())
+    }
+}
+
+impl Iterator for MergedTransactions {
+    type Item = Result<Transaction, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, source)) = self.heads.pop()?;
+        let transaction = self.pending[source].take().unwrap();
+
+        if let Err(err) = self.advance(source) {
+            return Some(Err(err));
+        }
+
+        Some(Ok(transaction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge_paths() -> Vec<String> {
+        vec![
+            String::from("test-data/merge_a.csv"),
+            String::from("test-data/merge_b.csv"),
+        ]
+    }
+
+    #[test]
+    fn merged_in_timestamp_order() {
+        let merged =
+            MergedTransactions::from_paths(&merge_paths(), &InputReader::default()).unwrap();
+        let ids: Vec<u32> = merged.map(|r| r.unwrap().id).collect();
+
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn missing_timestamp_is_an_error() {
+        let paths = vec![String::from("test-data/test.csv")];
+
+        assert!(MergedTransactions::from_paths(&paths, &InputReader::default()).is_err());
+    }
+}
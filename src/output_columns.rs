@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use transaction_tool::{amount::Amount, types::Client};
+
+use crate::locale::Locale;
+
+// the columns of the client output, which --columns picks from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    PeakTotal,
+    Escrow,
+    TransactionCount,
+    LastTxId,
+    MaxDeposit,
+    MaxWithdrawal,
+}
+
+// every column, as written with --extended. the last two are left out of the default output
+pub const EXTENDED_COLUMNS: [OutputColumn; 11] = [
+    OutputColumn::Client,
+    OutputColumn::Available,
+    OutputColumn::Held,
+    OutputColumn::Total,
+    OutputColumn::Locked,
+    OutputColumn::PeakTotal,
+    OutputColumn::Escrow,
+    OutputColumn::TransactionCount,
+    OutputColumn::LastTxId,
+    OutputColumn::MaxDeposit,
+    OutputColumn::MaxWithdrawal,
+];
+
+impl OutputColumn {
+    // the header of the column, as in the full output
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputColumn::Client => "client",
+            OutputColumn::Available => "available",
+            OutputColumn::Held => "held",
+            OutputColumn::Total => "total",
+            OutputColumn::Locked => "locked",
+            OutputColumn::PeakTotal => "peak_total",
+            OutputColumn::Escrow => "escrow",
+            OutputColumn::TransactionCount => "transaction_count",
+            OutputColumn::LastTxId => "last_tx_id",
+            OutputColumn::MaxDeposit => "max_deposit",
+            OutputColumn::MaxWithdrawal => "max_withdrawal",
+        }
+    }
+}
+
+impl FromStr for OutputColumn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EXTENDED_COLUMNS
+            .into_iter()
+            .find(|column| column.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = EXTENDED_COLUMNS
+                    .iter()
+                    .map(|column| column.name())
+                    .collect();
+                format!(
+                    "Unknown column '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+// a comma separated list of distinct column names, e.g. `client,total`
+pub fn parse_columns(list: &str) -> Result<Vec<OutputColumn>, String> {
+    let mut columns = Vec::new();
+
+    for name in list.split(',') {
+        let column: OutputColumn = name.trim().parse()?;
+
+        if columns.contains(&column) {
+            return Err(format!("Column '{}' is listed twice", column.name()));
+        }
+
+        columns.push(column);
+    }
+
+    Ok(columns)
+}
+
+// a client row with only the chosen columns, in the order they were given. balances are
+// rendered for a locale if there is one, as in LocalizedClient
+pub struct SelectedClient<'a> {
+    client: &'a Client,
+    columns: &'a [OutputColumn],
+    locale: Option<Locale>,
+}
+
+impl<'a> SelectedClient<'a> {
+    pub fn new(client: &'a Client, columns: &'a [OutputColumn], locale: Option<Locale>) -> Self {
+        Self {
+            client,
+            columns,
+            locale,
+        }
+    }
+
+    fn serialize_amount<S: SerializeStruct>(
+        &self,
+        s: &mut S,
+        name: &'static str,
+        amount: Amount,
+    ) -> Result<(), S::Error> {
+        match self.locale {
+            Some(locale) => s.serialize_field(name, &locale.format_amount(amount)),
+            None => s.serialize_field(name, &amount),
+        }
+    }
+}
+
+impl Serialize for SelectedClient<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let client = self.client;
+        let mut s = serializer.serialize_struct("Client", self.columns.len())?;
+
+        for &column in self.columns {
+            let name = column.name();
+
+            match column {
+                OutputColumn::Client => s.serialize_field(name, &client.id)?,
+                OutputColumn::Available => self.serialize_amount(&mut s, name, client.available)?,
+                OutputColumn::Held => self.serialize_amount(&mut s, name, client.held)?,
+                OutputColumn::Total => self.serialize_amount(&mut s, name, client.total)?,
+                OutputColumn::Locked => s.serialize_field(name, &client.locked)?,
+                OutputColumn::PeakTotal => {
+                    self.serialize_amount(&mut s, name, client.peak_total)?
+                }
+                OutputColumn::Escrow => self.serialize_amount(&mut s, name, client.escrow)?,
+                OutputColumn::TransactionCount => {
+                    s.serialize_field(name, &client.transaction_count)?
+                }
+                OutputColumn::LastTxId => s.serialize_field(name, &client.last_tx_id)?,
+                OutputColumn::MaxDeposit => {
+                    self.serialize_amount(&mut s, name, client.max_deposit)?
+                }
+                OutputColumn::MaxWithdrawal => {
+                    self.serialize_amount(&mut s, name, client.max_withdrawal)?
+                }
+            }
+        }
+
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_lists_parsed_in_order() {
+        assert_eq!(
+            parse_columns("total, client").unwrap(),
+            vec![OutputColumn::Total, OutputColumn::Client]
+        );
+        assert_eq!(
+            parse_columns("client,balance").unwrap_err(),
+            "Unknown column 'balance', expected one of client, available, held, total, locked, \
+             peak_total, escrow, transaction_count, last_tx_id, max_deposit, max_withdrawal"
+        );
+        assert_eq!(
+            parse_columns("held,held").unwrap_err(),
+            "Column 'held' is listed twice"
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    mem,
+};
+
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+use crate::{
+    processor,
+    types::{RejectReason, State, Transaction, TransactionType},
+};
+
+// rows handed to the shards at a time. much larger than processor::BATCH_SIZE, so each
+// thread gets a good run of work between the points where they all wait for each other
+pub const PARALLEL_BATCH_SIZE: usize = 1 << 20;
+
+// a state split into shards of clients, each processed on its own thread. transactions for
+// different clients never interact, apart from through transaction ids and transfers. every
+// row that refers to an id is sent to the shard of the client that first used it, so each
+// shard sees all it needs, in input order, and the merged result matches processing
+// serially. a transfer between two shards waits for the rows before it, then runs on its own
+// with both clients in the source's shard.
+//
+// the one difference is an id reused by another client, which is rejected as a duplicate
+// even if its first use was itself rejected. the logs in the merged state, e.g.
+// recent_rejects and audit_log, are in input order within a shard but grouped by shard
+pub struct ShardedState {
+    // the state passed in, minus its clients and transfers, which moved to the shards
+    base: State,
+    shards: Vec<State>,
+
+    // the client each deposit, withdrawal or transfer id was first used by
+    owners: HashMap<u32, u16>,
+
+    pool: ThreadPool,
+}
+
+impl ShardedState {
+    pub fn new(mut state: State, threads: usize) -> Result<Self, ThreadPoolBuildError> {
+        let threads = threads.max(1);
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+
+        let mut shards: Vec<State> = (0..threads).map(|_| state.empty_like()).collect();
+        for (client_id, client) in state.clients.drain() {
+            shards[shard_of(client_id, threads)]
+                .clients
+                .insert(client_id, client);
+        }
+
+        // each shard lists the locked clients it was given, and the base keeps none
+        state.index_locked_clients();
+        for shard in &mut shards {
+            shard.index_locked_clients();
+        }
+
+        let mut owners = HashMap::new();
+        for (tx_id, transaction) in state.transfers.drain() {
+            // only transactions with a client are ever applied
+            if let Some(client_id) = transaction.client_id {
+                owners.insert(tx_id, client_id);
+                shards[shard_of(client_id, threads)]
+                    .transfers
+                    .insert(tx_id, transaction);
+            }
+        }
+
+        Ok(Self {
+            base: state,
+            shards,
+            owners,
+            pool,
+        })
+    }
+
+    // routes the transactions to their shards, then processes the shards in parallel
+    pub fn process(&mut self, transactions: Vec<Transaction>) {
+        let mut parts: Vec<Vec<Transaction>> = self.shards.iter().map(|_| Vec::new()).collect();
+
+        for transaction in transactions {
+            match self.route(&transaction) {
+                Route::Shard(shard) => parts[shard].push(transaction),
+                Route::Across(source, dest) => {
+                    self.run(&mut parts);
+                    self.transfer_across(transaction, source, dest);
+                }
+                Route::Conflict => {
+                    self.base
+                        .record_reject(transaction.id, RejectReason::ConflictingTransaction);
+                    self.base.record_audit(
+                        transaction.id,
+                        transaction.client_id,
+                        transaction.transaction_type,
+                    );
+                }
+            }
+        }
+
+        self.run(&mut parts);
+    }
+
+    // processes and empties each shard's part in parallel
+    fn run(&mut self, parts: &mut [Vec<Transaction>]) {
+        if parts.iter().all(Vec::is_empty) {
+            return;
+        }
+
+        let shards = &mut self.shards;
+        self.pool.install(|| {
+            shards.par_iter_mut().zip(parts).for_each(|(shard, part)| {
+                *shard = processor::process_batch(mem::take(shard), mem::take(part))
+            });
+        });
+    }
+
+    // applies a transfer to a client in another shard, by lending the destination client
+    // to the source's shard for the one transaction
+    fn transfer_across(&mut self, transaction: Transaction, source: usize, dest: usize) {
+        let dest_id = transaction.dest_client_id.unwrap();
+
+        if let Some(client) = self.shards[dest].clients.remove(&dest_id) {
+            self.shards[source].clients.insert(dest_id, client);
+        }
+
+        let shard = mem::take(&mut self.shards[source]);
+        self.shards[source] = processor::process_transaction(shard, transaction);
+
+        if let Some(client) = self.shards[source].clients.remove(&dest_id) {
+            self.shards[dest].clients.insert(dest_id, client);
+        }
+    }
+
+    // see State::skip_unreadable_row
+    pub fn skip_unreadable_row(&mut self, location: &str, reason: &str) {
+        self.base.skip_unreadable_row(location, reason);
+    }
+
+    pub fn into_state(self) -> State {
+        let mut state = self.base;
+        for shard in self.shards {
+            state.merge(shard);
+        }
+
+        state
+    }
+
+    fn route(&mut self, transaction: &Transaction) -> Route {
+        let count = self.shards.len();
+        let own_shard = transaction
+            .client_id
+            .map_or(0, |client_id| shard_of(client_id, count));
+
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer => {
+                let client_id = match transaction.client_id {
+                    // a filtered out client's row claims nothing, as it never enters the state
+                    Some(client_id) if self.base.accepts_client(client_id) => client_id,
+                    _ => return Route::Shard(own_shard),
+                };
+
+                match self.owners.entry(transaction.id) {
+                    Entry::Vacant(slot) => {
+                        slot.insert(client_id);
+                    }
+                    Entry::Occupied(owner) if *owner.get() == client_id => {}
+                    Entry::Occupied(_) => return Route::Conflict,
+                }
+
+                match transaction.dest_client_id {
+                    Some(dest_id)
+                        if transaction.transaction_type == TransactionType::Transfer
+                            && shard_of(dest_id, count) != own_shard =>
+                    {
+                        Route::Across(own_shard, shard_of(dest_id, count))
+                    }
+                    _ => Route::Shard(own_shard),
+                }
+            }
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                match self.owners.get(&transaction.id) {
+                    Some(&owner) => Route::Shard(shard_of(owner, count)),
+                    None => Route::Shard(own_shard),
+                }
+            }
+            TransactionType::Freeze | TransactionType::Thaw => Route::Shard(own_shard),
+        }
+    }
+}
+
+// where a transaction is sent by ShardedState::process
+enum Route {
+    Shard(usize),
+    // a transfer from a client in the first shard to one in the second
+    Across(usize, usize),
+    // a deposit, withdrawal or transfer reusing an id another client used first, which always
+    // conflicts with the first use. a repeat by the same client is judged in its shard
+    Conflict,
+}
+
+fn shard_of(client_id: u16, count: usize) -> usize {
+    client_id as usize % count
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+
+    fn transactions(path: &str) -> Vec<Transaction> {
+        ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_path(path)
+            .unwrap()
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn sharded_matches_serial() {
+        for path in ["test-data/test.csv", "test-data/daily_1.csv"] {
+            let serial = processor::process_batch(State::new(), transactions(path));
+
+            for threads in [1, 2, 4] {
+                let mut sharded = ShardedState::new(State::new(), threads).unwrap();
+                sharded.process(transactions(path));
+                let state = sharded.into_state();
+
+                assert_eq!(state.clients, serial.clients, "{} threads", threads);
+                assert_eq!(state.transfers, serial.transfers);
+                assert_eq!(state.rejected, serial.rejected);
+                assert_eq!(
+                    state.locked_clients().collect::<HashSet<_>>(),
+                    serial.locked_clients().collect::<HashSet<_>>()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transfers_across_shards_match_serial() {
+        let data = "type, client, tx, amount, dest\n\
+                    deposit, 1, 1, 10.0,\n\
+                    transfer, 1, 2, 4.0, 2\n\
+                    deposit, 2, 3, 1.0,\n\
+                    transfer, 2, 4, 5.0, 5\n\
+                    transfer, 1, 5, 100.0, 2\n\
+                    transfer, 5, 6, 2.0, 1\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let serial = processor::process_batch(State::new(), rows.clone());
+
+        for threads in [1, 2, 4] {
+            let mut sharded = ShardedState::new(State::new(), threads).unwrap();
+            sharded.process(rows.clone());
+            let state = sharded.into_state();
+
+            assert_eq!(state.clients, serial.clients, "{} threads", threads);
+            assert_eq!(state.rejected, serial.rejected);
+        }
+    }
+
+    #[test]
+    fn disputes_follow_the_owner() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 5.0\n\
+                    dispute, 2, 1,\n\
+                    deposit, 2, 1, 3.0\n\
+                    dispute, 1, 1,\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let mut sharded = ShardedState::new(State::new(), 2).unwrap();
+        sharded.process(rows);
+        let state = sharded.into_state();
+
+        // client 2's dispute and reused id are both rejected, and client 1's dispute applies
+        assert_eq!(state.rejected, 2);
+        assert_eq!(state.clients[&1].held, "5.0".parse().unwrap());
+        assert!(!state.clients.contains_key(&2));
+    }
+}
@@ -0,0 +1,170 @@
+use std::{error::Error, io::Write, sync::Arc};
+
+use arrow::{
+    array::{ArrayRef, BooleanArray, Decimal128Array, UInt16Array, UInt32Array, UInt64Array},
+    datatypes::{DataType, Field, Schema, DECIMAL128_MAX_PRECISION},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use parquet::arrow::ArrowWriter;
+use transaction_tool::{
+    amount::Amount,
+    types::{Client, MAX_PRECISION},
+};
+
+// the same columns as the CSV report, typed so analytics tools can load them directly
+fn client_schema(precision: u32) -> Schema {
+    let balance = DataType::Decimal128(DECIMAL128_MAX_PRECISION, precision as i8);
+
+    Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", balance.clone(), false),
+        Field::new("held", balance.clone(), false),
+        Field::new("total", balance.clone(), false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("peak_total", balance.clone(), false),
+        Field::new("escrow", balance, false),
+        Field::new("transaction_count", DataType::UInt64, false),
+        Field::new("last_tx_id", DataType::UInt32, true),
+    ])
+}
+
+// decimals with the ledger precision as their scale, taken from the exact units, so each
+// balance reads back as exactly the amount the csv report prints, however many digits it has
+fn balance_column<F: Fn(&Client) -> Amount>(
+    clients: &[&Client],
+    precision: u32,
+    balance: F,
+) -> Result<ArrayRef, ArrowError> {
+    let step = 10i128.pow(MAX_PRECISION.saturating_sub(precision));
+    let values = clients
+        .iter()
+        .map(|&c| balance(c).round_to(precision).units() / step);
+
+    let column = Decimal128Array::from_iter_values(values)
+        .with_precision_and_scale(DECIMAL128_MAX_PRECISION, precision as i8)?;
+    Ok(Arc::new(column))
+}
+
+// writes the clients as a single row group, ordered by client id
+pub fn write_parquet<'a, W, I>(clients: I, precision: u32, writer: W) -> Result<(), Box<dyn Error>>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = &'a Client>,
+{
+    let mut clients: Vec<&Client> = clients.into_iter().collect();
+    clients.sort_by_key(|c| c.id);
+
+    let schema = Arc::new(client_schema(precision));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt16Array::from_iter_values(clients.iter().map(|c| c.id))),
+        balance_column(&clients, precision, |c| c.available)?,
+        balance_column(&clients, precision, |c| c.held)?,
+        balance_column(&clients, precision, |c| c.total)?,
+        Arc::new(BooleanArray::from(
+            clients.iter().map(|c| c.locked).collect::<Vec<bool>>(),
+        )),
+        balance_column(&clients, precision, |c| c.peak_total)?,
+        balance_column(&clients, precision, |c| c.escrow)?,
+        Arc::new(UInt64Array::from_iter_values(
+            clients.iter().map(|c| c.transaction_count),
+        )),
+        Arc::new(UInt32Array::from(
+            clients
+                .iter()
+                .map(|c| c.last_tx_id)
+                .collect::<Vec<Option<u32>>>(),
+        )),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut writer = ArrowWriter::try_new(writer, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::File};
+
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+
+    #[test]
+    fn parquet_round_trip() {
+        let mut first = Client::new(2);
+        first.available = "1.5".parse().unwrap();
+        first.held = "0.25".parse().unwrap();
+        first.total = "1.75".parse().unwrap();
+
+        let mut second = Client::new(1);
+        second.locked = true;
+        second.total = "92233720368.12345678".parse().unwrap();
+
+        let written = |precision: u32| {
+            let path = env::temp_dir().join(format!(
+                "clients-{}-{}.parquet",
+                std::process::id(),
+                precision
+            ));
+            write_parquet([&first, &second], precision, File::create(&path).unwrap()).unwrap();
+
+            let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+                .unwrap()
+                .build()
+                .unwrap();
+            let batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+            std::fs::remove_file(path).unwrap();
+
+            batches[0].clone()
+        };
+        let decimal_column = |batch: &RecordBatch, name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Decimal128Array>()
+                .unwrap()
+                .clone()
+        };
+
+        let batch = written(4);
+        let ids = batch
+            .column_by_name("client")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt16Array>()
+            .unwrap()
+            .clone();
+        let locked = batch
+            .column_by_name("locked")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .unwrap()
+            .clone();
+
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(ids.values().to_vec(), vec![1, 2]);
+        assert_eq!(decimal_column(&batch, "available").scale(), 4);
+        assert_eq!(decimal_column(&batch, "available").value(1), 15000);
+        assert_eq!(decimal_column(&batch, "held").value_as_string(1), "0.2500");
+        assert_eq!(decimal_column(&batch, "total").value_as_string(1), "1.7500");
+        assert_eq!(
+            decimal_column(&batch, "total").value_as_string(0),
+            "92233720368.1235"
+        );
+        assert!(locked.value(0));
+        assert!(!locked.value(1));
+
+        // more digits than a float holds
+        let batch = written(8);
+        assert_eq!(
+            decimal_column(&batch, "total").value_as_string(0),
+            "92233720368.12345678"
+        );
+    }
+}
@@ -1,161 +1,913 @@
-use crate::types::{Client, State, Transaction, TransactionType};
+use std::{collections::hash_map::Entry, fs::File, mem, path::Path};
+
+use crate::{
+    amount::Amount,
+    audit::{AuditOutcome, Stats},
+    error::ProcessError,
+    input::{unreadable_row, InputReader, TransactionRows},
+    ledger::Ledger,
+    types::{
+        Client, EngineConfig, ExcessDecimals, MissingClientPolicy, RejectReason, State,
+        Transaction, TransactionStatus, TransactionType,
+    },
+};
+
+// how many rows are read before handing them to process_batch as one batch
+pub const BATCH_SIZE: usize = 8192;
+
+/// Applies one transaction to `state` under the rules in `state.config`, and returns the new
+/// state. This never fails: a transaction that breaks a rule is counted in `State::rejected`,
+/// with its reason in `State::recent_rejects`, and leaves every balance as it was.
+pub fn process_transaction<L: Ledger>(
+    mut state: State<L>,
+    mut transaction: Transaction,
+) -> State<L> {
+    let (id, client_id, transaction_type) = (
+        transaction.id,
+        transaction.client_id,
+        transaction.transaction_type,
+    );
+
+    state.check_timestamp_order(&transaction);
+
+    let undo = state
+        .undo_log
+        .as_ref()
+        .map(|_| state.undo_entry(&transaction));
+    let totals = state.totals_before(&transaction);
+
+    state = match state.round_to_precision(&mut transaction) {
+        Ok(()) => process_row(state, transaction),
+        Err(reason) => state.reject(id, reason),
+    };
+    state.record_audit(id, client_id, transaction_type);
+
+    if let Some(totals) = totals {
+        state.record_total_changes(totals);
+    }
+
+    if let Some(entry) = undo {
+        state.record_undo(entry);
+    }
+
+    state
+}
+
+fn process_row<L: Ledger>(mut state: State<L>, transaction: Transaction) -> State<L> {
+    let client_id = match resolve_client(&state, &transaction) {
+        Ok(client_id) => client_id,
+        Err(reason) => return state.reject(transaction.id, reason),
+    };
+
+    // filtered out clients are skipped rather than rejected, so they never enter the state
+    if !state.accepts_client(client_id) {
+        state.set_row_outcome(AuditOutcome::Filtered);
+        return state;
+    }
+
+    state.record_seen(client_id);
+
+    match state.config.max_tx_per_client {
+        Some(max) => process_rate_limited(state, transaction, client_id, max),
+        None => dispatch(state, transaction, client_id),
+    }
+}
+
+// the client a transaction acts for. a dispute, resolve or chargeback without one may
+// stand for the owner of the transaction it references, if the policy allows it
+fn resolve_client<L: Ledger>(
+    state: &State<L>,
+    transaction: &Transaction,
+) -> Result<u16, RejectReason> {
+    if let Some(client_id) = transaction.client_id {
+        return Ok(client_id);
+    }
 
-pub fn process_transaction(state: State, transaction: Transaction) -> State {
     match transaction.transaction_type {
-        TransactionType::Deposit => process_deposit(state, transaction),
-        TransactionType::Withdrawal => process_withdrawal(state, transaction),
-        TransactionType::Dispute => process_dispute(state, transaction),
-        TransactionType::Resolve => process_resolve(state, transaction),
-        TransactionType::Chargeback => process_chargeback(state, transaction),
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+            if state.config.missing_client == MissingClientPolicy::MatchOwner =>
+        {
+            state
+                .transfers
+                .get(&transaction.id)
+                .and_then(|target| target.client_id)
+                .ok_or(RejectReason::UnknownTransaction)
+        }
+        _ => Err(RejectReason::MissingClient),
     }
 }
 
-fn process_deposit(mut state: State, transaction: Transaction) -> State {
-    // if this deposit references an already existing transaction id, it is invalid and should be skipped
+// only applied transactions count towards the limit, so rejected ones don't use it up
+fn process_rate_limited<L: Ledger>(
+    state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+    max: usize,
+) -> State<L> {
+    if state.accepted_count(client_id) >= max {
+        return state.reject(transaction.id, RejectReason::RateLimited);
+    }
+
+    let rejected = state.rejected;
+    let mut state = dispatch(state, transaction, client_id);
+
+    if state.rejected == rejected {
+        state.record_accepted(client_id);
+    }
+
+    state
+}
+
+fn dispatch<L: Ledger>(state: State<L>, transaction: Transaction, client_id: u16) -> State<L> {
+    match transaction.transaction_type {
+        TransactionType::Deposit => process_deposit(state, transaction, client_id),
+        TransactionType::Withdrawal => process_withdrawal(state, transaction, client_id),
+        TransactionType::Dispute => process_dispute(state, transaction, client_id),
+        TransactionType::Resolve => process_resolve(state, transaction, client_id),
+        TransactionType::Chargeback => process_chargeback(state, transaction, client_id),
+        TransactionType::Freeze | TransactionType::Thaw => {
+            process_admin(state, transaction, client_id)
+        }
+        TransactionType::Transfer => process_transfer(state, transaction, client_id),
+    }
+}
+
+/// Reads a CSV of transactions with a `type, client, tx, amount` header, trimming whitespace
+/// around fields, and applies each row to `state` in order, as `process_transaction` would,
+/// under the rules in `state.config`, e.g. from `State::with_config`. Passing the returned state
+/// to the next call accumulates several files, with transaction ids unique across all of them.
+///
+/// A file with only the header row has no transactions, and gives back `state` as it was.
+/// `state` can keep its transactions in any `Ledger`, as can the state given to
+/// `process_transaction` and `process_batch`.
+///
+/// Rows don't need to match the header's length: fields past it are ignored, and missing
+/// trailing ones read as blank. A row that still can't be read as a transaction, e.g. one
+/// without a tx id, of an unknown type, or whose client id is out of range, is skipped with a
+/// warning in `State::warnings`.
+///
+/// Fails with `ProcessError::Io` if the file can't be opened or read, with
+/// `ProcessError::MissingHeader` if it is empty, without even a header row, with
+/// `ProcessError::TooManyClients` once the input has more clients than
+/// `EngineConfig::max_clients` allows, and with `ProcessError::LikelyDuplicateInput` if more
+/// of the file repeats earlier transactions than `EngineConfig::max_duplicate_fraction`
+/// allows, e.g. when the same file is processed twice into one state.
+pub fn process_transaction_file<L: Ledger, P: AsRef<Path>>(
+    path: P,
+    state: State<L>,
+) -> Result<State<L>, ProcessError> {
+    process_transaction_file_with_progress(path, state, |_| {})
+}
+
+/// Like `process_transaction_file`, but calls `progress` with the number of rows processed so
+/// far each time a batch of up to `BATCH_SIZE` rows has been applied, including the last one,
+/// so a caller can report on a long run.
+pub fn process_transaction_file_with_progress<L, P, F>(
+    path: P,
+    state: State<L>,
+    mut progress: F,
+) -> Result<State<L>, ProcessError>
+where
+    L: Ledger,
+    P: AsRef<Path>,
+    F: FnMut(u64),
+{
+    let path = path.as_ref();
+    // rows are read as on the command line, see TransactionRows
+    let transactions =
+        TransactionRows::from_reader(&InputReader::default(), Box::new(File::open(path)?))?;
+
+    let before = state.stats.clone();
+    let mut rows = 0;
+    let state = process_transaction_rows(
+        transactions,
+        &path.display().to_string(),
+        state,
+        |_, batch| {
+            rows += batch as u64;
+            progress(rows);
+            Ok(())
+        },
+    )?;
+
+    state.check_duplicate_input(&before)?;
+
+    Ok(state)
+}
+
+/// Applies already opened rows in batches of up to `BATCH_SIZE`, e.g. ones read from stdin or
+/// with a different delimiter, skipping any that can't be read as a transaction with a warning
+/// naming `source`. `after_batch` is called with the state and the number of rows in each
+/// batch once it has been applied, including the last one, and an error from it stops the run.
+/// Unlike `process_transaction_file`, repeated input isn't checked for.
+pub fn process_transaction_rows<L, F>(
+    transactions: TransactionRows,
+    source: &str,
+    state: State<L>,
+    mut after_batch: F,
+) -> Result<State<L>, ProcessError>
+where
+    L: Ledger,
+    F: FnMut(&State<L>, usize) -> Result<(), ProcessError>,
+{
+    let mut state = state;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    for record in transactions {
+        // a row that can't be read as a transaction, e.g. one of a type a newer feed added,
+        // is skipped rather than failing the input
+        match record {
+            Ok(transaction) => batch.push(transaction),
+            Err(err) => {
+                let (location, reason) = unreadable_row(source, err)?;
+                state.skip_unreadable_row(&location, &reason);
+                continue;
+            }
+        }
+
+        if batch.len() == BATCH_SIZE {
+            let full = mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+            state = process_batch(state, full);
+            state.check_client_limit()?;
+            after_batch(&state, BATCH_SIZE)?;
+        }
+    }
+
+    let rows = batch.len();
+    state = process_batch(state, batch);
+    state.check_client_limit()?;
+    after_batch(&state, rows)?;
+
+    Ok(state)
+}
+
+/// Applies transactions one at a time as they are read, starting from a new `State` in
+/// streaming mode (see `EngineConfig::streaming`), so memory stays bounded by the clients seen
+/// and the transactions that can still be disputed, rather than growing with the whole input.
+/// `reader.deserialize()` on a `csv::Reader` gives a suitable iterator.
+///
+/// Fails at the first record the iterator couldn't read, past `EngineConfig::max_clients`, or
+/// past `EngineConfig::max_duplicate_fraction` at the end, as `process_transaction_file` does.
+pub fn process_transactions<I>(transactions: I) -> Result<State, ProcessError>
+where
+    I: Iterator<Item = Result<Transaction, csv::Error>>,
+{
+    process_transactions_with_config(EngineConfig::default(), transactions)
+}
+
+/// Like `process_transactions`, under the rules in `config`, apart from streaming, which is
+/// always on.
+pub fn process_transactions_with_config<I>(
+    config: EngineConfig,
+    transactions: I,
+) -> Result<State, ProcessError>
+where
+    I: Iterator<Item = Result<Transaction, csv::Error>>,
+{
+    let mut state = State::with_config(config);
+    state.config.streaming = true;
+
+    for transaction in transactions {
+        state = process_transaction(state, transaction?);
+        state.check_client_limit()?;
+    }
+
+    state.check_duplicate_input(&Stats::default())?;
+
+    Ok(state)
+}
+
+/// Checks the rules `process_transaction` can judge from the row alone, under the options
+/// set on `state`: amounts fit the precision if excess decimals are rejected, deposits,
+/// withdrawals, transfers and admin rows need a client, transfers also need a destination,
+/// amounts can't be negative, disputes and resolves carry no amount unless it is checked, and
+/// admin rows must be allowed. Rules that depend on earlier rows, such as duplicate ids or
+/// available funds, aren't checked, and `state` is left untouched.
+pub fn validate_transaction<L: Ledger>(
+    state: &State<L>,
+    transaction: &Transaction,
+) -> Result<(), RejectReason> {
+    if state.config.excess_decimals == ExcessDecimals::Reject
+        && !transaction.fits_precision(state.config.precision)
+    {
+        return Err(RejectReason::TooManyDecimals);
+    }
+
+    let may_omit_client = matches!(
+        transaction.transaction_type,
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+    ) && state.config.missing_client == MissingClientPolicy::MatchOwner;
+
+    if transaction.client_id.is_none() && !may_omit_client {
+        return Err(RejectReason::MissingClient);
+    }
+
+    match transaction.transaction_type {
+        TransactionType::Deposit
+        | TransactionType::Withdrawal
+        | TransactionType::Chargeback
+        | TransactionType::Transfer
+            if transaction.amount < Amount::ZERO =>
+        {
+            Err(RejectReason::NegativeAmount)
+        }
+        TransactionType::Transfer if transaction.dest_client_id.is_none() => {
+            Err(RejectReason::MissingDestination)
+        }
+        TransactionType::Transfer if transaction.dest_client_id == transaction.client_id => {
+            Err(RejectReason::SelfTransfer)
+        }
+        TransactionType::Dispute
+            if !state.config.check_dispute_amount && transaction.amount != Amount::ZERO =>
+        {
+            Err(RejectReason::UnexpectedAmount)
+        }
+        TransactionType::Resolve if transaction.amount != Amount::ZERO => {
+            Err(RejectReason::UnexpectedAmount)
+        }
+        TransactionType::Freeze | TransactionType::Thaw
+            if !state.config.allow_admin_transactions =>
+        {
+            Err(RejectReason::AdminNotAllowed)
+        }
+        _ => Ok(()),
+    }
+}
+
+// processes transactions in order with the same results as process_transaction, but with a
+// cheaper path for deposits, which dominate bulk-credit files: capacity for them is reserved
+// up front, and each one is applied in place
+pub fn process_batch<L: Ledger>(mut state: State<L>, transactions: Vec<Transaction>) -> State<L> {
+    let deposits = transactions
+        .iter()
+        .filter(|tx| tx.transaction_type == TransactionType::Deposit)
+        .count();
+    state.transfers.reserve(deposits);
+
+    for transaction in transactions {
+        // the rate limit is counted in process_transaction, so the fast path can't apply
+        // deferred disputes are applied from process_deposit, so those runs can't either, and
+        // neither can runs keeping an undo log, which process_transaction fills in
+        match transaction.client_id {
+            Some(client_id)
+                if transaction.transaction_type == TransactionType::Deposit
+                    && state.config.max_tx_per_client.is_none()
+                    && !state.config.defer_early_disputes
+                    && state.undo_log.is_none() =>
+            {
+                let id = transaction.id;
+                let mut transaction = transaction;
+                state.check_timestamp_order(&transaction);
+                let totals = state.totals_before(&transaction);
+
+                // rounded before the client filter, as in process_transaction
+                if let Err(reason) = state.round_to_precision(&mut transaction) {
+                    state.record_reject(id, reason);
+                } else if state.accepts_client(client_id) {
+                    state.record_seen(client_id);
+                    apply_batched_deposit(&mut state, transaction, client_id);
+                } else {
+                    state.set_row_outcome(AuditOutcome::Filtered);
+                }
+
+                state.record_audit(id, Some(client_id), TransactionType::Deposit);
+
+                if let Some(totals) = totals {
+                    state.record_total_changes(totals);
+                }
+            }
+            _ => state = process_transaction(state, transaction),
+        }
+    }
+
+    state
+}
+
+// the same rules as process_deposit, applied in place to a deposit already rounded
+fn apply_batched_deposit<L: Ledger>(
+    state: &mut State<L>,
+    mut transaction: Transaction,
+    client_id: u16,
+) {
+    if transaction.amount < Amount::ZERO {
+        state.record_reject(transaction.id, RejectReason::NegativeAmount);
+        return;
+    }
+
+    if state.is_retired(transaction.id) {
+        state.record_reject(transaction.id, RejectReason::DuplicateTransaction);
+        return;
+    }
+
     if state.transfers.contains_key(&transaction.id) {
-        return state;
+        let reason = state.reused_id_reason(&transaction);
+        state.record_reject(transaction.id, reason);
+        return;
+    }
+
+    if state.exceeds_client_limit(client_id) {
+        state.record_reject(transaction.id, RejectReason::TooManyClients);
+        return;
+    }
+
+    let client = state
+        .clients
+        .entry(client_id)
+        .or_insert_with(|| Client::new(client_id));
+
+    if client.locked && !state.config.deposits_allowed_when_locked {
+        if !state.config.escrow_locked_deposits {
+            state.record_reject(transaction.id, RejectReason::ClientLocked);
+            return;
+        }
+
+        client.escrow += transaction.amount;
+        client.record_activity(transaction.id);
+        transaction.status = TransactionStatus::Escrowed;
+        state.transfers.insert(transaction.id, transaction);
+
+        return;
+    }
+
+    client.available += transaction.amount;
+    client.total += transaction.amount;
+    client.peak_total = client.peak_total.max(client.total);
+    client.max_deposit = client.max_deposit.max(transaction.amount);
+    client.record_activity(transaction.id);
+
+    state.transfers.insert(transaction.id, transaction);
+}
+
+fn process_deposit<L: Ledger>(
+    mut state: State<L>,
+    mut transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    // a negative amount would run the transaction backwards, so the row is skipped like
+    // any other invalid one rather than failing the whole run
+    if transaction.amount < Amount::ZERO {
+        return state.reject(transaction.id, RejectReason::NegativeAmount);
+    }
+
+    // if this deposit references an already existing transaction id, it is invalid and should be skipped
+    if state.is_known_transaction(transaction.id) {
+        let reason = state.reused_id_reason(&transaction);
+        return state.reject(transaction.id, reason);
+    }
+
+    if state.exceeds_client_limit(client_id) {
+        return state.reject(transaction.id, RejectReason::TooManyClients);
     }
 
-    let client = match state.clients.get_mut(&transaction.client_id) {
+    let client = match state.clients.get_mut(&client_id) {
         Some(client) => client,
         None => {
-            state
-                .clients
-                .insert(transaction.client_id, Client::new(transaction.client_id));
-            state.clients.get_mut(&transaction.client_id).unwrap()
+            state.clients.insert(client_id, Client::new(client_id));
+            state.clients.get_mut(&client_id).unwrap()
         }
     };
 
-    if client.locked {
-        return state;
+    if client.locked && !state.config.deposits_allowed_when_locked {
+        if !state.config.escrow_locked_deposits {
+            return state.reject(transaction.id, RejectReason::ClientLocked);
+        }
+
+        let tx_id = transaction.id;
+        client.escrow += transaction.amount;
+        client.record_activity(tx_id);
+        transaction.status = TransactionStatus::Escrowed;
+        state.transfers.insert(tx_id, transaction);
+
+        return apply_deferred_dispute(state, tx_id);
     }
 
     client.available += transaction.amount;
     client.total += transaction.amount;
+    client.peak_total = client.peak_total.max(client.total);
+    client.max_deposit = client.max_deposit.max(transaction.amount);
+    client.record_activity(transaction.id);
 
-    state.transfers.insert(transaction.id, transaction);
+    let tx_id = transaction.id;
+    state.transfers.insert(tx_id, transaction);
 
-    state
+    apply_deferred_dispute(state, tx_id)
+}
+
+// a dispute that arrived before its deposit is applied once the deposit is recorded, and
+// goes through the same checks as if it had arrived in order. it is audited a second time
+// here, just ahead of the deposit that released it
+fn apply_deferred_dispute<L: Ledger>(mut state: State<L>, tx_id: u32) -> State<L> {
+    match state.take_deferred_dispute(tx_id) {
+        Some((client_id, dispute)) => {
+            let dispute_client = dispute.client_id;
+            let mut state = process_dispute(state, dispute, client_id);
+            state.record_audit(tx_id, dispute_client, TransactionType::Dispute);
+
+            state
+        }
+        None => state,
+    }
 }
 
-fn process_withdrawal(mut state: State, transaction: Transaction) -> State {
+fn process_withdrawal<L: Ledger>(
+    mut state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    if transaction.amount < Amount::ZERO {
+        return state.reject(transaction.id, RejectReason::NegativeAmount);
+    }
+
     // if this withdrawal references an already existing transaction id, it is invalid and should be skipped
-    if state.transfers.contains_key(&transaction.id) {
-        return state;
+    if state.is_known_transaction(transaction.id) {
+        let reason = state.reused_id_reason(&transaction);
+        return state.reject(transaction.id, reason);
     }
 
-    let client = match state.clients.get_mut(&transaction.client_id) {
+    // judged before the client is borrowed, but only acted on once every other rule passes,
+    // so a withdrawal rejected for another reason isn't flagged
+    let repeated = state.is_repeated_withdrawal(client_id, transaction.amount);
+
+    let client = match state.clients.get_mut(&client_id) {
         Some(client) => client,
-        None => return state, // client doesn't exist, withdrawal is invalid
+        None => return state.reject(transaction.id, RejectReason::UnknownClient),
     };
 
-    if client.locked || client.available < transaction.amount {
-        return state;
+    if client.locked {
+        return state.reject(transaction.id, RejectReason::ClientLocked);
+    }
+
+    // within its overdraft limit a client's available and total may go negative
+    if client.available - transaction.amount < -client.overdraft_limit {
+        let reason = shortfall_reason(client, transaction.amount);
+        return state.reject(transaction.id, reason);
+    }
+
+    // funds the client has, but must keep
+    if let Some(min) = state.config.min_balance {
+        if client.available - transaction.amount < min {
+            return state.reject(transaction.id, RejectReason::BelowMinimum);
+        }
+    }
+
+    if repeated && state.config.reject_duplicate_withdrawals {
+        return state.reject(transaction.id, RejectReason::RepeatedWithdrawal);
     }
 
     client.available -= transaction.amount;
     client.total -= transaction.amount;
+    client.max_withdrawal = client.max_withdrawal.max(transaction.amount);
+    client.record_activity(transaction.id);
+
+    if repeated {
+        state.warn_repeated_withdrawal(client_id, &transaction);
+    }
+    state.record_withdrawal(client_id, transaction.amount);
+    state.transfers.insert(transaction.id, transaction);
+
+    state
+}
+
+// why a client's available funds can't cover taking out `amount`. if the funds held by its
+// disputes would have covered it, the hold is named, so operators can tell a client waiting
+// on a dispute from one that is simply short
+fn shortfall_reason(client: &Client, amount: Amount) -> RejectReason {
+    if client.held > Amount::ZERO
+        && client.available + client.held - amount >= -client.overdraft_limit
+    {
+        RejectReason::FundsHeld
+    } else {
+        RejectReason::InsufficientFunds
+    }
+}
+
+// moves funds from the client to the destination client, creating it if needed. the source
+// side follows the withdrawal rules and the destination side the deposit rules, apart from
+// escrow, and either both apply or neither does
+fn process_transfer<L: Ledger>(
+    mut state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    if transaction.amount < Amount::ZERO {
+        return state.reject(transaction.id, RejectReason::NegativeAmount);
+    }
+
+    let dest_id = match transaction.dest_client_id {
+        Some(dest_id) => dest_id,
+        None => return state.reject(transaction.id, RejectReason::MissingDestination),
+    };
+
+    // it would change no balance, but would still count as activity and use up the id
+    if dest_id == client_id {
+        return state.reject(transaction.id, RejectReason::SelfTransfer);
+    }
+
+    if state.is_known_transaction(transaction.id) {
+        let reason = state.reused_id_reason(&transaction);
+        return state.reject(transaction.id, reason);
+    }
+
+    // a filtered out client never enters the state, so it can't be paid into either
+    if !state.accepts_client(dest_id) {
+        return state.reject(transaction.id, RejectReason::UnknownClient);
+    }
+
+    let source = match state.clients.get(&client_id) {
+        Some(source) => source,
+        None => return state.reject(transaction.id, RejectReason::UnknownClient),
+    };
+
+    // a locked destination takes money in only if deposits to it would be credited too
+    let dest_locked = matches!(state.clients.get(&dest_id), Some(dest) if dest.locked)
+        && !state.config.deposits_allowed_when_locked;
+    if source.locked || dest_locked {
+        return state.reject(transaction.id, RejectReason::ClientLocked);
+    }
+
+    if source.available - transaction.amount < -source.overdraft_limit {
+        let reason = shortfall_reason(source, transaction.amount);
+        return state.reject(transaction.id, reason);
+    }
+
+    if let Some(min) = state.config.min_balance {
+        if source.available - transaction.amount < min {
+            return state.reject(transaction.id, RejectReason::BelowMinimum);
+        }
+    }
 
+    if state.exceeds_client_limit(dest_id) {
+        return state.reject(transaction.id, RejectReason::TooManyClients);
+    }
+
+    let source = state.clients.get_mut(&client_id).unwrap();
+    source.available -= transaction.amount;
+    source.total -= transaction.amount;
+    source.record_activity(transaction.id);
+
+    let dest = state
+        .clients
+        .entry(dest_id)
+        .or_insert_with(|| Client::new(dest_id));
+    dest.available += transaction.amount;
+    dest.total += transaction.amount;
+    dest.peak_total = dest.peak_total.max(dest.total);
+    dest.record_activity(transaction.id);
+
+    state.record_seen(dest_id);
     state.transfers.insert(transaction.id, transaction);
 
     state
 }
 
-fn process_dispute(mut state: State, transaction: Transaction) -> State {
-    let mut target_transaction = match state.transfers.get_mut(&transaction.id) {
+fn process_dispute<L: Ledger>(
+    mut state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    // a dispute's amount is only meaningful when it is checked against the transaction
+    if !state.config.check_dispute_amount && transaction.amount != Amount::ZERO {
+        return state.reject(transaction.id, RejectReason::UnexpectedAmount);
+    }
+
+    let target_transaction = match state.transfers.get_mut(&transaction.id) {
         Some(tx) => tx,
-        None => return state,
+        None if state.config.defer_early_disputes => {
+            let tx_id = transaction.id;
+            if !state.defer_dispute(client_id, transaction) {
+                return state.reject(tx_id, RejectReason::AlreadyDisputed);
+            }
+
+            state.set_row_outcome(AuditOutcome::Deferred);
+            return state;
+        }
+        None => return state.reject(transaction.id, RejectReason::UnknownTransaction),
     };
 
-    if target_transaction.disputed
-        || target_transaction.transaction_type != TransactionType::Deposit
-        || target_transaction.client_id != transaction.client_id
+    if target_transaction.client_id != Some(client_id) {
+        let owner = target_transaction.client_id;
+        return state.reject_client_mismatch(transaction.id, owner, client_id);
+    }
+
+    // a transfer moved funds between two clients, so there is no single balance to hold
+    if target_transaction.transaction_type == TransactionType::Transfer {
+        return state.reject(transaction.id, RejectReason::NotDisputable);
+    }
+
+    match target_transaction.status {
+        TransactionStatus::Open | TransactionStatus::Resolved => {}
+        TransactionStatus::Disputed => {
+            return state.reject(transaction.id, RejectReason::AlreadyDisputed)
+        }
+        TransactionStatus::ChargedBack => {
+            return state.reject(transaction.id, RejectReason::ChargedBack)
+        }
+        TransactionStatus::Escrowed => {
+            return state.reject(transaction.id, RejectReason::NotDisputable)
+        }
+    }
+
+    // a blank amount reads as zero, and is never checked
+    if state.config.check_dispute_amount
+        && transaction.amount != Amount::ZERO
+        && transaction.amount != target_transaction.amount
     {
-        return state;
+        return state.reject(transaction.id, RejectReason::AmountMismatch);
     }
 
-    let mut client = state
-        .clients
-        .get_mut(&target_transaction.client_id)
-        .unwrap();
+    // only possible if the client was removed from the state behind the processor's back
+    let client = match state.clients.get_mut(&client_id) {
+        Some(client) => client,
+        None => return state.reject(transaction.id, RejectReason::MissingOwner),
+    };
 
     if client.locked {
-        return state;
+        return state.reject(transaction.id, RejectReason::ClientLocked);
     }
 
-    target_transaction.disputed = true;
+    target_transaction.status = TransactionStatus::Disputed;
     client.held += target_transaction.amount;
-    client.available -= target_transaction.amount;
+
+    // a disputed deposit is held out of available. a disputed withdrawal has already left
+    // available, so the claimed amount is held on top of the total until the dispute settles
+    match target_transaction.transaction_type {
+        TransactionType::Withdrawal => {
+            client.total += target_transaction.amount;
+            client.peak_total = client.peak_total.max(client.total);
+        }
+        _ => client.available -= target_transaction.amount,
+    }
 
     state
 }
 
-fn process_resolve(mut state: State, transaction: Transaction) -> State {
-    let mut target_transaction = match state.transfers.get_mut(&transaction.id) {
+fn process_resolve<L: Ledger>(
+    mut state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    if transaction.amount != Amount::ZERO {
+        return state.reject(transaction.id, RejectReason::UnexpectedAmount);
+    }
+
+    let target_transaction = match state.transfers.get_mut(&transaction.id) {
         Some(tx) => tx,
-        None => return state,
+        None => return state.reject(transaction.id, RejectReason::UnknownTransaction),
     };
 
-    if !target_transaction.disputed || target_transaction.client_id != transaction.client_id {
-        return state;
+    if target_transaction.client_id != Some(client_id) {
+        let owner = target_transaction.client_id;
+        return state.reject_client_mismatch(transaction.id, owner, client_id);
     }
 
-    let mut client = state
-        .clients
-        .get_mut(&target_transaction.client_id)
-        .unwrap();
+    if target_transaction.status != TransactionStatus::Disputed {
+        return state.reject(transaction.id, RejectReason::NotDisputed);
+    }
+
+    // as in process_dispute
+    let client = match state.clients.get_mut(&client_id) {
+        Some(client) => client,
+        None => return state.reject(transaction.id, RejectReason::MissingOwner),
+    };
 
     if client.locked {
-        return state;
+        return state.reject(transaction.id, RejectReason::ClientLocked);
+    }
+
+    // only possible if the state was corrupted or built from out of order replays
+    if client.held < target_transaction.amount {
+        return state.reject(transaction.id, RejectReason::HeldUnderflow);
     }
 
-    target_transaction.disputed = false;
+    target_transaction.status = TransactionStatus::Resolved;
     client.held -= target_transaction.amount;
-    client.available += target_transaction.amount;
+
+    // resolving a withdrawal dispute lets the withdrawal stand
+    match target_transaction.transaction_type {
+        TransactionType::Withdrawal => client.total -= target_transaction.amount,
+        _ => client.available += target_transaction.amount,
+    }
 
     state
 }
 
-fn process_chargeback(mut state: State, transaction: Transaction) -> State {
-    let target_transaction = match state.transfers.get(&transaction.id) {
+fn process_chargeback<L: Ledger>(
+    mut state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    let target_transaction = match state.transfers.get_mut(&transaction.id) {
         Some(tx) => tx,
-        None => return state,
+        None => return state.reject(transaction.id, RejectReason::UnknownTransaction),
     };
 
-    if !target_transaction.disputed || target_transaction.client_id != transaction.client_id {
-        return state;
+    if target_transaction.client_id != Some(client_id) {
+        let owner = target_transaction.client_id;
+        return state.reject_client_mismatch(transaction.id, owner, client_id);
     }
 
-    let mut client = state
-        .clients
-        .get_mut(&target_transaction.client_id)
-        .unwrap();
+    if target_transaction.status != TransactionStatus::Disputed {
+        return state.reject(transaction.id, RejectReason::NotDisputed);
+    }
+
+    // a chargeback amount below the disputed amount settles for part of it. a blank amount
+    // reads as zero, and charges back the whole transaction
+    let disputed_amount = target_transaction.amount;
+    let charged_back = match transaction.amount {
+        amount if amount < Amount::ZERO => {
+            return state.reject(transaction.id, RejectReason::NegativeAmount)
+        }
+        amount if amount > disputed_amount => {
+            return state.reject(transaction.id, RejectReason::AmountMismatch)
+        }
+        Amount::ZERO => disputed_amount,
+        amount => amount,
+    };
+    let kept = disputed_amount - charged_back;
+
+    // as in process_dispute
+    let client = match state.clients.get_mut(&client_id) {
+        Some(client) => client,
+        None => return state.reject(transaction.id, RejectReason::MissingOwner),
+    };
 
     if client.locked {
-        return state;
+        return state.reject(transaction.id, RejectReason::ClientLocked);
     }
 
-    client.locked = true;
-    client.held -= target_transaction.amount;
-    client.total -= target_transaction.amount;
+    // as in process_resolve
+    if client.held < disputed_amount {
+        return state.reject(transaction.id, RejectReason::HeldUnderflow);
+    }
+
+    target_transaction.status = TransactionStatus::ChargedBack;
+    client.held -= disputed_amount;
+
+    // a deposit charged back leaves the account, a withdrawal charged back is returned to it.
+    // whatever a partial chargeback doesn't reverse is released as if resolved
+    match target_transaction.transaction_type {
+        TransactionType::Withdrawal => {
+            client.available += charged_back;
+            client.total -= kept;
+        }
+        _ => {
+            client.available += kept;
+            client.total -= charged_back;
+        }
+    }
+
+    if state.record_chargeback(client_id) {
+        state.set_locked(client_id, true);
+    }
+    state.retire_transaction(transaction.id);
+
+    state
+}
+
+// freeze and thaw set and clear the lock directly, regardless of any chargeback
+fn process_admin<L: Ledger>(
+    mut state: State<L>,
+    transaction: Transaction,
+    client_id: u16,
+) -> State<L> {
+    if !state.config.allow_admin_transactions {
+        return state.reject(transaction.id, RejectReason::AdminNotAllowed);
+    }
+
+    let freeze = transaction.transaction_type == TransactionType::Freeze;
+
+    match state.clients.entry(client_id) {
+        Entry::Occupied(_) => {}
+        // freezing an unseen client blocks it from the start
+        Entry::Vacant(slot) if freeze => {
+            slot.insert(Client::new(client_id));
+        }
+        Entry::Vacant(_) => return state.reject(transaction.id, RejectReason::UnknownClient),
+    }
+
+    state.set_locked(client_id, freeze);
+    state.admin_log.push(transaction);
 
     state
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashSet, env, fs};
+
+    use csv::{ReaderBuilder, Trim};
+
     use super::*;
+    use crate::types::{ErrorBudget, ShadowLedger};
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
 
     #[test]
     fn valid_deposit() {
         let start_state = State::new();
-        let tx = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client_id: 1,
-            id: 1,
-            amount: 1.0,
-            disputed: false,
-        };
+        let tx = Transaction::deposit(1, 1, amount("1.0"));
 
         let result_state = process_transaction(start_state, tx);
 
@@ -164,29 +916,17 @@ mod tests {
 
         let result_client = result_state.clients.get(&1).unwrap();
 
-        assert_eq!(result_client.available, 1.0);
-        assert_eq!(result_client.total, 1.0);
-        assert_eq!(result_client.held, 0.0);
+        assert_eq!(result_client.available, amount("1.0"));
+        assert_eq!(result_client.total, amount("1.0"));
+        assert_eq!(result_client.held, Amount::ZERO);
     }
 
     #[test]
     fn valid_withdrawal() {
         let mut state = State::new();
         let txs = vec![
-            Transaction {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: 1.0,
-                disputed: false,
-            },
-            Transaction {
-                transaction_type: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: 0.35,
-                disputed: false,
-            },
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::withdrawal(1, 2, amount("0.35")),
         ];
 
         for tx in txs {
@@ -197,60 +937,310 @@ mod tests {
 
         let result_client = state.clients.get(&1).unwrap();
 
-        assert_eq!(result_client.available, 0.65);
-        assert_eq!(result_client.total, 0.65);
+        assert_eq!(result_client.available, amount("0.65"));
+        assert_eq!(result_client.total, amount("0.65"));
     }
 
     #[test]
-    fn invalid_withdrawal_insufficient_funds() {
+    fn withdrawal_within_overdraft_limit() {
         let mut state = State::new();
-        let txs = vec![
-            Transaction {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: 1.0,
-                disputed: false,
-            },
-            Transaction {
-                transaction_type: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: 10.0,
-                disputed: false,
-            },
-        ];
-
-        for tx in txs {
-            state = process_transaction(state, tx);
-        }
-
-        assert_eq!(state.clients.len(), 1);
+        state.set_overdraft_limit(1, amount("5.0"));
+
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    withdrawal, 1, 2, 4.5\n\
+                    withdrawal, 1, 3, 2.0\n\
+                    withdrawal, 1, 4, 1.5\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let state = reader
+            .deserialize()
+            .map(Result::unwrap)
+            .fold(state, process_transaction);
+        let client = &state.clients[&1];
+
+        // tx 3 would take available to -5.5, past the limit, and tx 4 reaches it exactly
+        assert_eq!(client.available, amount("-5.0"));
+        assert_eq!(client.total, amount("-5.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(3, RejectReason::InsufficientFunds)]
+        );
+    }
 
-        let result_client = state.clients.get(&1).unwrap();
+    #[test]
+    fn withdrawal_of_held_funds_names_the_hold() {
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::dispute(1, 1),
+            Transaction::withdrawal(1, 2, amount("5.0")),
+            Transaction::deposit(2, 3, amount("1.0")),
+            Transaction::withdrawal(2, 4, amount("5.0")),
+        ]
+        .into_iter()
+        .fold(State::new(), process_transaction);
+
+        // client 1 has the funds but they are all held, while client 2 is simply short
+        assert_eq!(state.clients[&1].available, Amount::ZERO);
+        assert_eq!(state.clients[&1].held, amount("10.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(2, RejectReason::FundsHeld),
+                &(4, RejectReason::InsufficientFunds)
+            ]
+        );
+    }
 
-        assert_eq!(result_client.available, 1.0);
-        assert_eq!(result_client.total, 1.0);
+    #[test]
+    fn withdrawal_below_minimum_balance_rejected() {
+        let mut state = State::new();
+        state.config.min_balance = Some(amount("2.0"));
+
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::withdrawal(1, 2, amount("8.5")),
+            // leaves exactly the minimum
+            Transaction::withdrawal(1, 3, amount("8.0")),
+            Transaction::withdrawal(1, 4, amount("0.0001")),
+            // still short of funds altogether, which is reported first
+            Transaction::withdrawal(1, 5, amount("20.0")),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert_eq!(state.clients[&1].available, amount("2.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(2, RejectReason::BelowMinimum),
+                &(4, RejectReason::BelowMinimum),
+                &(5, RejectReason::InsufficientFunds)
+            ]
+        );
     }
 
     #[test]
-    fn dispute_and_resolve() {
+    fn client_locked_once_chargebacks_reach_threshold() {
+        let mut state = State::new();
+        state.config.chargebacks_before_lock = 2;
+
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::deposit(1, 2, amount("4.0")),
+            Transaction::deposit(1, 3, amount("1.0")),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert!(!state.clients[&1].locked);
+        assert_eq!(state.chargeback_count(1), 1);
+
+        // still open for business until the second
+        let state = [
+            Transaction::withdrawal(1, 4, amount("1.0")),
+            Transaction::dispute(1, 2),
+            Transaction::chargeback(1, 2),
+            Transaction::dispute(1, 3),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        let client = &state.clients[&1];
+        assert!(client.locked);
+        assert_eq!(client.available, amount("0.0"));
+        assert_eq!(client.total, amount("0.0"));
+        assert_eq!(state.chargeback_count(1), 2);
+        assert_eq!(state.rejected, 1);
+    }
+
+    #[test]
+    fn repeated_withdrawal_in_window_flagged() {
+        let txs = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::withdrawal(1, 2, amount("1.5")),
+            Transaction::withdrawal(1, 3, amount("2.0")),
+            Transaction::withdrawal(1, 4, amount("1.5")),
+            // repeats 2.0, but would break the minimum balance, so it isn't flagged
+            Transaction::withdrawal(1, 5, amount("2.0")),
+        ];
+
+        let mut state = State::new();
+        state.config.duplicate_withdrawal_window = Some(2);
+        state.config.min_balance = Some(amount("5.0"));
+        let state = txs.clone().into_iter().fold(state, process_transaction);
+
+        // only warned about, so it is still applied
+        assert_eq!(state.clients[&1].available, amount("5.0"));
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "withdrawal 4 by client 1 repeats the amount 1.5 of one of its last 2 withdrawals"
+            ]
+        );
+
+        let mut state = State::new();
+        state.config.duplicate_withdrawal_window = Some(2);
+        state.config.reject_duplicate_withdrawals = true;
+        state.config.min_balance = Some(amount("5.0"));
+        let state = txs.into_iter().fold(state, process_transaction);
+
+        // the rejection stands in for the warning
+        assert_eq!(state.clients[&1].available, amount("6.5"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(4, RejectReason::RepeatedWithdrawal),
+                &(5, RejectReason::BelowMinimum)
+            ]
+        );
+        assert_eq!(state.warnings().count(), 0);
+    }
+
+    #[test]
+    fn other_withdrawals_in_window_not_flagged() {
+        let mut state = State::new();
+        state.config.duplicate_withdrawal_window = Some(2);
+        state.config.reject_duplicate_withdrawals = true;
+
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::deposit(2, 2, amount("10.0")),
+            Transaction::withdrawal(1, 3, amount("1.5")),
+            // a different amount, and the same amount from another client
+            Transaction::withdrawal(1, 4, amount("1.25")),
+            Transaction::withdrawal(2, 5, amount("1.5")),
+            // a rejected withdrawal isn't remembered
+            Transaction::withdrawal(1, 6, amount("20.0")),
+            Transaction::withdrawal(1, 7, amount("20.0")),
+            // 1.5 has dropped out of client 1's last two withdrawals
+            Transaction::withdrawal(1, 8, amount("2.0")),
+            Transaction::withdrawal(1, 9, amount("1.5")),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert_eq!(state.clients[&1].available, amount("3.75"));
+        assert_eq!(state.clients[&2].available, amount("8.5"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(6, RejectReason::InsufficientFunds),
+                &(7, RejectReason::InsufficientFunds)
+            ]
+        );
+        assert_eq!(state.warnings().count(), 0);
+    }
+
+    #[test]
+    fn peak_total_is_high_water_mark() {
+        let mut state = State::new();
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("5.0")),
+            Transaction::withdrawal(1, 2, amount("4.0")),
+            Transaction::deposit(1, 3, amount("2.0")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.total, amount("3.0"));
+        assert_eq!(result_client.peak_total, amount("5.0"));
+    }
+
+    #[test]
+    fn disputed_withdrawal_raises_peak_total() {
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::withdrawal(1, 2, amount("4.0")),
+            Transaction::deposit(1, 3, amount("3.0")),
+            Transaction::dispute(1, 2),
+        ]
+        .into_iter()
+        .fold(State::new(), process_transaction);
+        let client = &state.clients[&1];
+
+        assert_eq!(client.total, amount("13.0"));
+        assert_eq!(client.peak_total, amount("13.0"));
+    }
+
+    #[test]
+    fn balances_past_the_largest_single_amount_kept_exactly() {
+        // each close to the most a single amount can be, i64::MAX hundred-millionths
+        let large = amount("92233720368.5477");
+        let state = [
+            Transaction::deposit(1, 1, large),
+            Transaction::deposit(1, 2, large),
+            Transaction::deposit(1, 3, amount("60000000000.0")),
+            Transaction::withdrawal(1, 4, amount("0.0001")),
+        ]
+        .into_iter()
+        .fold(State::new(), process_transaction);
+
+        let client = &state.clients[&1];
+        assert_eq!(state.rejected, 0);
+        assert_eq!(client.available, amount("244467440737.0953"));
+        assert_eq!(client.total.to_string(), "244467440737.0953");
+    }
+
+    #[test]
+    fn largest_deposit_and_withdrawal_tracked() {
+        let mut state = State::new();
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("3.0")),
+            Transaction::deposit(1, 2, amount("12.5")),
+            Transaction::withdrawal(1, 3, amount("4.0")),
+            Transaction::deposit(1, 4, amount("0.5")),
+            Transaction::withdrawal(1, 5, amount("1.25")),
+            // rejected, the first for its reused id and the second for lack of funds
+            Transaction::deposit(1, 2, amount("100.0")),
+            Transaction::withdrawal(1, 6, amount("50.0")),
+            Transaction::withdrawal(2, 7, amount("1.0")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        let client = &state.clients[&1];
+        assert_eq!(state.rejected, 3);
+        assert_eq!(client.max_deposit, amount("12.5"));
+        assert_eq!(client.max_withdrawal, amount("4.0"));
+        assert!(!state.clients.contains_key(&2));
+    }
+
+    #[test]
+    fn invalid_withdrawal_insufficient_funds() {
+        let mut state = State::new();
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::withdrawal(1, 2, amount("10.0")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        assert_eq!(state.clients.len(), 1);
+
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.available, amount("1.0"));
+        assert_eq!(result_client.total, amount("1.0"));
+    }
+
+    #[test]
+    fn dispute_and_resolve() {
         let mut state = State::new();
         let txs_1 = vec![
-            Transaction {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: 1.0,
-                disputed: false,
-            },
-            Transaction {
-                transaction_type: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: 0.0,
-                disputed: false,
-            },
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::dispute(1, 1),
         ];
 
         for tx in txs_1 {
@@ -261,52 +1251,52 @@ mod tests {
 
         let mut result_client = state.clients.get(&1).unwrap();
 
-        assert_eq!(result_client.available, 0.0);
-        assert_eq!(result_client.total, 1.0);
-        assert_eq!(result_client.held, 1.0);
+        assert_eq!(result_client.available, Amount::ZERO);
+        assert_eq!(result_client.total, amount("1.0"));
+        assert_eq!(result_client.held, amount("1.0"));
 
-        let resolve_tx = Transaction {
-            transaction_type: TransactionType::Resolve,
-            client_id: 1,
-            id: 1,
-            amount: 0.0,
-            disputed: false,
-        };
+        let resolve_tx = Transaction::resolve(1, 1);
 
         state = process_transaction(state, resolve_tx);
 
         result_client = state.clients.get(&1).unwrap();
 
-        assert_eq!(result_client.available, 1.0);
-        assert_eq!(result_client.total, 1.0);
-        assert_eq!(result_client.held, 0.0);
+        assert_eq!(result_client.available, amount("1.0"));
+        assert_eq!(result_client.total, amount("1.0"));
+        assert_eq!(result_client.held, Amount::ZERO);
+    }
+
+    #[test]
+    fn dispute_and_resolve_leave_no_residue() {
+        let mut state = State::new();
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("0.1")),
+            Transaction::deposit(1, 2, amount("0.2")),
+            Transaction::deposit(1, 3, amount("0.7")),
+            Transaction::dispute(1, 1),
+            Transaction::dispute(1, 2),
+            Transaction::resolve(1, 1),
+            Transaction::resolve(1, 2),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(result_client.available, amount("1.0"));
+        assert_eq!(result_client.total, amount("1.0"));
     }
 
     #[test]
     fn chargeback() {
         let mut state = State::new();
         let txs = vec![
-            Transaction {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: 1.0,
-                disputed: false,
-            },
-            Transaction {
-                transaction_type: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: 0.0,
-                disputed: false,
-            },
-            Transaction {
-                transaction_type: TransactionType::Chargeback,
-                client_id: 1,
-                id: 1,
-                amount: 0.0,
-                disputed: false,
-            },
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
         ];
 
         for tx in txs {
@@ -317,25 +1307,1708 @@ mod tests {
 
         let result_client = state.clients.get(&1).unwrap();
 
-        assert_eq!(result_client.available, 0.0);
-        assert_eq!(result_client.total, 0.0);
-        assert_eq!(result_client.held, 0.0);
+        assert_eq!(result_client.available, Amount::ZERO);
+        assert_eq!(result_client.total, Amount::ZERO);
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert!(result_client.locked);
+    }
+
+    fn disputed_withdrawal(settlement: TransactionType) -> State {
+        let mut state = State::new();
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::withdrawal(1, 2, amount("4.0")),
+            Transaction::dispute(1, 2),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.available, amount("6.0"));
+        assert_eq!(result_client.held, amount("4.0"));
+        assert_eq!(result_client.total, amount("10.0"));
+
+        let settle_tx = Transaction::new(settlement, 1, 2, Amount::ZERO);
+
+        process_transaction(state, settle_tx)
+    }
+
+    #[test]
+    fn withdrawal_dispute_and_chargeback() {
+        let state = disputed_withdrawal(TransactionType::Chargeback);
+        let result_client = state.clients.get(&1).unwrap();
+
+        // the withdrawal is reversed
+        assert_eq!(result_client.available, amount("10.0"));
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(result_client.total, amount("10.0"));
+        assert!(result_client.locked);
+        assert_eq!(state.rejected, 0);
+    }
+
+    #[test]
+    fn withdrawal_dispute_and_resolve() {
+        let state = disputed_withdrawal(TransactionType::Resolve);
+        let result_client = state.clients.get(&1).unwrap();
+
+        // the withdrawal stands
+        assert_eq!(result_client.available, amount("6.0"));
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(result_client.total, amount("6.0"));
+        assert!(!result_client.locked);
+    }
+
+    fn locked_client_deposit(escrow_locked_deposits: bool) -> State {
+        let mut state = State::new();
+        state.config.escrow_locked_deposits = escrow_locked_deposits;
+
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+            Transaction::deposit(1, 2, amount("3.0")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        state
+    }
+
+    #[test]
+    fn locked_deposit_accrues_escrow() {
+        let state = locked_client_deposit(true);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert!(result_client.locked);
+        assert_eq!(result_client.escrow, amount("3.0"));
+        assert_eq!(result_client.available, Amount::ZERO);
+        assert_eq!(result_client.total, Amount::ZERO);
+    }
+
+    #[test]
+    fn escrowed_deposit_not_disputable_after_thaw() {
+        let mut state = State::new();
+        state.config.escrow_locked_deposits = true;
+        state.config.allow_admin_transactions = true;
+
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::new(TransactionType::Freeze, 1, 2, Amount::ZERO),
+            Transaction::deposit(1, 3, amount("5.0")),
+            Transaction::new(TransactionType::Thaw, 1, 4, Amount::ZERO),
+            Transaction::dispute(1, 3),
+            Transaction::resolve(1, 3),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+        let client = &state.clients[&1];
+
+        assert!(!client.locked);
+        assert_eq!(client.available, amount("10.0"));
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(client.total, amount("10.0"));
+        assert_eq!(client.escrow, amount("5.0"));
+        assert_eq!(state.transfers[&3].status, TransactionStatus::Escrowed);
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(3, RejectReason::NotDisputable),
+                &(3, RejectReason::NotDisputed)
+            ]
+        );
+    }
+
+    #[test]
+    fn locked_deposit_credited_when_allowed() {
+        let mut state = State::new();
+        state.config.deposits_allowed_when_locked = true;
+        // the config takes precedence over escrow
+        state.config.escrow_locked_deposits = true;
+
+        let data = "type, client, tx, amount, dest\n\
+                    deposit, 1, 1, 1.0,\n\
+                    dispute, 1, 1,,\n\
+                    chargeback, 1, 1,,\n\
+                    deposit, 1, 2, 3.0,\n\
+                    deposit, 2, 3, 2.0,\n\
+                    transfer, 2, 4, 0.5, 1\n\
+                    withdrawal, 1, 5, 1.0,\n\
+                    transfer, 1, 6, 1.0, 2\n";
+        let state = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .map(Result::unwrap)
+            .fold(state, process_transaction);
+        let client = &state.clients[&1];
+
+        assert!(client.locked);
+        assert_eq!(client.available, amount("3.5"));
+        assert_eq!(client.total, amount("3.5"));
+        assert_eq!(client.escrow, Amount::ZERO);
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(5, RejectReason::ClientLocked),
+                &(6, RejectReason::ClientLocked)
+            ]
+        );
+    }
+
+    #[test]
+    fn locked_deposit_rejected_by_default() {
+        let state = locked_client_deposit(false);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.escrow, Amount::ZERO);
+        assert_eq!(result_client.total, Amount::ZERO);
+        assert!(!state.transfers.contains_key(&2));
+    }
+
+    fn admin_sequence(allow_admin_transactions: bool) -> State {
+        let mut state = State::new();
+        state.config.allow_admin_transactions = allow_admin_transactions;
+
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::new(TransactionType::Freeze, 1, 2, Amount::ZERO),
+            Transaction::deposit(1, 3, amount("5.0")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        state
+    }
+
+    #[test]
+    fn freeze_blocks_deposits() {
+        let state = admin_sequence(true);
+        let result_client = state.clients.get(&1).unwrap();
+
         assert!(result_client.locked);
+        assert_eq!(result_client.total, amount("1.0"));
+        assert_eq!(state.admin_log.len(), 1);
+    }
+
+    #[test]
+    fn thaw_reenables_deposits() {
+        let mut state = admin_sequence(true);
+        let txs = vec![
+            Transaction::new(TransactionType::Thaw, 1, 4, Amount::ZERO),
+            Transaction::deposit(1, 5, amount("2.0")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert!(!result_client.locked);
+        assert_eq!(result_client.total, amount("3.0"));
+        assert_eq!(state.admin_log.len(), 2);
+    }
+
+    #[test]
+    fn thaw_after_chargeback() {
+        let mut state = locked_client_deposit(false);
+        state.config.allow_admin_transactions = true;
+
+        let tx = Transaction::new(TransactionType::Thaw, 1, 9, Amount::ZERO);
+        state = process_transaction(state, tx);
+
+        assert!(!state.clients.get(&1).unwrap().locked);
+    }
+
+    #[test]
+    fn admin_transactions_rejected_by_default() {
+        let state = admin_sequence(false);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert!(!result_client.locked);
+        assert_eq!(result_client.total, amount("6.0"));
+        assert_eq!(
+            state.recent_rejects().last(),
+            Some(&(2, RejectReason::AdminNotAllowed))
+        );
+    }
+
+    // mostly deposits, with duplicate ids, a locked client and a few other transaction types
+    fn deposit_heavy_fixture() -> Vec<Transaction> {
+        let mut txs = Vec::new();
+
+        for id in 1..=2000u32 {
+            let (transaction_type, tx_id, amount) = match id % 100 {
+                0 => (TransactionType::Withdrawal, id, amount("0.7")),
+                50 => (TransactionType::Deposit, id - 1, amount("3.0")), // duplicate id
+                _ => (
+                    TransactionType::Deposit,
+                    id,
+                    Amount::from_units(10_000_000 * (id % 7) as i64),
+                ),
+            };
+
+            txs.push(Transaction::new(
+                transaction_type,
+                (id % 13) as u16,
+                tx_id,
+                amount,
+            ));
+        }
+
+        for transaction_type in [TransactionType::Dispute, TransactionType::Chargeback] {
+            txs.push(Transaction::new(transaction_type, 3, 3, Amount::ZERO));
+        }
+
+        for id in 2001..=2100u32 {
+            txs.push(Transaction::deposit((id % 13) as u16, id, amount("1.25")));
+        }
+
+        txs
+    }
+
+    #[test]
+    fn batch_matches_row_by_row() {
+        let fixture = deposit_heavy_fixture();
+
+        let row_by_row = fixture
+            .clone()
+            .into_iter()
+            .fold(State::new(), process_transaction);
+        let batched = fixture
+            .chunks(300)
+            .fold(State::new(), |s, chunk| process_batch(s, chunk.to_vec()));
+
+        assert!(row_by_row.clients.get(&3).unwrap().locked);
+        assert_eq!(batched.clients, row_by_row.clients);
+        assert_eq!(batched.transfers, row_by_row.transfers);
+        assert_eq!(batched.rejected, row_by_row.rejected);
+
+        // an amount with too many decimals is rejected even for a client filtered out
+        let config = EngineConfig {
+            only_clients: Some(HashSet::from([1])),
+            excess_decimals: ExcessDecimals::Reject,
+            ..EngineConfig::default()
+        };
+        let rows = vec![
+            Transaction::deposit(1, 1, amount("1.5")),
+            Transaction::deposit(2, 2, amount("1.00005")),
+            Transaction::deposit(2, 3, amount("1.5")),
+            Transaction::deposit(1, 4, amount("1.00005")),
+        ];
+
+        let row_by_row = rows
+            .iter()
+            .cloned()
+            .fold(State::with_config(config.clone()), process_transaction);
+        let batched = process_batch(State::with_config(config), rows);
+
+        assert_eq!(row_by_row.rejected, 2);
+        assert_eq!(batched.clients, row_by_row.clients);
+        assert_eq!(batched.rejected, row_by_row.rejected);
+        assert!(batched.recent_rejects().eq(row_by_row.recent_rejects()));
+    }
+
+    #[test]
+    fn recent_rejects_keeps_latest() {
+        let mut state = State::with_recent_rejects_capacity(2);
+
+        for id in 1..=3 {
+            let tx = Transaction::withdrawal(1, id, amount("1.0"));
+            state = process_transaction(state, tx);
+        }
+
+        let rejects: Vec<&(u32, RejectReason)> = state.recent_rejects().collect();
+
+        assert_eq!(
+            rejects,
+            vec![
+                &(2, RejectReason::UnknownClient),
+                &(3, RejectReason::UnknownClient)
+            ]
+        );
     }
 
     #[test]
     fn invalid_withdrawal_no_client() {
         let start_state = State::new();
-        let tx = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client_id: 1,
-            id: 1,
-            amount: 1.0,
-            disputed: false,
-        };
+        let tx = Transaction::withdrawal(1, 1, amount("1.0"));
 
         let result_state = process_transaction(start_state, tx);
 
         assert!(result_state.clients.is_empty());
     }
+
+    fn dispute_with_amount(check_dispute_amount: bool, dispute_amount: Amount) -> State {
+        let mut state = State::new();
+        state.config.check_dispute_amount = check_dispute_amount;
+
+        let dispute = Transaction {
+            amount: dispute_amount,
+            ..Transaction::dispute(1, 1)
+        };
+
+        for tx in [Transaction::deposit(1, 1, amount("10.0")), dispute] {
+            state = process_transaction(state, tx);
+        }
+
+        state
+    }
+
+    #[test]
+    fn dispute_matching_amount_accepted() {
+        let state = dispute_with_amount(true, amount("10.0"));
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.held, amount("10.0"));
+        assert_eq!(state.rejected, 0);
+    }
+
+    #[test]
+    fn dispute_mismatched_amount_rejected() {
+        let state = dispute_with_amount(true, amount("7.5"));
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(result_client.available, amount("10.0"));
+        assert_eq!(
+            state.recent_rejects().last(),
+            Some(&(1, RejectReason::AmountMismatch))
+        );
+    }
+
+    #[test]
+    fn dispute_amount_rejected_by_default() {
+        let state = dispute_with_amount(false, amount("7.5"));
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(state.rejected, 1);
+    }
+
+    #[test]
+    fn small_deposits_onto_large_balance_are_exact() {
+        let mut state = State::new();
+
+        // each of these additions would lose precision in f64
+        let mut txs = vec![amount("1000000000.0")];
+        txs.extend((0..1000).map(|_| amount("0.0001")));
+
+        for (id, amount) in txs.into_iter().enumerate() {
+            let tx = Transaction::deposit(1, id as u32, amount);
+            state = process_transaction(state, tx);
+        }
+
+        assert_eq!(state.clients[&1].total, amount("1000000000.1"));
+    }
+
+    #[test]
+    fn exact_drain_withdrawal() {
+        let mut state = State::new();
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("0.7")),
+            Transaction::withdrawal(1, 2, amount("0.05")),
+            // available is now 0.65, which would be 0.6499999999999999 in f64
+            Transaction::withdrawal(1, 3, amount("0.65")),
+            Transaction::withdrawal(1, 4, amount("0.0001")),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert!(state.transfers.contains_key(&3));
+        assert_eq!(result_client.available, Amount::ZERO);
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(4, RejectReason::InsufficientFunds)]
+        );
+    }
+
+    #[test]
+    fn rate_limited_client() {
+        let mut state = State::new();
+        state.config.max_tx_per_client = Some(2);
+
+        let txs = vec![
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::withdrawal(1, 2, amount("5.0")),
+            Transaction::deposit(1, 3, amount("1.0")),
+            Transaction::deposit(1, 4, amount("1.0")),
+            Transaction::deposit(2, 5, amount("1.0")),
+        ];
+
+        let state = process_batch(state, txs);
+
+        assert_eq!(state.clients.get(&1).unwrap().total, amount("2.0"));
+        assert_eq!(state.clients.get(&2).unwrap().total, amount("1.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(2, RejectReason::InsufficientFunds),
+                &(4, RejectReason::RateLimited)
+            ]
+        );
+    }
+
+    fn dispute_without_client(missing_client: MissingClientPolicy) -> State {
+        let mut state = State::new();
+        state.config.missing_client = missing_client;
+
+        let dispute = Transaction {
+            client_id: None,
+            ..Transaction::dispute(1, 1)
+        };
+
+        for tx in [Transaction::deposit(1, 1, amount("5.0")), dispute] {
+            state = process_transaction(state, tx);
+        }
+
+        state
+    }
+
+    #[test]
+    fn omitted_client_rejected() {
+        let state = dispute_without_client(MissingClientPolicy::Reject);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(
+            state.recent_rejects().last(),
+            Some(&(1, RejectReason::MissingClient))
+        );
+    }
+
+    #[test]
+    fn omitted_client_matches_owner() {
+        let state = dispute_without_client(MissingClientPolicy::MatchOwner);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.held, amount("5.0"));
+        assert_eq!(result_client.available, Amount::ZERO);
+        assert_eq!(state.rejected, 0);
+        assert!(!state.clients.contains_key(&0));
+    }
+
+    fn dispute_before_deposit(defer_early_disputes: bool) -> State {
+        let mut state = State::new();
+        state.config.defer_early_disputes = defer_early_disputes;
+
+        let txs = vec![
+            Transaction::dispute(1, 1),
+            Transaction::deposit(1, 2, amount("3.0")),
+            Transaction::deposit(1, 1, amount("5.0")),
+            Transaction::dispute(1, 3),
+        ];
+
+        for tx in txs {
+            state = process_transaction(state, tx);
+        }
+
+        state
+    }
+
+    #[test]
+    fn early_dispute_applied_on_deposit() {
+        let mut state = dispute_before_deposit(true);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.available, amount("3.0"));
+        assert_eq!(result_client.held, amount("5.0"));
+        assert_eq!(result_client.total, amount("8.0"));
+        assert_eq!(state.transfers[&1].status, TransactionStatus::Disputed);
+
+        // the dispute for tx 3 is still waiting for its deposit
+        assert_eq!(state.rejected, 0);
+        assert_eq!(state.drain_deferred_disputes(), vec![(3, 1)]);
+    }
+
+    #[test]
+    fn early_dispute_rejected_by_default() {
+        let state = dispute_before_deposit(false);
+        let result_client = state.clients.get(&1).unwrap();
+
+        assert_eq!(result_client.available, amount("8.0"));
+        assert_eq!(result_client.held, Amount::ZERO);
+        assert_eq!(state.rejected, 2);
+    }
+
+    #[test]
+    fn transaction_file_processed() {
+        let state = process_transaction_file("test-data/test.csv", State::new()).unwrap();
+
+        assert_eq!(state.clients[&1].total, amount("-0.5"));
+        assert!(state.clients[&1].locked);
+        assert_eq!(state.clients[&2].available, amount("2.0"));
+
+        let missing = process_transaction_file("test-data/does-not-exist.csv", State::new());
+
+        assert!(matches!(missing, Err(ProcessError::Io(_))));
+    }
+
+    #[test]
+    fn mixed_case_types_read_and_unknown_ones_skipped() {
+        let state =
+            process_transaction_file("test-data/mixed_case_types.csv", State::new()).unwrap();
+
+        assert_eq!(state.clients[&1].available, amount("3.5"));
+        assert_eq!(state.clients[&1].held, amount("2.0"));
+        assert_eq!(state.stats.skipped, 1);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "skipped line 4 of test-data/mixed_case_types.csv: unknown transaction type 'foo'"
+            ]
+        );
+    }
+
+    #[test]
+    fn ragged_rows_read_or_skipped() {
+        // an extra trailing column is ignored, a missing amount column reads as blank, and
+        // rows without a tx are skipped rather than failing the file
+        let state = process_transaction_file("test-data/ragged.csv", State::new()).unwrap();
+
+        assert_eq!(state.clients[&1].available, amount("3.0"));
+        assert_eq!(state.clients[&2].held, amount("3.0"));
+        assert_eq!(state.rejected, 0);
+        assert_eq!(state.stats.skipped, 2);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "skipped line 5 of test-data/ragged.csv: field 2: cannot parse integer from \
+                 empty string",
+                "skipped line 7 of test-data/ragged.csv: field 2: cannot parse integer from \
+                 empty string",
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_range_client_ids_skipped() {
+        let path = "test-data/client_out_of_range.csv";
+        let state = process_transaction_file(path, State::new()).unwrap();
+
+        assert_eq!(state.clients.len(), 2);
+        assert_eq!(state.clients[&1].available, amount("0.5"));
+        assert_eq!(state.clients[&2].available, amount("2.0"));
+        assert_eq!(state.stats.skipped, 2);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "skipped line 3 of test-data/client_out_of_range.csv: client id 70000 is out \
+                 of range, it must be from 0 to 65535",
+                "skipped line 4 of test-data/client_out_of_range.csv: client id -3 is out of \
+                 range, it must be from 0 to 65535",
+            ]
+        );
+    }
+
+    #[test]
+    fn header_only_file_is_empty_but_empty_file_fails() {
+        let state = process_transaction_file("test-data/header_only.csv", State::new()).unwrap();
+
+        assert!(state.clients.is_empty());
+        assert_eq!(state.rejected, 0);
+
+        let empty = process_transaction_file("test-data/empty.csv", State::new());
+
+        assert!(matches!(empty, Err(ProcessError::MissingHeader)));
+    }
+
+    #[test]
+    fn same_file_twice_fails_as_duplicate_input() {
+        let path = "test-data/five_rows.csv";
+
+        // by default every row of the second pass is skipped, and the balances look right
+        let state = process_transaction_file(path, State::new()).unwrap();
+        let state = process_transaction_file(path, state).unwrap();
+        assert_eq!(state.clients[&1].available, amount("4.0"));
+        assert_eq!(state.stats.duplicates, 5);
+
+        let config = EngineConfig {
+            max_duplicate_fraction: Some(0.9),
+            ..EngineConfig::default()
+        };
+        let state = process_transaction_file(path, State::with_config(config)).unwrap();
+        let err = process_transaction_file(path, state).unwrap_err();
+
+        assert!(matches!(
+            err,
+            ProcessError::LikelyDuplicateInput {
+                duplicates: 5,
+                rows: 5
+            }
+        ));
+    }
+
+    #[test]
+    fn client_limit_fails_the_run() {
+        let config = EngineConfig {
+            max_clients: Some(2),
+            ..EngineConfig::default()
+        };
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    deposit, 2, 2, 1.0\n\
+                    deposit, 1, 3, 1.0\n\
+                    deposit, 3, 4, 1.0\n";
+        let read = || {
+            ReaderBuilder::new()
+                .trim(Trim::All)
+                .from_reader(data.as_bytes())
+                .into_deserialize()
+        };
+
+        let err = process_transactions_with_config(config.clone(), read()).unwrap_err();
+
+        assert!(matches!(err, ProcessError::TooManyClients { max: 2 }));
+        assert_eq!(err.to_string(), "input has more than 2 distinct clients");
+
+        // row by row, the deposit creating the third client is rejected, as is a transfer
+        // paying into a fourth, while the existing clients carry on
+        let mut state = read()
+            .map(Result::unwrap)
+            .fold(State::with_config(config), process_transaction);
+        let mut transfer = Transaction::new(TransactionType::Transfer, 1, 5, amount("1.0"));
+        transfer.dest_client_id = Some(4);
+        state = process_transaction(state, transfer);
+        state = process_transaction(state, Transaction::deposit(2, 6, amount("1.0")));
+
+        assert_eq!(state.clients.len(), 2);
+        assert_eq!(state.clients[&1].available, amount("2.0"));
+        assert_eq!(state.clients[&2].available, amount("2.0"));
+        assert_eq!(
+            state.recent_rejects().copied().collect::<Vec<_>>(),
+            vec![
+                (4, RejectReason::TooManyClients),
+                (5, RejectReason::TooManyClients)
+            ]
+        );
+        assert!(state.check_client_limit().is_err());
+        assert!(State::new().check_client_limit().is_ok());
+    }
+
+    #[test]
+    fn timestamps_checked_for_order() {
+        let process = |path: &str| {
+            let mut state = State::new();
+            state.config.check_timestamp_order = true;
+            process_transaction_file(path, state).unwrap()
+        };
+
+        // offsets and plain epoch seconds are read the same way, 10:30+01:00 is 09:30 UTC
+        let state = process("test-data/timestamps_in_order.csv");
+
+        assert_eq!(state.warnings().count(), 0);
+        assert_eq!(state.transfers[&2].timestamp, Some(1709285400));
+        assert_eq!(state.transfers[&4].timestamp, None);
+        assert_eq!(state.clients[&1].available, amount("6.0"));
+        assert_eq!(state.clients[&2].held, amount("3.0"));
+
+        // an out of order row is warned about, but still applied
+        let state = process("test-data/timestamps_out_of_order.csv");
+
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec!["transaction 3 is timestamped 3600 seconds before a transaction read ahead of it"]
+        );
+        assert_eq!(state.clients[&1].available, amount("4.0"));
+
+        let unchecked =
+            process_transaction_file("test-data/timestamps_out_of_order.csv", State::new())
+                .unwrap();
+        assert_eq!(unchecked.warnings().count(), 0);
+    }
+
+    #[test]
+    fn progress_reported_after_each_batch() {
+        let path = env::temp_dir().join(format!("progress-{}.csv", std::process::id()));
+        let mut data = String::from("type, client, tx, amount\n");
+        for id in 0..BATCH_SIZE + 10 {
+            data.push_str(&format!("deposit, 1, {}, 1.0\n", id));
+        }
+        fs::write(&path, data).unwrap();
+
+        let mut reports = Vec::new();
+        let state =
+            process_transaction_file_with_progress(&path, State::new(), |rows| reports.push(rows))
+                .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let rows = BATCH_SIZE as u64;
+        assert_eq!(reports, vec![rows, rows + 10]);
+        assert_eq!(state.clients[&1].transaction_count, rows + 10);
+    }
+
+    #[test]
+    fn streaming_keeps_only_disputable_transactions() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 5.0\n\
+                    deposit, 1, 2, 2.0\n\
+                    dispute, 1, 1,\n\
+                    chargeback, 1, 1,\n\
+                    deposit, 2, 1, 9.0\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let state = process_transactions(reader.deserialize()).unwrap();
+
+        assert!(!state.transfers.contains_key(&1));
+        assert!(state.transfers.contains_key(&2));
+        assert_eq!(state.clients[&1].total, amount("2.0"));
+        assert!(state.clients[&1].locked);
+
+        // the charged back id still can't be reused
+        assert_eq!(state.rejected, 1);
+        assert!(!state.clients.contains_key(&2));
+
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\nteleport, 1, 2, 1.0\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+
+        assert!(matches!(
+            process_transactions(reader.deserialize()),
+            Err(ProcessError::MalformedRow { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn negative_amounts_skipped() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,-5.0\n\
+                    deposit,1,2,10.0\n\
+                    withdrawal,1,3,-1.0\n";
+        let transactions: Vec<Transaction> = ReaderBuilder::new()
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        // the batched deposit path and process_transaction must agree
+        let batched = process_batch(State::new(), transactions.clone());
+        let single = transactions
+            .into_iter()
+            .fold(State::new(), process_transaction);
+
+        for state in [batched, single] {
+            assert_eq!(state.clients[&1].available, amount("10.0"));
+            assert_eq!(state.clients[&1].total, amount("10.0"));
+            assert_eq!(state.rejected, 2);
+            assert!(state
+                .recent_rejects()
+                .all(|(_, reason)| *reason == RejectReason::NegativeAmount));
+        }
+    }
+
+    #[test]
+    fn amounts_on_dispute_rows_rejected() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    dispute, 1, 1, 999.0\n\
+                    dispute, 1, 1,\n\
+                    resolve, 1, 1, 999.0\n";
+        let state = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .map(Result::unwrap)
+            .fold(State::new(), process_transaction);
+
+        // the bogus dispute and resolve are skipped and warned about, leaving the second
+        // dispute in place
+        assert_eq!(state.rejected, 2);
+        assert_eq!(state.clients[&1].held, amount("10.0"));
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec!["skipped transaction 1: amount given for a transaction that takes none"; 2]
+        );
+    }
+
+    #[test]
+    fn resumed_from_snapshot_matches_uninterrupted_run() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 2, 2, 5.5\n\
+                    withdrawal, 2, 3, 8.0\n\
+                    dispute, 1, 1,\n\
+                    deposit, 1, 2, 1.0\n\
+                    resolve, 1, 1,\n\
+                    dispute, 1, 1,\n\
+                    chargeback, 1, 1, 4.0\n\
+                    withdrawal, 2, 4, 1.5\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let new_state = || {
+            let mut state = State::new();
+            state.config.check_dispute_amount = true;
+            state.set_overdraft_limit(2, amount("3.0"));
+            state
+        };
+        let expected = rows.iter().cloned().fold(new_state(), process_transaction);
+
+        // checkpoint with tx 1 disputed and the reused id 2 still to come
+        let path = env::temp_dir().join(format!("snapshot-{}.json", std::process::id()));
+        rows[..4]
+            .iter()
+            .cloned()
+            .fold(new_state(), process_transaction)
+            .save(&path)
+            .unwrap();
+        let resumed = rows[4..]
+            .iter()
+            .cloned()
+            .fold(State::load(&path).unwrap(), process_transaction);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(resumed.clients, expected.clients);
+        assert_eq!(resumed.transfers, expected.transfers);
+        assert_eq!(resumed.rejected, expected.rejected);
+        assert!(resumed.recent_rejects().eq(expected.recent_rejects()));
+        assert!(resumed.warnings().eq(expected.warnings()));
+        assert!(resumed.config.check_dispute_amount);
+        assert_eq!(resumed.clients[&2].overdraft_limit, amount("3.0"));
+    }
+
+    #[test]
+    fn snapshot_saved_before_versioning_loads() {
+        // written by State::save as first released, with the settings at the top level and
+        // each client saved as a (client, overdraft limit) pair
+        let state = State::load("test-data/snapshot_unversioned.json").unwrap();
+
+        assert_eq!(state.config, EngineConfig::default());
+        assert_eq!(state.rejected, 1);
+        assert_eq!(state.clients[&1].available, amount("8.25"));
+        assert_eq!(state.clients[&1].peak_total, amount("10.5"));
+        assert_eq!(state.clients[&1].overdraft_limit, amount("5.0"));
+        assert_eq!(state.clients[&1].transaction_count, 0);
+        assert_eq!(state.clients[&2].held, amount("3.0"));
+        assert_eq!(state.transfers[&2].status, TransactionStatus::Disputed);
+        assert_eq!(state.locked_clients().collect::<Vec<_>>(), vec![3]);
+
+        let state = [
+            Transaction::resolve(2, 2),
+            Transaction::deposit(1, 1, amount("1.0")),
+            Transaction::withdrawal(1, 6, amount("12.0")),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert_eq!(state.clients[&2].available, amount("3.0"));
+        assert_eq!(state.clients[&1].available, amount("-3.75"));
+        assert_eq!(state.clients[&1].max_withdrawal, amount("12.0"));
+        assert_eq!(state.rejected, 2);
+    }
+
+    #[test]
+    fn snapshot_keeps_long_balances_exact() {
+        let config = EngineConfig {
+            precision: 8,
+            ..EngineConfig::default()
+        };
+        // the balances and the last deposit have more significant digits than a float keeps
+        let state = (1..=30)
+            .map(|id| Transaction::deposit(1, id, amount("92233720368.1237")))
+            .chain([
+                Transaction::deposit(2, 31, amount("92233720368.12345678")),
+                Transaction::dispute(2, 31),
+            ])
+            .fold(State::with_config(config), process_transaction);
+
+        let path = env::temp_dir().join(format!("long-{}.json", std::process::id()));
+        state.save(&path).unwrap();
+        let loaded = State::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.clients[&1].total, amount("2767011611043.711"));
+        assert_eq!(loaded.clients[&2].held, amount("92233720368.12345678"));
+        assert_eq!(loaded.clients, state.clients);
+        assert_eq!(loaded.transfers, state.transfers);
+    }
+
+    #[test]
+    fn locked_clients_follow_chargebacks_and_admin_rows() {
+        let state = process_csv(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 2.0\n\
+             deposit, 2, 2, 3.0\n\
+             deposit, 3, 3, 1.0\n\
+             dispute, 1, 1,\n\
+             chargeback, 1, 1,\n\
+             dispute, 2, 2,\n\
+             chargeback, 2, 2,\n",
+        );
+        let locked = |state: &State| {
+            let mut ids: Vec<u16> = state.locked_clients().collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(locked(&state), vec![1, 2]);
+
+        // a thaw unlocks, and a freeze locks, even a client not seen before
+        let mut state = state;
+        state.config.allow_admin_transactions = true;
+        let state = [
+            Transaction::new(TransactionType::Thaw, 1, 10, Amount::ZERO),
+            Transaction::new(TransactionType::Freeze, 4, 11, Amount::ZERO),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert_eq!(locked(&state), vec![2, 4]);
+
+        let path = env::temp_dir().join(format!("locked-{}.json", std::process::id()));
+        state.save(&path).unwrap();
+        let loaded = State::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(locked(&loaded), vec![2, 4]);
+    }
+
+    #[test]
+    fn amounts_rounded_to_precision() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 0.12345678\n\
+                    deposit, 1, 2, 1.5\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let state = process_batch(State::new(), rows.clone());
+        assert_eq!(state.clients[&1].available, amount("1.6235"));
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec!["rounded the amount of transaction 1 to 4 decimal places"]
+        );
+
+        let mut precise = State::new();
+        precise.config.precision = 8;
+        let state = rows.into_iter().fold(precise, process_transaction);
+        assert_eq!(state.clients[&1].available, amount("1.62345678"));
+        assert_eq!(state.warnings().count(), 0);
+    }
+
+    #[test]
+    fn excess_decimals_rounded_half_even_or_rejected() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.00005\n\
+                    deposit, 1, 2, 1.00015\n\
+                    deposit, 1, 3, 2.5\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let expected = [
+            (ExcessDecimals::Round, "4.5003", 0),
+            (ExcessDecimals::RoundHalfEven, "4.5002", 0),
+            (ExcessDecimals::Reject, "2.5", 2),
+        ];
+        for (excess_decimals, available, rejected) in expected {
+            let mut state = State::new();
+            state.config.excess_decimals = excess_decimals;
+
+            for row in &rows {
+                let valid = validate_transaction(&state, row);
+                assert_eq!(valid.is_err(), row.id != 3 && rejected > 0);
+            }
+
+            let batched = process_batch(state.empty_like(), rows.clone());
+            let state = rows.iter().cloned().fold(state, process_transaction);
+
+            assert_eq!(state.clients[&1].available, amount(available));
+            assert_eq!(state.rejected, rejected);
+            assert_eq!(batched.clients, state.clients);
+            assert_eq!(state.warnings().count(), 2);
+        }
+    }
+
+    #[test]
+    fn excess_digits_rounded_once_at_ledger_precision() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.000000001\n\
+                    deposit, 2, 2, 1.000049999\n\
+                    deposit, 3, 3, 1.000050001\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        // all three need rounding at four places, though the first reads as 1.0 at eight
+        let mut state = State::new();
+        state.config.excess_decimals = ExcessDecimals::Reject;
+        for row in &rows {
+            assert_eq!(
+                validate_transaction(&state, row),
+                Err(RejectReason::TooManyDecimals)
+            );
+        }
+        let state = rows.iter().cloned().fold(state, process_transaction);
+        assert_eq!(state.rejected, 3);
+
+        // rounding at eight places first would make the second 1.00005, and round it up
+        let state = rows.iter().cloned().fold(State::new(), process_transaction);
+        assert_eq!(state.clients[&1].available, amount("1.0"));
+        assert_eq!(state.clients[&2].available, amount("1.0"));
+        assert_eq!(state.clients[&3].available, amount("1.0001"));
+        assert_eq!(state.warnings().count(), 3);
+
+        let mut state = State::new();
+        state.config.excess_decimals = ExcessDecimals::RoundHalfEven;
+        let state = process_batch(state, rows);
+        assert_eq!(state.clients[&2].available, amount("1.0"));
+        assert_eq!(state.clients[&3].available, amount("1.0001"));
+    }
+
+    #[test]
+    fn activity_counts_only_applied_deposits_and_withdrawals() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 3.0\n\
+                    withdrawal, 1, 3, 50.0\n\
+                    deposit, 1, 1, 5.0\n\
+                    dispute, 1, 2,\n\
+                    resolve, 1, 2,\n\
+                    deposit, 2, 4, -1.0\n";
+        let rows: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        // the batched deposit path and process_transaction must agree
+        let batched = process_batch(State::new(), rows.clone());
+        let single = rows.into_iter().fold(State::new(), process_transaction);
+
+        for state in [batched, single] {
+            // the overdrawn withdrawal and the reused id are skipped, and the dispute and
+            // resolve don't count
+            let client = &state.clients[&1];
+            assert_eq!(client.transaction_count, 2);
+            assert_eq!(client.last_tx_id, Some(2));
+            assert_eq!(state.rejected, 3);
+            assert!(!state.clients.contains_key(&2));
+        }
+    }
+
+    fn disputed_deposit_charged_back(chargeback_amount: &str) -> State {
+        let data = format!(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 100.0\n\
+             deposit, 1, 2, 20.0\n\
+             dispute, 1, 1,\n\
+             chargeback, 1, 1, {}\n",
+            chargeback_amount
+        );
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+
+        reader
+            .deserialize()
+            .map(Result::unwrap)
+            .fold(State::new(), process_transaction)
+    }
+
+    #[test]
+    fn partial_chargeback() {
+        let state = disputed_deposit_charged_back("30.0");
+        let client = &state.clients[&1];
+
+        // 30.0 leaves the account and the other 70.0 is released back to available
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(client.available, amount("90.0"));
+        assert_eq!(client.total, amount("90.0"));
+        assert!(client.locked);
+
+        let state = disputed_deposit_charged_back("");
+        let client = &state.clients[&1];
+
+        assert_eq!(client.available, amount("20.0"));
+        assert_eq!(client.total, amount("20.0"));
+
+        let state = disputed_deposit_charged_back("100.5");
+        let client = &state.clients[&1];
+
+        assert_eq!(state.rejected, 1);
+        assert_eq!(client.held, amount("100.0"));
+        assert!(!client.locked);
+    }
+
+    #[test]
+    fn held_never_driven_negative() {
+        for settle in [TransactionType::Resolve, TransactionType::Chargeback] {
+            let mut state = disputed_deposit_charged_back("");
+            let mut deposit = state.transfers.remove(&2).unwrap();
+
+            // a disputed deposit whose amount was never moved into held
+            deposit.status = TransactionStatus::Disputed;
+            state.transfers.insert(2, deposit);
+            state.clients.get_mut(&1).unwrap().locked = false;
+
+            let tx = Transaction::new(settle, 1, 2, Amount::ZERO);
+            let state = process_transaction(state, tx);
+            let client = &state.clients[&1];
+
+            assert_eq!(client.held, Amount::ZERO);
+            assert_eq!(client.total, amount("20.0"));
+            assert!(!client.locked);
+            assert_eq!(
+                state.recent_rejects().last(),
+                Some(&(2, RejectReason::HeldUnderflow))
+            );
+            assert_eq!(state.warnings().count(), 1);
+        }
+    }
+
+    #[test]
+    fn removed_client_rejected_without_panic() {
+        for (settle, status) in [
+            (TransactionType::Dispute, TransactionStatus::Open),
+            (TransactionType::Resolve, TransactionStatus::Disputed),
+            (TransactionType::Chargeback, TransactionStatus::Disputed),
+        ] {
+            let deposit = Transaction::deposit(1, 1, amount("5.0"));
+            let mut state = process_transaction(State::new(), deposit);
+
+            // the transaction stays behind after its client is gone
+            state.clients.remove(&1);
+            state.transfers.get_mut(&1).unwrap().status = status;
+
+            let tx = Transaction::new(settle, 1, 1, Amount::ZERO);
+            let state = process_transaction(state, tx);
+
+            assert!(state.clients.is_empty());
+            assert_eq!(state.transfers[&1].status, status);
+            assert_eq!(
+                state.recent_rejects().last(),
+                Some(&(1, RejectReason::MissingOwner))
+            );
+            assert_eq!(state.warnings().count(), 1);
+        }
+    }
+
+    fn process_csv(data: &str) -> State {
+        ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .map(Result::unwrap)
+            .fold(State::new(), process_transaction)
+    }
+
+    #[test]
+    fn mismatched_client_warned_and_balances_unchanged() {
+        let state = process_csv(
+            "type, client, tx, amount, dest\n\
+             deposit, 1, 1, 10.0,\n\
+             deposit, 2, 2, 3.0,\n\
+             dispute, 2, 1,,\n\
+             dispute, 1, 1,,\n\
+             resolve, 2, 1,,\n\
+             chargeback, 2, 1,,\n",
+        );
+
+        let client = &state.clients[&1];
+        assert_eq!(client.available, Amount::ZERO);
+        assert_eq!(client.held, amount("10.0"));
+        assert!(!client.locked);
+        assert_eq!(state.clients[&2].available, amount("3.0"));
+        assert_eq!(state.rejected, 3);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec!["skipped transaction 1: referenced by client 2, but it belongs to client 1"; 3]
+        );
+    }
+
+    #[test]
+    fn transfer_moves_funds_between_clients() {
+        let state = process_csv(
+            "type, client, tx, amount, dest\n\
+             deposit, 1, 1, 10.0,\n\
+             deposit, 2, 2, 1.0,\n\
+             transfer, 1, 3, 4.0, 2\n",
+        );
+
+        assert_eq!(state.clients[&1].available, amount("6.0"));
+        assert_eq!(state.clients[&1].total, amount("6.0"));
+        assert_eq!(state.clients[&2].available, amount("5.0"));
+        assert_eq!(state.clients[&2].total, amount("5.0"));
+        assert_eq!(state.clients[&2].last_tx_id, Some(3));
+        assert_eq!(state.rejected, 0);
+    }
+
+    #[test]
+    fn transfer_with_insufficient_funds_rejected() {
+        let state = process_csv(
+            "type, client, tx, amount, dest\n\
+             deposit, 1, 1, 3.0,\n\
+             deposit, 2, 2, 1.0,\n\
+             transfer, 1, 3, 4.0, 2\n",
+        );
+
+        assert_eq!(state.clients[&1].available, amount("3.0"));
+        assert_eq!(state.clients[&2].available, amount("1.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(3, RejectReason::InsufficientFunds)]
+        );
+    }
+
+    #[test]
+    fn transfer_below_minimum_balance_rejected() {
+        let mut state = State::new();
+        state.config.min_balance = Some(amount("2.0"));
+
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction {
+                dest_client_id: Some(2),
+                ..Transaction::new(TransactionType::Transfer, 1, 2, amount("8.5"))
+            },
+            // leaves exactly the minimum
+            Transaction {
+                dest_client_id: Some(2),
+                ..Transaction::new(TransactionType::Transfer, 1, 3, amount("8.0"))
+            },
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert_eq!(state.clients[&1].available, amount("2.0"));
+        assert_eq!(state.clients[&2].available, amount("8.0"));
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(2, RejectReason::BelowMinimum)]
+        );
+    }
+
+    #[test]
+    fn transfer_creates_destination_client() {
+        let state = process_csv(
+            "type, client, tx, amount, dest\n\
+             deposit, 1, 1, 3.0,\n\
+             transfer, 1, 2, 1.25, 7\n\
+             dispute, 1, 2,,\n\
+             transfer, 1, 3, 1.0,\n",
+        );
+
+        let dest = &state.clients[&7];
+        assert_eq!(dest.available, amount("1.25"));
+        assert_eq!(dest.total, amount("1.25"));
+        assert_eq!(dest.peak_total, amount("1.25"));
+        assert_eq!(state.clients[&1].available, amount("1.75"));
+
+        // a transfer can't be disputed, and one without a destination is rejected
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![
+                &(2, RejectReason::NotDisputable),
+                &(3, RejectReason::MissingDestination)
+            ]
+        );
+    }
+
+    #[test]
+    fn transfer_to_own_client_rejected() {
+        let data = "type, client, tx, amount, dest\n\
+                    deposit, 1, 1, 3.0,\n\
+                    transfer, 1, 2, 1.0, 1\n";
+        let state = process_csv(data);
+
+        let client = &state.clients[&1];
+        assert_eq!(client.available, amount("3.0"));
+        assert_eq!(client.transaction_count, 1);
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(2, RejectReason::SelfTransfer)]
+        );
+
+        let transfer = Transaction {
+            dest_client_id: Some(1),
+            ..Transaction::new(TransactionType::Transfer, 1, 2, amount("1.0"))
+        };
+        assert_eq!(
+            validate_transaction(&State::new(), &transfer),
+            Err(RejectReason::SelfTransfer)
+        );
+    }
+
+    #[test]
+    fn stats_count_rows_by_outcome() {
+        let data = "type, client, tx, amount, dest\n\
+                    deposit, 1, 1, 10.0,\n\
+                    deposit, 2, 2, 5.0,\n\
+                    deposit, 1, 3, 1.0,\n\
+                    withdrawal, 1, 4, 2.0,\n\
+                    withdrawal, 2, 5, 50.0,\n\
+                    dispute, 1, 1,,\n\
+                    resolve, 1, 1,,\n\
+                    dispute, 2, 2,,\n\
+                    chargeback, 2, 2,,\n\
+                    transfer, 1, 6, 1.0, 3\n\
+                    deposit, 2, 7, 1.0,\n\
+                    deposit, 1, 3, 1.0,\n";
+        let expected = crate::audit::Stats {
+            rows: 12,
+            deposits: 3,
+            withdrawals: 1,
+            transfers: 1,
+            disputes: 2,
+            resolves: 1,
+            chargebacks: 1,
+            admin: 0,
+            // the overdrawn withdrawal, the deposit to the locked client and the repeated tx 3
+            skipped: 3,
+            duplicates: 1,
+        };
+
+        assert_eq!(process_csv(data).stats, expected);
+
+        // the batched deposit path counts the same way
+        let transactions = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(process_batch(State::new(), transactions).stats, expected);
+    }
+
+    fn dispute_settled_then_disputed(settlement: &str) -> State {
+        let data = format!(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 4.0\n\
+             dispute, 1, 1,\n\
+             {}, 1, 1,\n\
+             dispute, 1, 1,\n",
+            settlement
+        );
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+
+        reader
+            .deserialize()
+            .map(Result::unwrap)
+            .fold(State::new(), process_transaction)
+    }
+
+    #[test]
+    fn resolved_transaction_disputed_again() {
+        let state = dispute_settled_then_disputed("resolve");
+        let client = &state.clients[&1];
+
+        assert_eq!(state.rejected, 0);
+        assert_eq!(state.transfers[&1].status, TransactionStatus::Disputed);
+        assert_eq!(client.held, amount("4.0"));
+        assert_eq!(client.available, Amount::ZERO);
+    }
+
+    #[test]
+    fn charged_back_transaction_not_disputed_again() {
+        let state = dispute_settled_then_disputed("chargeback");
+        let client = &state.clients[&1];
+
+        assert_eq!(state.transfers[&1].status, TransactionStatus::ChargedBack);
+        assert_eq!(
+            state.recent_rejects().collect::<Vec<_>>(),
+            vec![&(1, RejectReason::ChargedBack)]
+        );
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(client.total, Amount::ZERO);
+    }
+
+    type Setup = fn(&mut State);
+
+    // the outcome audited for the last of `rows`, after checking every row was audited once,
+    // both row by row and through the batched deposit path
+    fn last_audited(setup: Setup, rows: &str) -> AuditOutcome {
+        let data = format!("type, client, tx, amount\n{}\n", rows);
+        let transactions: Vec<Transaction> = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let mut state = State::new();
+        state.audit_log = Some(Vec::new());
+        setup(&mut state);
+        let mut batched = State::new();
+        batched.audit_log = Some(Vec::new());
+        setup(&mut batched);
+
+        let count = transactions.len();
+        let batched = process_batch(batched, transactions.clone());
+        let single = transactions.into_iter().fold(state, process_transaction);
+
+        let single = single.audit_log.unwrap();
+        assert_eq!(single, batched.audit_log.unwrap());
+        assert_eq!(single.len(), count);
+
+        single.last().unwrap().outcome
+    }
+
+    #[test]
+    fn audit_outcome_for_every_branch() {
+        use RejectReason::*;
+
+        let none: Setup = |_| {};
+        let cases: Vec<(Setup, &str, AuditOutcome)> = vec![
+            (none, "deposit, 1, 1, 1.0", AuditOutcome::Applied),
+            (
+                none,
+                "deposit, 1, 1, 1.0\ndeposit, 1, 1, 1.0",
+                AuditOutcome::Rejected(DuplicateTransaction),
+            ),
+            (
+                none,
+                "withdrawal, 1, 1, 1.0",
+                AuditOutcome::Rejected(UnknownClient),
+            ),
+            (
+                none,
+                "dispute, 1, 9,",
+                AuditOutcome::Rejected(UnknownTransaction),
+            ),
+            (
+                none,
+                "deposit, 1, 1, 1.0\ndispute, 1, 1,\nchargeback, 1, 1,\ndeposit, 1, 2, 1.0",
+                AuditOutcome::Rejected(ClientLocked),
+            ),
+            (
+                none,
+                "deposit, 1, 1, 1.0\nwithdrawal, 1, 2, 2.0",
+                AuditOutcome::Rejected(InsufficientFunds),
+            ),
+            (
+                none,
+                "deposit, 1, 1, 1.0\ndispute, 2, 1,",
+                AuditOutcome::Rejected(ClientMismatch),
+            ),
+            (
+                none,
+                "deposit, 1, 1, 1.0\ndispute, 1, 1,\ndispute, 1, 1,",
+                AuditOutcome::Rejected(AlreadyDisputed),
+            ),
+            (
+                none,
+                "deposit, 1, 1, 1.0\nresolve, 1, 1,",
+                AuditOutcome::Rejected(NotDisputed),
+            ),
+            (
+                none,
+                "freeze, 1, 1,",
+                AuditOutcome::Rejected(AdminNotAllowed),
+            ),
+            (
+                |s| s.config.check_dispute_amount = true,
+                "deposit, 1, 1, 1.0\ndispute, 1, 1, 2.0",
+                AuditOutcome::Rejected(AmountMismatch),
+            ),
+            (
+                |s| s.config.max_tx_per_client = Some(1),
+                "deposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0",
+                AuditOutcome::Rejected(RateLimited),
+            ),
+            (
+                none,
+                "deposit, , 1, 1.0",
+                AuditOutcome::Rejected(MissingClient),
+            ),
+            (
+                none,
+                "deposit, 1, 1, -1.0",
+                AuditOutcome::Rejected(NegativeAmount),
+            ),
+            (
+                |s| {
+                    // a disputed deposit whose amount was never moved into held
+                    s.clients.insert(1, Client::new(1));
+                    s.transfers.insert(
+                        1,
+                        Transaction {
+                            status: TransactionStatus::Disputed,
+                            ..Transaction::deposit(1, 1, amount("1.0"))
+                        },
+                    );
+                },
+                "resolve, 1, 1,",
+                AuditOutcome::Rejected(HeldUnderflow),
+            ),
+            (
+                none,
+                "deposit, 1, 1, 1.0\ndispute, 1, 1,\nchargeback, 1, 1,\ndispute, 1, 1,",
+                AuditOutcome::Rejected(ChargedBack),
+            ),
+            (
+                |s| s.config.defer_early_disputes = true,
+                "dispute, 1, 1,",
+                AuditOutcome::Deferred,
+            ),
+            (
+                |s| s.config.only_clients = Some([2].into_iter().collect()),
+                "deposit, 1, 1, 1.0",
+                AuditOutcome::Filtered,
+            ),
+        ];
+
+        for (setup, rows, expected) in cases {
+            assert_eq!(last_audited(setup, rows), expected, "{}", rows);
+        }
+    }
+
+    #[test]
+    fn rollback_undoes_applied_transactions() {
+        let mut state = State::new();
+        state.undo_log = Some(Vec::new());
+
+        let state = [
+            Transaction::deposit(1, 1, amount("5.0")),
+            // rejected, so there is nothing to undo for it
+            Transaction::withdrawal(1, 2, amount("9.0")),
+            Transaction::withdrawal(1, 3, amount("2.0")),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert_eq!(state.clients[&1].available, amount("3.0"));
+
+        let mut state = state;
+        assert_eq!(state.rollback_last(1), 1);
+
+        let client = &state.clients[&1];
+        assert_eq!(client.available, amount("5.0"));
+        assert_eq!(client.total, amount("5.0"));
+        assert_eq!(client.transaction_count, 1);
+        assert!(!state.transfers.contains_key(&3));
+
+        // the id is free again, and the deposit can be undone too, removing the client
+        let mut state = process_transaction(state, Transaction::withdrawal(1, 3, amount("1.0")));
+        assert_eq!(state.clients[&1].available, amount("4.0"));
+        assert_eq!(state.rollback_last(5), 2);
+        assert!(state.clients.is_empty());
+        assert!(state.transfers.is_empty());
+        assert_eq!(state.rollback_last(1), 0);
+    }
+
+    #[test]
+    fn rollback_reopens_disputes_and_unlocks() {
+        let mut state = State::new();
+        state.undo_log = Some(Vec::new());
+
+        let mut state = [
+            Transaction::deposit(1, 1, amount("2.0")),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+        ]
+        .into_iter()
+        .fold(state, process_transaction);
+
+        assert!(state.clients[&1].locked);
+        assert_eq!(state.rollback_last(1), 1);
+
+        let client = &state.clients[&1];
+        assert!(!client.locked);
+        assert_eq!(client.held, amount("2.0"));
+        assert_eq!(client.available, Amount::ZERO);
+        assert_eq!(state.transfers[&1].status, TransactionStatus::Disputed);
+        assert_eq!(state.locked_clients().count(), 0);
+
+        assert_eq!(state.rollback_last(1), 1);
+        assert_eq!(state.clients[&1].available, amount("2.0"));
+        assert_eq!(state.transfers[&1].status, TransactionStatus::Open);
+
+        // without an undo log there is nothing to roll back
+        let mut state =
+            process_transaction(State::new(), Transaction::deposit(1, 1, amount("1.0")));
+        assert_eq!(state.rollback_last(1), 0);
+        assert_eq!(state.clients[&1].available, amount("1.0"));
+    }
+
+    #[test]
+    fn shadow_ledger_detects_drift() {
+        let mut state = State::new();
+        state.shadow_ledger = Some(ShadowLedger::default());
+
+        // small deposits onto a large balance lose precision in f64 on every addition
+        let mut deposits = vec![(1, "10000000000"), (2, "1.0"), (2, "2.0")];
+        deposits.extend((0..1000).map(|_| (1, "0.0001")));
+        let deposits = deposits
+            .into_iter()
+            .enumerate()
+            .map(|(id, (client_id, value))| {
+                Transaction::deposit(client_id, id as u32, amount(value))
+            });
+        let state = process_batch(state, deposits.collect());
+
+        let shadow_ledger = state.shadow_ledger.as_ref().unwrap();
+        let drift = shadow_ledger.drift(&state.clients, state.config.precision);
+
+        assert_eq!(state.clients[&1].total, amount("10000000000.1"));
+        assert_eq!(drift, vec![(1, shadow_ledger.total(1))]);
+        assert_eq!(shadow_ledger.total(2), 3.0);
+
+        // undoing a row moves the shadow back with the exact total
+        let mut state = State::new();
+        state.shadow_ledger = Some(ShadowLedger::default());
+        state.undo_log = Some(Vec::new());
+        let mut state = process_transaction(state, Transaction::deposit(1, 1, amount("0.1")));
+        state.rollback_last(1);
+        assert_eq!(state.shadow_ledger.unwrap().total(1), 0.0);
+    }
+
+    fn budget_after_round_trips(round_trips: u32) -> (u64, f64) {
+        let mut state = State::new();
+        state.error_budget = Some(ErrorBudget::default());
+
+        // only the amounts with more decimal places than the ledger's can add error
+        let mut transactions = vec![Transaction::deposit(1, 0, amount("1.0"))];
+        for i in 0..round_trips {
+            transactions.push(Transaction::withdrawal(1, 2 * i + 1, amount("0.50004")));
+            transactions.push(Transaction::deposit(1, 2 * i + 2, amount("0.50004")));
+        }
+        let state = transactions.into_iter().fold(state, process_transaction);
+
+        let error_budget = state.error_budget.as_ref().unwrap();
+        (
+            error_budget.rounded(1),
+            error_budget.budget(1, state.config.precision),
+        )
+    }
+
+    #[test]
+    fn error_budget_grows_with_transactions() {
+        let (few_rounded, few_budget) = budget_after_round_trips(5);
+        let (many_rounded, many_budget) = budget_after_round_trips(50);
+
+        assert_eq!(few_rounded, 10);
+        assert_eq!(many_rounded, 100);
+        assert_eq!(few_budget, 10.0 * 0.00005);
+        assert!(many_budget > few_budget * 9.0);
+    }
 }
@@ -0,0 +1,75 @@
+use std::io::{self, Stderr, Write};
+
+// rows processed between two progress lines
+const PROGRESS_INTERVAL: u64 = 1_000_000;
+
+// a running count of the rows processed, printed as it grows. it goes to stderr, so it never
+// mixes with the client rows on stdout
+pub struct Progress<W: Write = Stderr> {
+    writer: W,
+    rows: u64,
+    reported: u64,
+}
+
+impl Progress {
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+}
+
+impl<W: Write> Progress<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            rows: 0,
+            reported: 0,
+        }
+    }
+
+    // counts rows that were just processed, reporting the total each time another
+    // PROGRESS_INTERVAL rows have gone by. rows come a batch at a time, so a report can land
+    // a little past the interval
+    pub fn add(&mut self, rows: usize) {
+        self.rows += rows as u64;
+
+        if self.rows - self.reported >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    // reports the final total, unless the last report already had it
+    pub fn finish(&mut self) {
+        if self.rows != self.reported || self.rows == 0 {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        // progress is only informational, so failing to print it doesn't fail the run
+        let _ = writeln!(self.writer, "processed {} rows", self.rows);
+        self.reported = self.rows;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_interval_and_the_total() {
+        let mut output = Vec::new();
+        let mut progress = Progress::new(&mut output);
+
+        progress.add(10);
+        progress.add(PROGRESS_INTERVAL as usize);
+        progress.add(5);
+        progress.finish();
+
+        let expected = format!(
+            "processed {} rows\nprocessed {} rows\n",
+            PROGRESS_INTERVAL + 10,
+            PROGRESS_INTERVAL + 15
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
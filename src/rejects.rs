@@ -0,0 +1,86 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use transaction_tool::{error::ProcessError, types::State};
+
+// how many rejects are buffered before they are written out, so files with many
+// skipped rows don't pay for a write per row
+const REJECTS_FLUSH_ROWS: usize = 1024;
+
+// a CSV of every transaction the processor rejected, as tx id and reason
+pub struct RejectsFile {
+    writer: BufWriter<File>,
+    pending: usize,
+}
+
+impl RejectsFile {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "tx,reason")?;
+
+        Ok(Self { writer, pending: 0 })
+    }
+
+    // writes the rejects the state gained since it had rejected `before` transactions.
+    // they are read back from State::recent_rejects, so its capacity must cover every
+    // transaction processed between two calls
+    pub fn record(&mut self, state: &State, before: usize) -> Result<(), ProcessError> {
+        let recent: Vec<_> = state.recent_rejects().collect();
+        let new = (state.rejected - before).min(recent.len());
+
+        for (tx_id, reason) in &recent[recent.len() - new..] {
+            writeln!(self.writer, "{},{}", tx_id, reason)?;
+        }
+
+        self.pending += new;
+        if self.pending >= REJECTS_FLUSH_ROWS {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ProcessError> {
+        self.writer.flush()?;
+        self.pending = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use transaction_tool::types::RejectReason;
+
+    use super::*;
+
+    #[test]
+    fn buffered_rejects_all_written() {
+        let path = env::temp_dir().join(format!("rejects-{}.csv", std::process::id()));
+        let mut rejects = RejectsFile::create(&path).unwrap();
+
+        let count = REJECTS_FLUSH_ROWS * 2 + 5;
+        let mut state = State::with_recent_rejects_capacity(count);
+        for id in 0..count as u32 {
+            let before = state.rejected;
+            state.record_reject(id, RejectReason::UnknownClient);
+            rejects.record(&state, before).unwrap();
+        }
+        rejects.flush().unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        let mut lines = written.lines();
+
+        assert_eq!(lines.next(), Some("tx,reason"));
+        assert_eq!(lines.clone().count(), count);
+        assert_eq!(lines.last(), Some("2052,client does not exist"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
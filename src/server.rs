@@ -0,0 +1,106 @@
+use std::{error::Error, io::Cursor};
+
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use transaction_tool::{
+    input::{unreadable_row, InputReader, TransactionRows},
+    processor,
+    types::{self, Client, State, Transaction},
+};
+
+// what the unreadable rows of a request are said to be in, e.g. `line 3 of request`
+const REQUEST_SOURCE: &str = "request";
+
+// where each row skipped as unreadable was, and why, as unreadable_row gives them
+type Skipped = Vec<(String, String)>;
+
+// serves POST /process until the process is stopped. each request's transactions are applied
+// on top of everything posted before, and the reply is the full client report as JSON, with
+// the rows of the request that were skipped as unreadable
+pub fn serve(addr: &str, input: &InputReader, mut state: State) -> Result<(), Box<dyn Error>> {
+    let server = Server::http(addr).map_err(|err| err as Box<dyn Error>)?;
+    eprintln!("Listening on {}", addr);
+
+    for mut request in server.incoming_requests() {
+        let (status, body) = match (request.method(), request.url()) {
+            (Method::Post, "/process") => match read_transactions(&mut request, input) {
+                Ok((transactions, skipped)) => {
+                    for (location, reason) in &skipped {
+                        state.skip_unreadable_row(location, reason);
+                    }
+                    state = processor::process_batch(state, transactions);
+                    // the batch is applied either way, so a failed report only fails the reply
+                    match client_report(&state, &skipped) {
+                        Ok(report) => (200, report),
+                        Err(err) => (500, serde_json::to_string(&err.to_string())?),
+                    }
+                }
+                Err(err) => (400, serde_json::to_string(&err.to_string())?),
+            },
+            _ => (404, String::from("\"not found\"")),
+        };
+
+        let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+        let response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(content_type);
+
+        // a client that hangs up early shouldn't take the server down
+        if let Err(err) = request.respond(response) {
+            eprintln!("Failed to respond: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+// a JSON array when the request says so, otherwise CSV with a header row, read as on the
+// command line. the whole body is read before any of it is applied, so a body that can't be
+// read at all changes nothing. rows that can't be read as transactions are left out, with
+// where they were and why
+fn read_transactions(
+    request: &mut Request,
+    input: &InputReader,
+) -> Result<(Vec<Transaction>, Skipped), Box<dyn Error>> {
+    let json = request.headers().iter().any(|header| {
+        header.field.equiv("Content-Type") && header.value.as_str().starts_with("application/json")
+    });
+
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body)?;
+
+    if json {
+        return Ok((types::transactions_from_json(&body)?, Vec::new()));
+    }
+
+    let (mut transactions, mut skipped) = (Vec::new(), Vec::new());
+    for record in TransactionRows::from_reader(input, Box::new(Cursor::new(body)))? {
+        match record {
+            Ok(transaction) => transactions.push(transaction),
+            Err(err) => skipped.push(unreadable_row(REQUEST_SOURCE, err)?),
+        }
+    }
+
+    Ok((transactions, skipped))
+}
+
+// the clients rounded to the ledger precision, as in the json output of the command line
+fn client_report(state: &State, skipped: &[(String, String)]) -> Result<String, Box<dyn Error>> {
+    let mut clients: Vec<Client> = state
+        .clients
+        .values()
+        .map(|client| client.rounded(state.config.precision))
+        .collect();
+    clients.sort_by_key(|c| c.id);
+
+    let skipped: Vec<_> = skipped
+        .iter()
+        .map(|(location, reason)| json!({ "location": location, "reason": reason }))
+        .collect();
+
+    let report = json!({
+        "clients": types::clients_to_json(&clients)?,
+        "skipped": skipped,
+    });
+    Ok(serde_json::to_string(&report)?)
+}
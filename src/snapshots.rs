@@ -0,0 +1,66 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use transaction_tool::{types::State, ProcessError};
+
+use crate::{write_client_state, Options};
+
+// writes every client's balances to a new csv file in `dir` each time another `every` rows
+// have been processed. each file is named after the time it was written and the rows
+// processed by then, e.g. `snapshot-1709283600-5000.csv`, so they sort in the order written
+pub struct Snapshots {
+    dir: PathBuf,
+    every: usize,
+    rows: usize,
+}
+
+impl Snapshots {
+    pub fn new(dir: PathBuf, every: usize) -> Self {
+        Self {
+            dir,
+            every,
+            rows: 0,
+        }
+    }
+
+    // counts one processed row, and snapshots the state if it completes another interval. a
+    // snapshot that can't be written fails the run like any other output
+    pub fn add(&mut self, state: &State) -> Result<(), ProcessError> {
+        self.rows += 1;
+
+        if self.rows.is_multiple_of(self.every) {
+            self.write(state)?;
+        }
+
+        Ok(())
+    }
+
+    // in the plain machine format with every column, whatever --locale or --columns say for
+    // the final output, so every snapshot reads the same way
+    fn write(&mut self, state: &State) -> Result<(), ProcessError> {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let path = self
+            .dir
+            .join(format!("snapshot-{}-{}.csv", seconds, self.rows));
+        let options = Options {
+            precision: Some(state.config.precision),
+            ..Options::default()
+        };
+
+        write_client_state(
+            &state.clients,
+            None,
+            BufWriter::new(File::create(&path)?),
+            &options,
+        )
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+// seconds since the epoch for an ISO 8601 date and time, e.g. `2024-03-01T12:30:00Z` or
+// `2024-03-01 13:30:00.250+01:00`, or for a date alone, taken as midnight. fractions of a
+// second are dropped, and a time without an offset is taken as UTC. None if the text isn't
+// one, or is before 1970
+pub fn parse_iso8601(s: &str) -> Option<u64> {
+    let (date, time) = match s.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let mut fields = date.splitn(3, '-');
+    let year = digits(fields.next()?, 4)?;
+    let month = digits(fields.next()?, 2)?;
+    let day = digits(fields.next()?, 2)?;
+
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let mut seconds = days_from_civil(year, month, day) * 86400;
+
+    if let Some(time) = time {
+        let (clock, offset) = match time.find(['Z', '+', '-']) {
+            Some(at) => time.split_at(at),
+            None => (time, ""),
+        };
+
+        seconds += clock_seconds(clock)?;
+        seconds -= offset_seconds(offset)?;
+    }
+
+    u64::try_from(seconds).ok()
+}
+
+// `HH:MM` or `HH:MM:SS`, with an optional fraction of a second
+fn clock_seconds(clock: &str) -> Option<i64> {
+    let clock = match clock.split_once('.') {
+        Some((clock, fraction))
+            if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            clock
+        }
+        Some(_) => return None,
+        None => clock,
+    };
+
+    let mut fields = clock.splitn(3, ':');
+    let hours = digits(fields.next()?, 2)?;
+    let minutes = digits(fields.next()?, 2)?;
+    let seconds = match fields.next() {
+        Some(seconds) => digits(seconds, 2)?,
+        None => 0,
+    };
+
+    if hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+// `Z`, or `+HH:MM`, `+HHMM` or `+HH` ahead of UTC, and the same with `-` behind it
+fn offset_seconds(offset: &str) -> Option<i64> {
+    let (sign, offset) = match offset.split_at_checked(1) {
+        None => return Some(0),
+        Some(("Z", "")) => return Some(0),
+        Some(("+", offset)) => (1, offset),
+        Some(("-", offset)) => (-1, offset),
+        Some(_) => return None,
+    };
+
+    let (hours, minutes) = match offset.len() {
+        2 => (offset, "00"),
+        4 => offset.split_at(2),
+        5 if &offset[2..3] == ":" => (&offset[..2], &offset[3..]),
+        _ => return None,
+    };
+    let (hours, minutes) = (digits(hours, 2)?, digits(minutes, 2)?);
+
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+// a field of exactly `len` ascii digits
+fn digits(field: &str, len: usize) -> Option<i64> {
+    if field.len() != len || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    field.parse().ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// days from 1970-01-01 to a date in the proleptic gregorian calendar, counting in 400 year
+// eras that start on the 1st of March, so the leap day falls at the end of each year
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso8601_read_as_epoch_seconds() {
+        assert_eq!(parse_iso8601("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_iso8601("2023-11-14T22:13:20Z"), Some(1700000000));
+        assert_eq!(parse_iso8601("2023-11-14 22:13:20.999"), Some(1700000000));
+        assert_eq!(parse_iso8601("2023-11-14T23:13:20+01:00"), Some(1700000000));
+        assert_eq!(parse_iso8601("2023-11-14T17:13:20-0500"), Some(1700000000));
+        assert_eq!(parse_iso8601("2023-11-14T22:13Z"), Some(1699999980));
+        assert_eq!(parse_iso8601("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_iso8601("2000-03-01T00:00:00Z"), Some(951868800));
+    }
+
+    #[test]
+    fn malformed_or_early_dates_rejected() {
+        for text in [
+            "",
+            "yesterday",
+            "2023-02-29",
+            "2023-13-01",
+            "2023-1-01",
+            "2023-11-14T24:00:00Z",
+            "2023-11-14T22:13:20.Z",
+            "2023-11-14T22:13:20+1",
+            "2023-11-14T22:13:20 UTC",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert_eq!(parse_iso8601(text), None, "{}", text);
+        }
+    }
+}
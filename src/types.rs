@@ -1,8 +1,25 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    mem,
+    path::Path,
+    str::FromStr,
+};
 
-use serde::{de, Deserialize, Serialize};
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Deserialize, Debug, PartialEq)]
+use crate::{
+    amount::{Amount, Excess},
+    audit::{AuditEvent, AuditOutcome, Stats},
+    error::ProcessError,
+    ledger::{InMemoryLedger, Ledger},
+    processor, timestamp,
+};
+
+// read with FromStr, in any case, and written in lowercase
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -10,35 +27,353 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    // administrative, only accepted when EngineConfig::allow_admin_transactions is set
+    Freeze,
+    Thaw,
+    // moves funds from the client to another one, named in the dest column
+    Transfer,
+}
+
+const TRANSACTION_TYPE_NAMES: [(&str, TransactionType); 9] = [
+    ("deposit", TransactionType::Deposit),
+    ("withdrawal", TransactionType::Withdrawal),
+    ("dispute", TransactionType::Dispute),
+    ("resolve", TransactionType::Resolve),
+    ("chargeback", TransactionType::Chargeback),
+    ("freeze", TransactionType::Freeze),
+    ("thaw", TransactionType::Thaw),
+    ("unfreeze", TransactionType::Thaw),
+    ("transfer", TransactionType::Transfer),
+];
+
+// feeds differ in how they capitalise the type, e.g. `Deposit` or `WITHDRAWAL`, so case is
+// ignored
+impl FromStr for TransactionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TRANSACTION_TYPE_NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, transaction_type)| *transaction_type)
+            .ok_or_else(|| format!("unknown transaction type '{}'", s))
+    }
+}
+
+// visits the str in place, so reading a row's type doesn't allocate
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TypeVisitor;
+
+        impl de::Visitor<'_> for TypeVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<TransactionType, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(TypeVisitor)
+    }
+}
+
+// where a deposit or withdrawal is in the dispute process. a resolved transaction may be
+// disputed again, a charged back one never
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionStatus {
+    #[default]
+    Open,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    // a deposit to a locked client kept in its escrow. the funds never reached its balances,
+    // so there is nothing a dispute could hold
+    Escrowed,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "TransactionRow")]
 pub struct Transaction {
-    #[serde(rename = "type")]
     pub transaction_type: TransactionType,
 
-    #[serde(rename = "client")]
-    pub client_id: u16,
+    // may be left blank on disputes, resolves and chargebacks, see MissingClientPolicy
+    pub client_id: Option<u16>,
 
-    #[serde(rename = "tx")]
     pub id: u32,
 
-    #[serde(deserialize_with = "optional_amount_value")]
-    pub amount: f64,
+    // zero when left blank, which disputes, resolves and chargebacks usually are
+    pub amount: Amount,
 
-    #[serde(default)]
-    pub disputed: bool,
+    // whatever the amount was read with past MAX_PRECISION decimal places, until
+    // State::round_to_precision rounds the two together to the ledger precision
+    pub amount_excess: Excess,
+
+    // the client a transfer pays into. unused by every other type
+    pub dest_client_id: Option<u16>,
+
+    pub status: TransactionStatus,
+
+    // seconds since the epoch, read from an integer or an ISO 8601 date and time. only
+    // required when merging several files by time
+    pub timestamp: Option<u64>,
+
+    // any other columns in the input, e.g. source or memo, kept for audit but never used
+    // in balance math
+    pub metadata: HashMap<String, String>,
+}
+
+// constructors for transactions built in code rather than read from a file. each starts
+// open, with no timestamp, destination or metadata
+impl Transaction {
+    pub fn new(transaction_type: TransactionType, client_id: u16, id: u32, amount: Amount) -> Self {
+        Self {
+            transaction_type,
+            client_id: Some(client_id),
+            id,
+            amount,
+            amount_excess: Excess::default(),
+            dest_client_id: None,
+            status: TransactionStatus::Open,
+            timestamp: None,
+            metadata: HashMap::new(),
+        }
+    }
+
+    pub fn deposit(client_id: u16, id: u32, amount: Amount) -> Self {
+        Self::new(TransactionType::Deposit, client_id, id, amount)
+    }
+
+    pub fn withdrawal(client_id: u16, id: u32, amount: Amount) -> Self {
+        Self::new(TransactionType::Withdrawal, client_id, id, amount)
+    }
+
+    // disputes, resolves and chargebacks name the transaction they refer to, so take no amount
+    pub fn dispute(client_id: u16, id: u32) -> Self {
+        Self::new(TransactionType::Dispute, client_id, id, Amount::ZERO)
+    }
+
+    pub fn resolve(client_id: u16, id: u32) -> Self {
+        Self::new(TransactionType::Resolve, client_id, id, Amount::ZERO)
+    }
+
+    pub fn chargeback(client_id: u16, id: u32) -> Self {
+        Self::new(TransactionType::Chargeback, client_id, id, Amount::ZERO)
+    }
+
+    // whether the amount, as read, has no more than this many decimal places
+    pub fn fits_precision(&self, precision: u32) -> bool {
+        self.amount_excess == Excess::default() && self.amount.round_to(precision) == self.amount
+    }
+}
+
+// a transaction as read, before its amount is checked against its type
+struct TransactionRow {
+    transaction_type: TransactionType,
+    client_id: Option<u16>,
+    id: u32,
+
+    // the text of an amount that isn't a number is kept for the error message
+    amount: Result<(Amount, Excess), String>,
+
+    dest_client_id: Option<u16>,
+    status: Option<TransactionStatus>,
+
+    // read from files written before status replaced it
+    disputed: bool,
+
+    timestamp: Option<u64>,
+    metadata: HashMap<String, String>,
+}
+
+// the columns that take a custom deserializer, so the row visitor can read them as values
+struct ClientColumn(Option<u16>);
+struct AmountColumn(Result<(Amount, Excess), String>);
+struct TimestampColumn(Option<u64>);
+
+impl<'de> Deserialize<'de> for ClientColumn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        optional_client_id(deserializer).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AmountColumn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        optional_amount_value(deserializer).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimestampColumn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        optional_timestamp(deserializer).map(Self)
+    }
+}
+
+// the columns TransactionRow reads into fields. any other is kept in metadata
+const COLUMNS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "dest",
+    "status",
+    "disputed",
+    "timestamp",
+];
+
+// read column by column rather than with a flattened map for the extra columns, since serde
+// buffers a flattened row first and that buffer can't hold the 128-bit integers csv reads
+// very long numbers as
+impl<'de> Deserialize<'de> for TransactionRow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RowVisitor;
+
+        impl<'de> de::Visitor<'de> for RowVisitor {
+            type Value = TransactionRow;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a transaction row")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<TransactionRow, A::Error> {
+                let (mut transaction_type, mut id) = (None, None);
+                let (mut client_id, mut dest_client_id) = (None, None);
+                let mut amount = blank_amount();
+                let mut status = None;
+                let mut disputed = false;
+                let mut timestamp = None;
+                let mut metadata = HashMap::new();
+
+                while let Some(column) = map.next_key::<String>()? {
+                    match column.as_str() {
+                        "type" => transaction_type = Some(map.next_value()?),
+                        "client" => client_id = map.next_value::<ClientColumn>()?.0,
+                        "tx" => id = Some(map.next_value()?),
+                        "amount" => amount = map.next_value::<AmountColumn>()?.0,
+                        "dest" => dest_client_id = map.next_value::<ClientColumn>()?.0,
+                        "status" => status = map.next_value()?,
+                        "disputed" => disputed = map.next_value()?,
+                        "timestamp" => timestamp = map.next_value::<TimestampColumn>()?.0,
+                        // read as text, so e.g. `007` and `1.50` are kept as written
+                        _ => {
+                            let value = map.next_value::<String>()?;
+                            metadata.insert(column, value);
+                        }
+                    }
+                }
+
+                Ok(TransactionRow {
+                    transaction_type: transaction_type
+                        .ok_or_else(|| de::Error::missing_field("type"))?,
+                    client_id,
+                    id: id.ok_or_else(|| de::Error::missing_field("tx"))?,
+                    amount,
+                    dest_client_id,
+                    status,
+                    disputed,
+                    timestamp,
+                    metadata,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(RowVisitor)
+    }
+}
+
+fn blank_amount() -> Result<(Amount, Excess), String> {
+    Ok((Amount::ZERO, Excess::default()))
+}
+
+// an amount that isn't a number fails the row, except on admin rows, which ignore theirs.
+// disputes, resolves and chargebacks may leave it blank, which reads as zero
+impl TryFrom<TransactionRow> for Transaction {
+    type Error = String;
+
+    fn try_from(row: TransactionRow) -> Result<Self, Self::Error> {
+        let (amount, amount_excess) = match (row.amount, row.transaction_type) {
+            (Ok(amount), _) => amount,
+            (Err(_), TransactionType::Freeze | TransactionType::Thaw) => blank_amount()?,
+            (Err(text), _) => return Err(format!("invalid amount '{}'", text)),
+        };
+
+        Ok(Self {
+            transaction_type: row.transaction_type,
+            client_id: row.client_id,
+            id: row.id,
+            amount,
+            amount_excess,
+            dest_client_id: row.dest_client_id,
+            status: row.status.unwrap_or(match row.disputed {
+                true => TransactionStatus::Disputed,
+                false => TransactionStatus::Open,
+            }),
+            timestamp: row.timestamp,
+            metadata: row.metadata,
+        })
+    }
 }
 
-#[derive(Serialize, Debug)]
+// written by hand to leave out metadata: a flattened field makes serde write the whole
+// struct as a map, which the csv writer doesn't support, and the extra columns can differ
+// from row to row, so they wouldn't fit a single header anyway
+impl Serialize for Transaction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Transaction", 7)?;
+        s.serialize_field("type", &self.transaction_type)?;
+        s.serialize_field("client", &self.client_id)?;
+        s.serialize_field("tx", &self.id)?;
+        s.serialize_field("amount", &self.amount)?;
+        s.serialize_field("status", &self.status)?;
+        s.serialize_field("timestamp", &self.timestamp)?;
+        s.serialize_field("dest", &self.dest_client_id)?;
+        s.end()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Client {
     #[serde(rename = "client")]
     pub id: u16,
 
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
     pub locked: bool,
+
+    // the highest total this client reached at any point during processing
+    pub peak_total: Amount,
+
+    // deposits received while locked, kept apart from the client's balances
+    pub escrow: Amount,
+
+    // deposits, withdrawals and transfers applied to this client, including deposits into
+    // escrow and transfers in. disputes, resolves and chargebacks act on those, so they
+    // aren't counted
+    #[serde(default)]
+    pub transaction_count: u64,
+
+    // the id of the most recently applied of those transactions
+    pub last_tx_id: Option<u32>,
+
+    // how far below zero withdrawals may take available. it is configuration rather than
+    // a balance, so it is left out of the output
+    #[serde(skip_serializing, default)]
+    pub overdraft_limit: Amount,
+
+    // the largest deposit credited to and the largest withdrawal applied from this client.
+    // they are only written with --extended, so they are left out of the output as well
+    #[serde(skip_serializing, default)]
+    pub max_deposit: Amount,
+    #[serde(skip_serializing, default)]
+    pub max_withdrawal: Amount,
 }
 
 impl Client {
@@ -46,37 +381,2418 @@ impl Client {
         Self {
             id,
             locked: false,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
+            peak_total: Amount::ZERO,
+            escrow: Amount::ZERO,
+            transaction_count: 0,
+            last_tx_id: None,
+            overdraft_limit: Amount::ZERO,
+            max_deposit: Amount::ZERO,
+            max_withdrawal: Amount::ZERO,
         }
     }
-}
 
-#[derive(Debug)]
-pub struct State {
-    pub transfers: HashMap<u32, Transaction>,
-    pub clients: HashMap<u16, Client>,
-}
+    // counts an applied deposit, withdrawal or transfer
+    pub fn record_activity(&mut self, tx_id: u32) {
+        self.transaction_count += 1;
+        self.last_tx_id = Some(tx_id);
+    }
 
-impl State {
-    pub fn new() -> Self {
+    // a copy with each balance rounded to the given number of decimal places, for output
+    pub fn rounded(&self, precision: u32) -> Self {
         Self {
-            transfers: HashMap::new(),
-            clients: HashMap::new(),
+            id: self.id,
+            available: self.available.round_to(precision),
+            held: self.held.round_to(precision),
+            total: self.total.round_to(precision),
+            locked: self.locked,
+            peak_total: self.peak_total.round_to(precision),
+            escrow: self.escrow.round_to(precision),
+            transaction_count: self.transaction_count,
+            last_tx_id: self.last_tx_id,
+            overdraft_limit: self.overdraft_limit,
+            max_deposit: self.max_deposit.round_to(precision),
+            max_withdrawal: self.max_withdrawal.round_to(precision),
         }
     }
+
+    // the balances and counters, named as in the output, for State::diff. the overdraft limit
+    // and the largest deposit and withdrawal are left out, as they are from the output
+    fn field_values(&self) -> [(&'static str, String); 8] {
+        [
+            ("available", self.available.to_string()),
+            ("held", self.held.to_string()),
+            ("total", self.total.to_string()),
+            ("locked", self.locked.to_string()),
+            ("peak_total", self.peak_total.to_string()),
+            ("escrow", self.escrow.to_string()),
+            ("transaction_count", self.transaction_count.to_string()),
+            (
+                "last_tx_id",
+                self.last_tx_id.map_or(String::new(), |id| id.to_string()),
+            ),
+        ]
+    }
 }
 
-// since amount can be blank for some transaction types,
-// this is a custom deserializer fn to handle the empty string case
-pub fn optional_amount_value<'de, D>(deserializer: D) -> Result<f64, D::Error>
+/// The clients as a JSON array, with the same fields as the CSV output and `locked` as a
+/// boolean. Amounts are JSON numbers holding exactly their decimal, e.g. `1.5`, however many
+/// significant digits it has, rather than the nearest float.
+pub fn clients_to_json<'a, I>(clients: I) -> serde_json::Result<serde_json::Value>
 where
-    D: de::Deserializer<'de>,
+    I: IntoIterator<Item = &'a Client>,
 {
-    let s: &str = de::Deserialize::deserialize(deserializer)?;
-    match s.parse::<f64>() {
-        Ok(f) => Ok(f),
-        Err(_) => Ok(0.0),
+    let mut report = Vec::new();
+
+    for client in clients {
+        let mut fields = serde_json::to_value(client)?;
+
+        // Amount serializes as text, and is the only text in a client
+        let values = fields
+            .as_object_mut()
+            .into_iter()
+            .flat_map(|f| f.values_mut());
+        for value in values {
+            if let serde_json::Value::String(amount) = value {
+                *value = serde_json::Value::Number(amount.parse()?);
+            }
+        }
+
+        report.push(fields);
+    }
+
+    Ok(serde_json::Value::Array(report))
+}
+
+// why a transaction was skipped by the processor
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    DuplicateTransaction,
+    UnknownClient,
+    UnknownTransaction,
+    ClientLocked,
+    InsufficientFunds,
+    ClientMismatch,
+    AlreadyDisputed,
+    NotDisputed,
+    AdminNotAllowed,
+    AmountMismatch,
+    RateLimited,
+    MissingClient,
+    NegativeAmount,
+    HeldUnderflow,
+    ChargedBack,
+    UnexpectedAmount,
+    MissingOwner,
+    MissingDestination,
+    NotDisputable,
+    TooManyDecimals,
+    ConflictingTransaction,
+    TooManyClients,
+    FundsHeld,
+    RepeatedWithdrawal,
+    BelowMinimum,
+    SelfTransfer,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            RejectReason::DuplicateTransaction => "transaction id already used",
+            RejectReason::UnknownClient => "client does not exist",
+            RejectReason::UnknownTransaction => "referenced transaction does not exist",
+            RejectReason::ClientLocked => "client account is locked",
+            RejectReason::InsufficientFunds => "insufficient available funds",
+            RejectReason::ClientMismatch => "referenced transaction belongs to another client",
+            RejectReason::AlreadyDisputed => "referenced transaction is already disputed",
+            RejectReason::NotDisputed => "referenced transaction is not disputed",
+            RejectReason::AdminNotAllowed => "administrative transactions are not allowed",
+            RejectReason::AmountMismatch => "amount does not match the referenced transaction",
+            RejectReason::RateLimited => "client exceeded its transaction limit",
+            RejectReason::MissingClient => "client field is missing",
+            RejectReason::NegativeAmount => "amount is negative",
+            RejectReason::HeldUnderflow => "held funds are less than the referenced amount",
+            RejectReason::ChargedBack => "referenced transaction was charged back",
+            RejectReason::UnexpectedAmount => "amount given for a transaction that takes none",
+            RejectReason::MissingOwner => "client of the referenced transaction does not exist",
+            RejectReason::MissingDestination => "destination client is missing",
+            RejectReason::NotDisputable => "referenced transaction can't be disputed",
+            RejectReason::TooManyDecimals => "amount has more decimal places than the ledger",
+            RejectReason::ConflictingTransaction => {
+                "transaction id already used for a different transaction"
+            }
+            RejectReason::TooManyClients => "client would exceed the maximum number of clients",
+            RejectReason::FundsHeld => "funds needed are held by a dispute",
+            RejectReason::RepeatedWithdrawal => {
+                "withdrawal repeats the amount of a recent one from the client"
+            }
+            RejectReason::BelowMinimum => "withdrawal would leave less than the minimum balance",
+            RejectReason::SelfTransfer => "transfer is to its own client",
+        };
+
+        write!(f, "{}", reason)
+    }
+}
+
+// what State::apply did with a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApplyOutcome {
+    // with the client it acted for, as it is now
+    Applied(Client),
+    Rejected(RejectReason),
+    // an early dispute held until its deposit arrives, see EngineConfig::defer_early_disputes
+    Deferred,
+    // skipped without a rejection, for a client outside EngineConfig::only_clients
+    Filtered,
+}
+
+// a client whose balances break a rule the engine always keeps, found by
+// State::verify_invariants. only a corrupted state or a bug in the processors can cause one
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantViolation {
+    // available + held differs from total
+    TotalMismatch {
+        client_id: u16,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+    NegativeHeld {
+        client_id: u16,
+        held: Amount,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvariantViolation::TotalMismatch {
+                client_id,
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "client {}: available {} plus held {} is not total {}",
+                client_id, available, held, total
+            ),
+            InvariantViolation::NegativeHeld { client_id, held } => {
+                write!(f, "client {}: held {} is negative", client_id, held)
+            }
+        }
+    }
+}
+
+/// Sums of the balances across every client, from `State::aggregate`, for reconciling the
+/// ledger against an external one. The sums are exact, however many clients there are.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Aggregate {
+    pub total: Amount,
+    pub available: Amount,
+    pub held: Amount,
+    pub client_count: usize,
+    pub locked_count: usize,
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} clients, {} locked: available {}, held {}, total {}",
+            self.client_count, self.locked_count, self.available, self.held, self.total
+        )
+    }
+}
+
+// a client whose fields differ between two states, found by State::diff
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientDiff {
+    pub client_id: u16,
+    pub fields: Vec<FieldDiff>,
+}
+
+// one field of a client, with its value in the state diffed and in the other state
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub ours: String,
+    pub theirs: String,
+}
+
+// e.g. `client 2: held 0.0 -> 1.5, total 1.5 -> 3.0`
+impl fmt::Display for ClientDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "client {}:", self.client_id)?;
+
+        for (i, diff) in self.fields.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let (ours, theirs) = (blank_as_none(&diff.ours), blank_as_none(&diff.theirs));
+            write!(f, "{} {} {} -> {}", separator, diff.field, ours, theirs)?;
+        }
+
+        Ok(())
+    }
+}
+
+// a field with no value, e.g. the last_tx_id of a client without transactions
+fn blank_as_none(value: &str) -> &str {
+    if value.is_empty() {
+        "none"
+    } else {
+        value
+    }
+}
+
+// how a dispute, resolve or chargeback with a blank client field is handled. any other
+// transaction without a client is always rejected
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum MissingClientPolicy {
+    #[default]
+    Reject,
+    // act for whichever client owns the referenced transaction
+    MatchOwner,
+}
+
+impl FromStr for MissingClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(MissingClientPolicy::Reject),
+            "match-owner" => Ok(MissingClientPolicy::MatchOwner),
+            _ => Err(format!("Unsupported missing client policy '{}'", s)),
+        }
+    }
+}
+
+// every policy for how the processors apply transactions, in one place. it is carried in
+// State::config, so it reaches every processor along with the state, and the defaults give
+// the strict behavior described in the README. a setting missing from a saved state, e.g.
+// one added since it was saved, reads as its default
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EngineConfig {
+    // when set, deposits to locked clients accrue in escrow instead of being rejected
+    pub escrow_locked_deposits: bool,
+
+    // when set, deposits to a locked client are credited as usual, and only money leaving it
+    // is blocked. this takes precedence over escrow_locked_deposits
+    pub deposits_allowed_when_locked: bool,
+
+    // freeze/thaw are rejected unless the input is trusted to contain them
+    pub allow_admin_transactions: bool,
+
+    // decimal places amounts are rounded to as they are processed, at most MAX_PRECISION
+    pub precision: u32,
+
+    pub excess_decimals: ExcessDecimals,
+
+    // when set, a dispute carrying an amount must match the amount of the transaction it references
+    pub check_dispute_amount: bool,
+
+    pub missing_client: MissingClientPolicy,
+
+    // when set, transactions for any other client are skipped before they reach the processors
+    pub only_clients: Option<HashSet<u16>>,
+
+    // when set, each client may have at most this many transactions applied in a run
+    pub max_tx_per_client: Option<usize>,
+
+    // when set, a dispute for a transaction not seen yet is held until that transaction arrives
+    pub defer_early_disputes: bool,
+
+    // when set, a transaction is dropped from transfers once it is charged back, since it
+    // can't be disputed again, so transfers only holds what is still disputable
+    pub streaming: bool,
+
+    // when set, a transaction timestamped earlier than one read before it is warned about.
+    // it is still applied in the order it was read
+    pub check_timestamp_order: bool,
+
+    // when set, a deposit or transfer that would create a client past this many is rejected,
+    // and the run fails with ProcessError::TooManyClients, as so many usually means corrupt input
+    pub max_clients: Option<usize>,
+
+    // when set, an input in which more than this fraction of the deposits, withdrawals and
+    // transfers repeat an identical earlier transaction fails with
+    // ProcessError::LikelyDuplicateInput. every id colliding usually means the same input was
+    // fed in twice, which would otherwise leave the balances looking right
+    pub max_duplicate_fraction: Option<f64>,
+
+    // when set, a withdrawal of the same amount as one of the client's last this many applied
+    // withdrawals is warned about, whatever its id, as it may be the same request sent twice
+    pub duplicate_withdrawal_window: Option<usize>,
+
+    // when set along with duplicate_withdrawal_window, such a withdrawal is also rejected
+    pub reject_duplicate_withdrawals: bool,
+
+    // when set, a withdrawal that would leave a client's available funds below this is
+    // rejected, for accounts that must keep a minimum balance
+    pub min_balance: Option<Amount>,
+
+    // how many chargebacks a client takes before it is locked. the default of 1 locks it on
+    // the first, and a policy that tolerates the odd chargeback sets it higher
+    pub chargebacks_before_lock: u32,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            escrow_locked_deposits: false,
+            deposits_allowed_when_locked: false,
+            allow_admin_transactions: false,
+            precision: DEFAULT_PRECISION,
+            excess_decimals: ExcessDecimals::Round,
+            check_dispute_amount: false,
+            missing_client: MissingClientPolicy::Reject,
+            only_clients: None,
+            max_tx_per_client: None,
+            defer_early_disputes: false,
+            streaming: false,
+            check_timestamp_order: false,
+            max_clients: None,
+            max_duplicate_fraction: None,
+            duplicate_withdrawal_window: None,
+            reject_duplicate_withdrawals: false,
+            min_balance: None,
+            chargebacks_before_lock: 1,
+        }
+    }
+}
+
+// what happens to an amount with more decimal places than EngineConfig::precision
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ExcessDecimals {
+    // to the nearest amount, halves away from zero
+    #[default]
+    Round,
+    // to the nearest amount, halves to the even last digit
+    RoundHalfEven,
+    // reject the transaction
+    Reject,
+}
+
+impl FromStr for ExcessDecimals {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round" => Ok(ExcessDecimals::Round),
+            "round-half-even" => Ok(ExcessDecimals::RoundHalfEven),
+            "reject" => Ok(ExcessDecimals::Reject),
+            _ => Err(format!("Unsupported excess decimals policy '{}'", s)),
+        }
+    }
+}
+
+// the most decimal places the ledger can track, and the number it tracks unless
+// EngineConfig::precision says otherwise
+pub const MAX_PRECISION: u32 = 8;
+pub const DEFAULT_PRECISION: u32 = 4;
+
+// how many of the most recent rejections State keeps by default
+pub const RECENT_REJECTS_CAPACITY: usize = 100;
+
+// every client's total as the floating point balances this tool used to keep would have it,
+// changed by the same amounts as the exact one, to show how far those balances drift on a
+// given input
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShadowLedger {
+    totals: HashMap<u16, f64>,
+}
+
+impl ShadowLedger {
+    pub fn adjust(&mut self, client_id: u16, amount: Amount) {
+        *self.totals.entry(client_id).or_insert(0.0) += amount.to_f64();
+    }
+
+    pub fn total(&self, client_id: u16) -> f64 {
+        self.totals.get(&client_id).copied().unwrap_or(0.0)
+    }
+
+    // (client id, shadow total) for every client whose shadow total, rounded to the ledger
+    // precision as the float balances were written, isn't its exact total, by client id
+    pub fn drift(&self, clients: &HashMap<u16, Client>, precision: u32) -> Vec<(u16, f64)> {
+        let mut drifted: Vec<(u16, f64)> = clients
+            .values()
+            .map(|client| (client.id, self.total(client.id)))
+            .filter(|&(id, shadow)| {
+                Amount::from_f64(shadow).map(|shadow| shadow.round_to(precision))
+                    != Ok(clients[&id].total)
+            })
+            .collect();
+        drifted.sort_by_key(|&(id, _)| id);
+
+        drifted
+    }
+
+    fn merge(&mut self, other: ShadowLedger) {
+        for (client_id, total) in other.totals {
+            *self.totals.entry(client_id).or_insert(0.0) += total;
+        }
+    }
+}
+
+// a bound on how far each client's total may be from what its input amounts add up to.
+// balances are exact, so the only error comes from amounts rounded to the ledger precision
+// on the way in, each off by at most half a unit in the last place the ledger keeps
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ErrorBudget {
+    rounded: HashMap<u16, u64>,
+}
+
+impl ErrorBudget {
+    pub fn count_rounded(&mut self, client_id: u16) {
+        *self.rounded.entry(client_id).or_insert(0) += 1;
+    }
+
+    // how many rounded amounts changed the client's total
+    pub fn rounded(&self, client_id: u16) -> u64 {
+        self.rounded.get(&client_id).copied().unwrap_or(0)
+    }
+
+    pub fn budget(&self, client_id: u16, precision: u32) -> f64 {
+        self.rounded(client_id) as f64 * 0.5 * 10f64.powi(-(precision as i32))
+    }
+
+    fn merge(&mut self, other: ErrorBudget) {
+        for (client_id, rounded) in other.rounded {
+            *self.rounded.entry(client_id).or_insert(0) += rounded;
+        }
+    }
+}
+
+// the totals of the clients a transaction could change, taken just before it is processed
+// for the shadow ledger and error budget, and whether its amount was rounded
+pub struct TotalsBefore {
+    totals: Vec<(u16, Amount)>,
+    rounded: bool,
+}
+
+// what State::rollback_last needs to undo one applied transaction, taken just before it was
+// applied: the prior record of every client it could touch, None for one it created, and
+// the prior state of everything else kept under its tx id
+#[derive(Debug, Clone)]
+pub struct UndoEntry {
+    pub tx_id: u32,
+    clients: Vec<(u16, Option<Client>)>,
+    transfer: Option<Transaction>,
+    retired: bool,
+    deferred_dispute: Option<(u16, Transaction)>,
+    accepted: Vec<(u16, Option<usize>)>,
+    withdrawals: Vec<(u16, Option<VecDeque<Amount>>)>,
+    chargebacks: Vec<(u16, Option<u32>)>,
+    admin_log_len: usize,
+}
+
+// serializable so a long-running process can checkpoint it with save and resume with load.
+// the applied transactions are kept in a HashMap unless it is built over another Ledger with
+// with_ledger. only the processing is generic over the ledger, so merging, saving and writing
+// the ledger out need the HashMap. fields added since snapshots were first written have
+// defaults, so older snapshots still load
+#[derive(Serialize, Deserialize, Debug)]
+pub struct State<L = InMemoryLedger> {
+    // the layout save writes, SNAPSHOT_VERSION
+    version: u64,
+
+    pub transfers: L,
+
+    #[serde(
+        serialize_with = "serialize_clients",
+        deserialize_with = "deserialize_clients"
+    )]
+    pub clients: HashMap<u16, Client>,
+
+    // bounded log of (tx id, reason) for the latest skipped transactions, oldest first
+    recent_rejects: VecDeque<(u32, RejectReason)>,
+    recent_rejects_capacity: usize,
+
+    // count of every transaction rejected so far, including those no longer in recent_rejects
+    pub rejected: usize,
+
+    // messages for rows that point at bad input or a corrupted state rather than breaking an
+    // ordinary rule, e.g. reused ids, amounts past the precision or a transaction whose
+    // client is gone, in input order
+    warned: Vec<String>,
+
+    pub config: EngineConfig,
+
+    // applied transactions per client, only counted while config.max_tx_per_client is set
+    accepted_per_client: HashMap<u16, usize>,
+
+    // the amounts of each client's latest applied withdrawals, oldest first, only kept while
+    // config.duplicate_withdrawal_window is set
+    #[serde(default)]
+    recent_withdrawals: HashMap<u16, VecDeque<Amount>>,
+
+    // applied chargebacks per client, counted towards config.chargebacks_before_lock
+    #[serde(default)]
+    chargebacks_per_client: HashMap<u16, u32>,
+
+    // early disputes by the tx id they reference, with the client each one acts for
+    deferred_disputes: HashMap<u32, (u16, Transaction)>,
+
+    // when set, every client id the processors saw, including ones that were only rejected
+    pub seen_clients: Option<HashSet<u16>>,
+
+    // when set, the position each client id was first seen at by the processors, counting
+    // from 0, so the clients can be listed in that order rather than by id
+    pub first_seen: Option<HashMap<u16, usize>>,
+
+    // every applied freeze and thaw, in order, for audit
+    pub admin_log: Vec<Transaction>,
+
+    // when set, what happened to every input row, in order. it describes a single run, so
+    // it isn't saved with the state
+    #[serde(skip)]
+    pub audit_log: Option<Vec<AuditEvent>>,
+
+    // when set, how to undo every transaction applied since, oldest first, for
+    // rollback_last. like the audit log it isn't saved with the state
+    #[serde(skip)]
+    pub undo_log: Option<Vec<UndoEntry>>,
+
+    // when set, every client's total is mirrored in floating point, to report how far that
+    // would drift. like the audit log it describes a single run
+    #[serde(skip)]
+    pub shadow_ledger: Option<ShadowLedger>,
+
+    // when set, the rounded amounts that changed each client's total are counted, to bound
+    // the error they leave. like the audit log it describes a single run
+    #[serde(skip)]
+    pub error_budget: Option<ErrorBudget>,
+
+    // the outcome of the row being processed, if it wasn't simply applied
+    #[serde(skip)]
+    row_outcome: Option<AuditOutcome>,
+
+    // the outcome of the last row processed, once record_audit has closed it
+    #[serde(skip)]
+    last_outcome: Option<AuditOutcome>,
+
+    // rows processed by outcome. like the audit log it describes a single run
+    #[serde(skip)]
+    pub stats: Stats,
+
+    // ids of the transactions dropped in streaming mode, kept so they still count as duplicates
+    retired_ids: HashSet<u32>,
+
+    // the latest timestamp read so far, for config.check_timestamp_order
+    latest_timestamp: Option<u64>,
+
+    // set once a transaction was rejected for creating a client past config.max_clients
+    #[serde(default)]
+    client_limit_exceeded: bool,
+
+    // ids of the locked clients, so they can be listed without scanning every client. it
+    // follows set_locked, and is rebuilt from the clients on load
+    #[serde(skip)]
+    locked_ids: HashSet<u16>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::with_recent_rejects_capacity(RECENT_REJECTS_CAPACITY)
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        let mut state = Self::new();
+        state.config = config;
+        state
+    }
+
+    pub fn with_recent_rejects_capacity(capacity: usize) -> Self {
+        Self::with_ledger_and_capacity(HashMap::new(), capacity)
+    }
+
+    // a state with no clients or transactions that processes with the same options
+    pub fn empty_like(&self) -> Self {
+        let mut state = Self::with_recent_rejects_capacity(self.recent_rejects_capacity);
+        state.config = self.config.clone();
+        state.seen_clients = self.seen_clients.as_ref().map(|_| HashSet::new());
+        state.first_seen = self.first_seen.as_ref().map(|_| HashMap::new());
+        state.audit_log = self.audit_log.as_ref().map(|_| Vec::new());
+        state.undo_log = self.undo_log.as_ref().map(|_| Vec::new());
+        state.shadow_ledger = self.shadow_ledger.as_ref().map(|_| ShadowLedger::default());
+        state.error_budget = self.error_budget.as_ref().map(|_| ErrorBudget::default());
+        state
+    }
+
+    // folds in a state built from other clients' transactions. the two must not share a
+    // client or a transaction id. logs are appended after this state's own entries
+    pub fn merge(&mut self, other: State) {
+        self.transfers.extend(other.transfers);
+        self.clients.extend(other.clients);
+        self.rejected += other.rejected;
+        self.warned.extend(other.warned);
+        self.accepted_per_client.extend(other.accepted_per_client);
+        self.recent_withdrawals.extend(other.recent_withdrawals);
+        self.chargebacks_per_client
+            .extend(other.chargebacks_per_client);
+        self.deferred_disputes.extend(other.deferred_disputes);
+        self.admin_log.extend(other.admin_log);
+        self.retired_ids.extend(other.retired_ids);
+        self.stats.merge(&other.stats);
+        self.locked_ids.extend(other.locked_ids);
+        self.latest_timestamp = self.latest_timestamp.max(other.latest_timestamp);
+        self.client_limit_exceeded |= other.client_limit_exceeded;
+
+        if let (Some(seen), Some(other_seen)) = (&mut self.seen_clients, other.seen_clients) {
+            seen.extend(other_seen);
+        }
+
+        // the other state's clients are taken as seen after all of this state's
+        if let (Some(first_seen), Some(other_first_seen)) = (&mut self.first_seen, other.first_seen)
+        {
+            let mut ids: Vec<(u16, usize)> = other_first_seen.into_iter().collect();
+            ids.sort_unstable_by_key(|&(_, position)| position);
+
+            for (id, _) in ids {
+                let next = first_seen.len();
+                first_seen.entry(id).or_insert(next);
+            }
+        }
+
+        if let (Some(audit_log), Some(other_log)) = (&mut self.audit_log, other.audit_log) {
+            audit_log.extend(other_log);
+        }
+
+        if let (Some(undo_log), Some(other_log)) = (&mut self.undo_log, other.undo_log) {
+            undo_log.extend(other_log);
+        }
+
+        if let (Some(shadow), Some(other_shadow)) = (&mut self.shadow_ledger, other.shadow_ledger) {
+            shadow.merge(other_shadow);
+        }
+
+        if let (Some(budget), Some(other_budget)) = (&mut self.error_budget, other.error_budget) {
+            budget.merge(other_budget);
+        }
+
+        for (tx_id, reason) in other.recent_rejects {
+            self.push_recent_reject(tx_id, reason);
+        }
+    }
+
+    // writes the whole state as json, so a later run can pick up where this one stopped.
+    // amounts are written as exact decimal strings, so balances load back unchanged however
+    // many digits they have. transaction metadata isn't kept, as it is never used in balance
+    // math
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()
+    }
+
+    // writes every retained deposit, withdrawal and transfer as csv, by tx id, with the
+    // columns type, client, tx, amount, status, timestamp and dest. the status is where each
+    // ended up in the dispute process, and the file reads back as transaction input
+    pub fn write_ledger<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+
+        let mut ids: Vec<&u32> = self.transfers.keys().collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            writer.serialize(&self.transfers[id])?;
+        }
+
+        writer.flush()
+    }
+
+    // reads a state written by save, by this build or an older one. the audit log, if
+    // wanted, has to be enabled again
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut snapshot: serde_json::Value = serde_json::from_reader(reader)?;
+
+        match snapshot.get("version").map(serde_json::Value::as_u64) {
+            None => migrate_unversioned_snapshot(&mut snapshot),
+            Some(Some(version)) if version <= SNAPSHOT_VERSION => {}
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the snapshot was saved by a newer version of the tool",
+                ))
+            }
+        }
+
+        amounts_as_text(&mut snapshot);
+
+        let mut state: Self = serde_json::from_value(snapshot)?;
+        state.version = SNAPSHOT_VERSION;
+        state.index_locked_clients();
+
+        Ok(state)
+    }
+}
+
+impl<L: Ledger> State<L> {
+    // a new state, with the default config, that keeps its transactions in `ledger` rather
+    // than in a HashMap. `ledger` should start out empty, as there are no clients yet
+    pub fn with_ledger(ledger: L) -> Self {
+        Self::with_ledger_and_capacity(ledger, RECENT_REJECTS_CAPACITY)
+    }
+
+    fn with_ledger_and_capacity(ledger: L, capacity: usize) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            transfers: ledger,
+            clients: HashMap::new(),
+            recent_rejects: VecDeque::with_capacity(capacity),
+            recent_rejects_capacity: capacity,
+            rejected: 0,
+            warned: Vec::new(),
+            config: EngineConfig::default(),
+            accepted_per_client: HashMap::new(),
+            recent_withdrawals: HashMap::new(),
+            chargebacks_per_client: HashMap::new(),
+            deferred_disputes: HashMap::new(),
+            seen_clients: None,
+            first_seen: None,
+            admin_log: Vec::new(),
+            audit_log: None,
+            undo_log: None,
+            shadow_ledger: None,
+            error_budget: None,
+            row_outcome: None,
+            last_outcome: None,
+            stats: Stats::default(),
+            retired_ids: HashSet::new(),
+            latest_timestamp: None,
+            client_limit_exceeded: false,
+            locked_ids: HashSet::new(),
+        }
+    }
+
+    // locks or unlocks a client, keeping locked_clients in step. does nothing for a client
+    // that doesn't exist
+    pub fn set_locked(&mut self, client_id: u16, locked: bool) {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.locked = locked;
+
+            if locked {
+                self.locked_ids.insert(client_id);
+            } else {
+                self.locked_ids.remove(&client_id);
+            }
+        }
+    }
+
+    /// Totals every client's balances, and counts the clients and how many are locked.
+    pub fn aggregate(&self) -> Aggregate {
+        let mut aggregate = Aggregate::default();
+
+        for client in self.clients.values() {
+            aggregate.total += client.total;
+            aggregate.available += client.available;
+            aggregate.held += client.held;
+            aggregate.client_count += 1;
+            if client.locked {
+                aggregate.locked_count += 1;
+            }
+        }
+
+        aggregate
+    }
+
+    // checks that every client's available and held add up to its total, and that held isn't
+    // negative. amounts are exact, so the sum has to match to the last digit. violations are
+    // listed by client id
+    pub fn verify_invariants(&self) -> Result<(), Vec<InvariantViolation>> {
+        let mut clients: Vec<&Client> = self.clients.values().collect();
+        clients.sort_by_key(|client| client.id);
+
+        let mut violations = Vec::new();
+        for client in clients {
+            if client.available + client.held != client.total {
+                violations.push(InvariantViolation::TotalMismatch {
+                    client_id: client.id,
+                    available: client.available,
+                    held: client.held,
+                    total: client.total,
+                });
+            }
+
+            if client.held < Amount::ZERO {
+                violations.push(InvariantViolation::NegativeHeld {
+                    client_id: client.id,
+                    held: client.held,
+                });
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    // the clients whose balances or counters differ from those in `other`, by client id. a
+    // client that is only in one of the states is compared against a new client, so one
+    // created but left with nothing still matches
+    pub fn diff(&self, other: &Self) -> Vec<ClientDiff> {
+        let mut ids: Vec<u16> = self
+            .clients
+            .keys()
+            .chain(other.clients.keys())
+            .copied()
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter()
+            .filter_map(|id| {
+                let field_values = |state: &Self| match state.clients.get(&id) {
+                    Some(client) => client.field_values(),
+                    None => Client::new(id).field_values(),
+                };
+
+                let fields: Vec<FieldDiff> = field_values(self)
+                    .into_iter()
+                    .zip(field_values(other))
+                    .filter(|((_, ours), (_, theirs))| ours != theirs)
+                    .map(|((field, ours), (_, theirs))| FieldDiff {
+                        field,
+                        ours,
+                        theirs,
+                    })
+                    .collect();
+
+                (!fields.is_empty()).then_some(ClientDiff {
+                    client_id: id,
+                    fields,
+                })
+            })
+            .collect()
+    }
+
+    // the ids of the locked clients, in no particular order
+    pub fn locked_clients(&self) -> impl Iterator<Item = u16> + '_ {
+        self.locked_ids.iter().copied()
+    }
+
+    // rebuilds locked_clients from the clients, for when they were changed directly rather
+    // than through set_locked
+    pub fn index_locked_clients(&mut self) {
+        self.locked_ids = self
+            .clients
+            .values()
+            .filter(|client| client.locked)
+            .map(|client| client.id)
+            .collect();
+    }
+
+    // rounds a transaction's amount, with any excess it was read with, to the ledger precision,
+    // warning if that changed it, or fails if the amount needs rounding and excess_decimals
+    // says to reject it
+    pub fn round_to_precision(
+        &mut self,
+        transaction: &mut Transaction,
+    ) -> Result<(), RejectReason> {
+        if transaction.fits_precision(self.config.precision) {
+            return Ok(());
+        }
+
+        if self.config.excess_decimals == ExcessDecimals::Reject {
+            return Err(RejectReason::TooManyDecimals);
+        }
+
+        self.warned.push(format!(
+            "rounded the amount of transaction {} to {} decimal places",
+            transaction.id, self.config.precision
+        ));
+        transaction.amount = transaction.amount.round_excess_to(
+            transaction.amount_excess,
+            self.config.precision,
+            self.config.excess_decimals == ExcessDecimals::RoundHalfEven,
+        );
+        transaction.amount_excess = Excess::default();
+
+        Ok(())
+    }
+
+    // the by-value form of record_reject, for the processors that pass State through
+    pub fn reject(mut self, tx_id: u32, reason: RejectReason) -> Self {
+        self.record_reject(tx_id, reason);
+        self
+    }
+
+    // rejects a dispute, resolve or chargeback from a client other than the owner of the
+    // transaction it references. that points at spoofed or buggy input, so it is warned about
+    // with both clients
+    pub fn reject_client_mismatch(
+        mut self,
+        tx_id: u32,
+        owner: Option<u16>,
+        client_id: u16,
+    ) -> Self {
+        self.record_reject(tx_id, RejectReason::ClientMismatch);
+
+        let owner = owner.map_or_else(|| String::from("no client"), |id| format!("client {}", id));
+        self.warned.push(format!(
+            "skipped transaction {}: referenced by client {}, but it belongs to {}",
+            tx_id, client_id, owner
+        ));
+
+        self
+    }
+
+    // records a skipped transaction, dropping the oldest entry once the log is full
+    pub fn record_reject(&mut self, tx_id: u32, reason: RejectReason) {
+        self.rejected += 1;
+        self.set_row_outcome(AuditOutcome::Rejected(reason));
+
+        if matches!(
+            reason,
+            RejectReason::DuplicateTransaction
+                | RejectReason::HeldUnderflow
+                | RejectReason::UnexpectedAmount
+                | RejectReason::MissingOwner
+                | RejectReason::TooManyDecimals
+                | RejectReason::ConflictingTransaction
+        ) {
+            self.warned
+                .push(format!("skipped transaction {}: {}", tx_id, reason));
+        }
+
+        self.push_recent_reject(tx_id, reason);
+    }
+
+    fn push_recent_reject(&mut self, tx_id: u32, reason: RejectReason) {
+        if self.recent_rejects_capacity == 0 {
+            return;
+        }
+
+        if self.recent_rejects.len() == self.recent_rejects_capacity {
+            self.recent_rejects.pop_front();
+        }
+
+        self.recent_rejects.push_back((tx_id, reason));
+    }
+
+    pub fn set_row_outcome(&mut self, outcome: AuditOutcome) {
+        self.row_outcome = Some(outcome);
+    }
+
+    // logs the row just processed with its outcome, applied unless set_row_outcome said otherwise
+    pub fn record_audit(
+        &mut self,
+        id: u32,
+        client_id: Option<u16>,
+        transaction_type: TransactionType,
+    ) {
+        let outcome = self.row_outcome.take().unwrap_or(AuditOutcome::Applied);
+        self.last_outcome = Some(outcome);
+        self.stats.record(transaction_type, outcome);
+
+        if let Some(audit_log) = &mut self.audit_log {
+            audit_log.push(AuditEvent {
+                id,
+                client_id,
+                transaction_type,
+                outcome,
+            });
+        }
+    }
+
+    // every client `transaction` could change: its own, its destination's, and the owner of
+    // the transaction it references
+    fn affected_clients(&self, transaction: &Transaction) -> Vec<u16> {
+        let owner = self
+            .transfers
+            .get(&transaction.id)
+            .and_then(|target| target.client_id);
+
+        let mut client_ids = vec![];
+        for client_id in [transaction.client_id, transaction.dest_client_id, owner]
+            .into_iter()
+            .flatten()
+        {
+            if !client_ids.contains(&client_id) {
+                client_ids.push(client_id);
+            }
+        }
+
+        client_ids
+    }
+
+    // what undoing `transaction` will need, taken before it is processed
+    pub fn undo_entry(&self, transaction: &Transaction) -> UndoEntry {
+        let client_ids = self.affected_clients(transaction);
+
+        UndoEntry {
+            tx_id: transaction.id,
+            clients: client_ids
+                .iter()
+                .map(|id| (*id, self.clients.get(id).cloned()))
+                .collect(),
+            transfer: self.transfers.get(&transaction.id).cloned(),
+            retired: self.is_retired(transaction.id),
+            deferred_dispute: self.deferred_disputes.get(&transaction.id).cloned(),
+            accepted: client_ids
+                .iter()
+                .map(|id| (*id, self.accepted_per_client.get(id).copied()))
+                .collect(),
+            withdrawals: client_ids
+                .iter()
+                .map(|id| (*id, self.recent_withdrawals.get(id).cloned()))
+                .collect(),
+            chargebacks: client_ids
+                .iter()
+                .map(|id| (*id, self.chargebacks_per_client.get(id).copied()))
+                .collect(),
+            admin_log_len: self.admin_log.len(),
+        }
+    }
+
+    // keeps `entry` in the undo log if the row just audited changed the state, i.e. it was
+    // applied or deferred
+    pub fn record_undo(&mut self, entry: UndoEntry) {
+        if let Some(undo_log) = &mut self.undo_log {
+            if matches!(
+                self.last_outcome,
+                Some(AuditOutcome::Applied | AuditOutcome::Deferred)
+            ) {
+                undo_log.push(entry);
+            }
+        }
+    }
+
+    // what record_total_changes needs, taken before `transaction` is processed, or None if
+    // neither the shadow ledger nor the error budget is kept
+    pub fn totals_before(&self, transaction: &Transaction) -> Option<TotalsBefore> {
+        if self.shadow_ledger.is_none() && self.error_budget.is_none() {
+            return None;
+        }
+
+        let total = |id| {
+            self.clients
+                .get(&id)
+                .map_or(Amount::ZERO, |client| client.total)
+        };
+        Some(TotalsBefore {
+            totals: self
+                .affected_clients(transaction)
+                .into_iter()
+                .map(|id| (id, total(id)))
+                .collect(),
+            rounded: !transaction.fits_precision(self.config.precision),
+        })
+    }
+
+    // mirrors how the row just processed changed each client's total in the shadow ledger,
+    // and counts it in the error budget if its amount was rounded
+    pub fn record_total_changes(&mut self, before: TotalsBefore) {
+        for (client_id, total) in before.totals {
+            let change = self
+                .clients
+                .get(&client_id)
+                .map_or(Amount::ZERO, |client| client.total)
+                - total;
+            if change == Amount::ZERO {
+                continue;
+            }
+
+            if let Some(shadow_ledger) = &mut self.shadow_ledger {
+                shadow_ledger.adjust(client_id, change);
+            }
+            if let (Some(error_budget), true) = (&mut self.error_budget, before.rounded) {
+                error_budget.count_rounded(client_id);
+            }
+        }
+    }
+
+    // undoes the last `n` transactions applied while the undo log was enabled, newest first,
+    // and returns how many were undone, fewer if the log ran out. balances, disputes, locks
+    // and ids go back to how they were. rejected rows, warnings, stats and the audit log
+    // describe what was read, so they are left as they are
+    pub fn rollback_last(&mut self, n: usize) -> usize {
+        let undo_log = match &mut self.undo_log {
+            Some(undo_log) => undo_log,
+            None => return 0,
+        };
+
+        let undone = undo_log.split_off(undo_log.len() - n.min(undo_log.len()));
+        let count = undone.len();
+
+        for entry in undone.into_iter().rev() {
+            self.undo(entry);
+        }
+
+        count
+    }
+
+    fn undo(&mut self, entry: UndoEntry) {
+        for (client_id, client) in entry.clients {
+            // the shadow ledger is moved back by the same amount as the total
+            if let Some(shadow_ledger) = &mut self.shadow_ledger {
+                let total = |client: Option<&Client>| client.map_or(Amount::ZERO, |c| c.total);
+                let change = total(client.as_ref()) - total(self.clients.get(&client_id));
+                shadow_ledger.adjust(client_id, change);
+            }
+
+            match client {
+                Some(client) => {
+                    let locked = client.locked;
+                    self.clients.insert(client_id, client);
+                    self.set_locked(client_id, locked);
+                }
+                None => {
+                    self.clients.remove(&client_id);
+                    self.locked_ids.remove(&client_id);
+                }
+            }
+        }
+
+        match entry.transfer {
+            Some(transaction) => self.transfers.insert(entry.tx_id, transaction),
+            None => self.transfers.remove(&entry.tx_id),
+        };
+
+        if !entry.retired {
+            self.retired_ids.remove(&entry.tx_id);
+        }
+
+        match entry.deferred_dispute {
+            Some(dispute) => self.deferred_disputes.insert(entry.tx_id, dispute),
+            None => self.deferred_disputes.remove(&entry.tx_id),
+        };
+
+        for (client_id, accepted) in entry.accepted {
+            match accepted {
+                Some(accepted) => self.accepted_per_client.insert(client_id, accepted),
+                None => self.accepted_per_client.remove(&client_id),
+            };
+        }
+
+        for (client_id, withdrawals) in entry.withdrawals {
+            match withdrawals {
+                Some(withdrawals) => self.recent_withdrawals.insert(client_id, withdrawals),
+                None => self.recent_withdrawals.remove(&client_id),
+            };
+        }
+
+        for (client_id, chargebacks) in entry.chargebacks {
+            match chargebacks {
+                Some(chargebacks) => self.chargebacks_per_client.insert(client_id, chargebacks),
+                None => self.chargebacks_per_client.remove(&client_id),
+            };
+        }
+
+        self.admin_log.truncate(entry.admin_log_len);
+    }
+
+    // applies one transaction in place, as processor::process_transaction does, and says
+    // what became of it
+    pub fn apply(&mut self, transaction: Transaction) -> ApplyOutcome
+    where
+        L: Default,
+    {
+        // the client a dispute without one acts for, if the policy lets it, is looked up
+        // before a chargeback in streaming mode can drop the transaction
+        let client_id = transaction.client_id.or_else(|| {
+            self.transfers
+                .get(&transaction.id)
+                .and_then(|target| target.client_id)
+        });
+
+        // a state with no capacity for recent rejects doesn't allocate
+        let state = mem::replace(self, Self::with_ledger_and_capacity(L::default(), 0));
+        *self = processor::process_transaction(state, transaction);
+
+        match self.last_outcome.take() {
+            Some(AuditOutcome::Rejected(reason)) => ApplyOutcome::Rejected(reason),
+            Some(AuditOutcome::Deferred) => ApplyOutcome::Deferred,
+            Some(AuditOutcome::Filtered) => ApplyOutcome::Filtered,
+            // a transaction without a client is always rejected, and an applied one leaves
+            // the client it acted for in the state
+            Some(AuditOutcome::Applied) | None => {
+                ApplyOutcome::Applied(self.clients[&client_id.unwrap()].clone())
+            }
+        }
+    }
+
+    // warns about a transaction timestamped before the latest one read so far, when
+    // config.check_timestamp_order is set. transactions without a timestamp are passed over
+    pub fn check_timestamp_order(&mut self, transaction: &Transaction) {
+        if !self.config.check_timestamp_order {
+            return;
+        }
+
+        match (transaction.timestamp, self.latest_timestamp) {
+            (Some(timestamp), Some(latest)) if timestamp < latest => {
+                self.warned.push(format!(
+                    "transaction {} is timestamped {} seconds before a transaction read ahead of it",
+                    transaction.id,
+                    latest - timestamp
+                ));
+            }
+            (Some(timestamp), _) => self.latest_timestamp = Some(timestamp),
+            (None, _) => {}
+        }
+    }
+
+    // whether `client_id` would be a new client past config.max_clients. if so, the
+    // transaction creating it is rejected, and check_client_limit fails from then on
+    pub fn exceeds_client_limit(&mut self, client_id: u16) -> bool {
+        let exceeded = match self.config.max_clients {
+            Some(max) => self.clients.len() >= max && !self.clients.contains_key(&client_id),
+            None => false,
+        };
+
+        self.client_limit_exceeded |= exceeded;
+        exceeded
+    }
+
+    // fails once a transaction was rejected for creating a client past config.max_clients,
+    // for callers that stop the run there
+    pub fn check_client_limit(&self) -> Result<(), ProcessError> {
+        match self.config.max_clients {
+            Some(max) if self.client_limit_exceeded => Err(ProcessError::TooManyClients { max }),
+            _ => Ok(()),
+        }
+    }
+
+    // fails if more of the deposits, withdrawals and transfers processed since `before` was
+    // taken from stats than config.max_duplicate_fraction allows were duplicates. those rejected
+    // for any other reason don't count either way
+    pub fn check_duplicate_input(&self, before: &Stats) -> Result<(), ProcessError> {
+        let Some(max_fraction) = self.config.max_duplicate_fraction else {
+            return Ok(());
+        };
+
+        let (stats, before) = (&self.stats, before);
+        let duplicates = stats.duplicates - before.duplicates;
+        let applied = (stats.deposits + stats.withdrawals + stats.transfers)
+            - (before.deposits + before.withdrawals + before.transfers);
+        let rows = applied + duplicates;
+
+        if rows > 0 && duplicates as f64 > max_fraction * rows as f64 {
+            return Err(ProcessError::LikelyDuplicateInput { duplicates, rows });
+        }
+
+        Ok(())
+    }
+
+    pub fn accepts_client(&self, client_id: u16) -> bool {
+        match &self.config.only_clients {
+            Some(clients) => clients.contains(&client_id),
+            None => true,
+        }
+    }
+
+    pub fn record_seen(&mut self, client_id: u16) {
+        if let Some(seen_clients) = &mut self.seen_clients {
+            seen_clients.insert(client_id);
+        }
+
+        if let Some(first_seen) = &mut self.first_seen {
+            let next = first_seen.len();
+            first_seen.entry(client_id).or_insert(next);
+        }
+    }
+
+    // the ids of the clients in the order they were first seen, if first_seen is set, and
+    // otherwise by id. a client never seen in a transaction, e.g. one only given an overdraft
+    // limit, comes after those that were, by id
+    pub fn client_ids_in_first_seen_order(&self) -> Vec<u16> {
+        let mut ids: Vec<u16> = self.clients.keys().copied().collect();
+
+        match &self.first_seen {
+            Some(first_seen) => ids.sort_unstable_by_key(|id| {
+                (first_seen.get(id).copied().unwrap_or(usize::MAX), *id)
+            }),
+            None => ids.sort_unstable(),
+        }
+
+        ids
+    }
+
+    // whether a deposit or withdrawal with this id was already applied, retired or not
+    pub fn is_known_transaction(&self, tx_id: u32) -> bool {
+        self.transfers.contains_key(&tx_id) || self.is_retired(tx_id)
+    }
+
+    // why a transaction reusing a known id is rejected. an exact repeat, e.g. from a file
+    // passed twice, is a plain duplicate, but one with a different type, client, amount or
+    // destination is a conflicting redefinition of the id. a retired transaction can't be
+    // compared, so reusing its id always reads as a duplicate
+    pub fn reused_id_reason(&self, transaction: &Transaction) -> RejectReason {
+        match self.transfers.get(&transaction.id) {
+            Some(stored)
+                if (
+                    stored.transaction_type,
+                    stored.client_id,
+                    stored.amount,
+                    stored.dest_client_id,
+                ) != (
+                    transaction.transaction_type,
+                    transaction.client_id,
+                    transaction.amount,
+                    transaction.dest_client_id,
+                ) =>
+            {
+                RejectReason::ConflictingTransaction
+            }
+            _ => RejectReason::DuplicateTransaction,
+        }
+    }
+
+    pub fn is_retired(&self, tx_id: u32) -> bool {
+        !self.retired_ids.is_empty() && self.retired_ids.contains(&tx_id)
+    }
+
+    // drops a charged back transaction in streaming mode, keeping only its id
+    pub fn retire_transaction(&mut self, tx_id: u32) {
+        if self.config.streaming && self.transfers.remove(&tx_id).is_some() {
+            self.retired_ids.insert(tx_id);
+        }
+    }
+
+    // creates the client if it hasn't been seen yet, so the limit is in place before its
+    // first transaction
+    pub fn set_overdraft_limit(&mut self, client_id: u16, limit: Amount) {
+        self.clients
+            .entry(client_id)
+            .or_insert_with(|| Client::new(client_id))
+            .overdraft_limit = limit;
+    }
+
+    pub fn accepted_count(&self, client_id: u16) -> usize {
+        self.accepted_per_client
+            .get(&client_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn record_accepted(&mut self, client_id: u16) {
+        *self.accepted_per_client.entry(client_id).or_insert(0) += 1;
+    }
+
+    pub fn chargeback_count(&self, client_id: u16) -> u32 {
+        self.chargebacks_per_client
+            .get(&client_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // counts an applied chargeback against the client, and says whether that brings it to
+    // config.chargebacks_before_lock, so it is to be locked
+    pub fn record_chargeback(&mut self, client_id: u16) -> bool {
+        let count = self.chargebacks_per_client.entry(client_id).or_insert(0);
+        *count += 1;
+
+        *count >= self.config.chargebacks_before_lock
+    }
+
+    // whether a withdrawal of `amount` repeats one of the client's latest applied withdrawals.
+    // always false unless config.duplicate_withdrawal_window is set
+    pub fn is_repeated_withdrawal(&self, client_id: u16, amount: Amount) -> bool {
+        self.recent_withdrawals
+            .get(&client_id)
+            .is_some_and(|amounts| amounts.contains(&amount))
+    }
+
+    // warns about an applied withdrawal that is_repeated_withdrawal flagged
+    pub fn warn_repeated_withdrawal(&mut self, client_id: u16, withdrawal: &Transaction) {
+        self.warned.push(format!(
+            "withdrawal {} by client {} repeats the amount {} of one of its last {} withdrawals",
+            withdrawal.id,
+            client_id,
+            withdrawal.amount,
+            self.config.duplicate_withdrawal_window.unwrap_or(0)
+        ));
+    }
+
+    // keeps the amount of an applied withdrawal while config.duplicate_withdrawal_window is
+    // set, dropping the client's oldest once there are more than the window
+    pub fn record_withdrawal(&mut self, client_id: u16, amount: Amount) {
+        let Some(window) = self.config.duplicate_withdrawal_window else {
+            return;
+        };
+
+        let amounts = self.recent_withdrawals.entry(client_id).or_default();
+        amounts.push_back(amount);
+        if amounts.len() > window {
+            amounts.pop_front();
+        }
+    }
+
+    // holds a dispute until the transaction it references arrives. only the first
+    // dispute for a given tx id is held, and false is returned for any later one
+    pub fn defer_dispute(&mut self, client_id: u16, dispute: Transaction) -> bool {
+        match self.deferred_disputes.entry(dispute.id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(slot) => {
+                slot.insert((client_id, dispute));
+                true
+            }
+        }
+    }
+
+    pub fn take_deferred_dispute(&mut self, tx_id: u32) -> Option<(u16, Transaction)> {
+        self.deferred_disputes.remove(&tx_id)
+    }
+
+    // the tx id and client of every dispute still waiting for its transaction, by tx id
+    pub fn drain_deferred_disputes(&mut self) -> Vec<(u32, u16)> {
+        let mut disputes: Vec<(u32, u16)> = self
+            .deferred_disputes
+            .drain()
+            .map(|(id, (client_id, _))| (id, client_id))
+            .collect();
+        disputes.sort_unstable();
+        disputes
+    }
+
+    // skips a row that couldn't be read as a transaction, e.g. one missing its tx field, with a
+    // warning. `location` says where the row was, e.g. `line 3 of input.csv`
+    pub fn skip_unreadable_row(&mut self, location: &str, reason: &str) {
+        self.stats.record_unreadable();
+        self.warned
+            .push(format!("skipped {}: {}", location, reason));
+    }
+
+    // problems with the input worth telling an operator about, which unlike recent_rejects
+    // are kept for the whole run
+    pub fn warnings(&self) -> impl Iterator<Item = String> + '_ {
+        self.warned.iter().cloned()
+    }
+
+    pub fn recent_rejects(&self) -> impl Iterator<Item = &(u32, RejectReason)> {
+        self.recent_rejects.iter()
+    }
+}
+
+// since amount can be blank for some transaction types,
+// this is a custom deserializer fn to handle the empty string case.
+// the column is read as text, as csv would otherwise hand over a float that keeps only about
+// 15 significant digits. json numbers are turned into text first, see transactions_from_json.
+// digits past MAX_PRECISION are kept as the Excess rather than rounded here, so the amount is
+// rounded once, to the ledger precision, see State::round_to_precision.
+// text that isn't a number comes back as Err for TransactionRow to judge, but NaN and
+// infinite amounts are always errors, as they would silently corrupt balances.
+// only plain decimals are read here. amounts written for people, e.g. `$1,000.50`, are turned
+// into plain ones with AmountFormat::to_plain before the row is read
+fn optional_amount_value<'de, D>(
+    deserializer: D,
+) -> Result<Result<(Amount, Excess), String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct AmountVisitor;
+
+    impl<'de> de::Visitor<'de> for AmountVisitor {
+        type Value = Result<(Amount, Excess), String>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an amount as a string")
+        }
+
+        // decimal strings are parsed exactly. anything else that reads as a float, e.g. `1e3`,
+        // is parsed as the decimal the float prints as, see Amount::from_f64
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            if s.trim().is_empty() {
+                return Ok(blank_amount());
+            }
+
+            let parsed = match Amount::parse_with_excess(s) {
+                Ok(parsed) => Ok(parsed),
+                Err(_) => match s.parse::<f64>() {
+                    Ok(f) if !f.is_finite() => Err(format!("amount {} is not a finite number", f)),
+                    Ok(f) => Amount::parse_with_excess(&f.to_string()),
+                    Err(_) => return Ok(Err(s.to_string())),
+                },
+            };
+
+            let (amount, excess) = parsed.map_err(E::custom)?;
+            let amount = amount.within_transaction_limit().map_err(E::custom)?;
+            Ok(Ok((amount, excess)))
+        }
+    }
+
+    deserializer.deserialize_str(AmountVisitor)
+}
+
+/// Reads a JSON array of transactions, with the same fields as a CSV row. An amount may be a
+/// number, a string such as `"1.5"`, or null for a blank one. Numbers are read exactly as
+/// written, however many significant digits they have.
+pub fn transactions_from_json(json: &[u8]) -> serde_json::Result<Vec<Transaction>> {
+    let mut rows: serde_json::Value = serde_json::from_slice(json)?;
+    amounts_as_text(&mut rows);
+
+    // extra columns are kept as text too, as csv gives them
+    let objects = rows.as_array_mut().into_iter().flatten();
+    for row in objects.filter_map(serde_json::Value::as_object_mut) {
+        for (name, value) in row.iter_mut() {
+            if COLUMNS.contains(&name.as_str()) {
+                continue;
+            }
+
+            match value {
+                serde_json::Value::Null => *value = serde_json::Value::String(String::new()),
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                    *value = serde_json::Value::String(value.to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    serde_json::from_value(rows)
+}
+
+// turns the amount of every transaction in a json value into the text optional_amount_value
+// reads: a number into its text as written, and null into a blank amount. Transaction is the
+// only type with an amount field
+fn amounts_as_text(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(items) => items.iter_mut().for_each(amounts_as_text),
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match field {
+                    serde_json::Value::Number(number) if name == "amount" => {
+                        *field = serde_json::Value::String(number.to_string())
+                    }
+                    serde_json::Value::Null if name == "amount" => {
+                        *field = serde_json::Value::String(String::new())
+                    }
+                    _ => amounts_as_text(field),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// the layout State::save writes. 2 is the first with a version. it carries the settings in
+// config and names the fields saved alongside each client, see SavedClient
+const SNAPSHOT_VERSION: u64 = 2;
+
+// what a client is saved as, since Client leaves these fields out of its own serialized form.
+// any of them missing reads as zero
+#[derive(Serialize, Deserialize)]
+struct SavedClient {
+    account: Client,
+    #[serde(default)]
+    overdraft_limit: Amount,
+    #[serde(default)]
+    max_deposit: Amount,
+    #[serde(default)]
+    max_withdrawal: Amount,
+}
+
+fn serialize_clients<S: Serializer>(
+    clients: &HashMap<u16, Client>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(clients.values().map(|client| SavedClient {
+        account: client.clone(),
+        overdraft_limit: client.overdraft_limit,
+        max_deposit: client.max_deposit,
+        max_withdrawal: client.max_withdrawal,
+    }))
+}
+
+fn deserialize_clients<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<u16, Client>, D::Error> {
+    let saved: Vec<SavedClient> = Vec::deserialize(deserializer)?;
+
+    Ok(saved
+        .into_iter()
+        .map(|saved| {
+            let mut client = saved.account;
+            client.overdraft_limit = saved.overdraft_limit;
+            client.max_deposit = saved.max_deposit;
+            client.max_withdrawal = saved.max_withdrawal;
+            (client.id, client)
+        })
+        .collect())
+}
+
+// brings a snapshot saved before SNAPSHOT_VERSION 2 up to it. those kept the settings as
+// fields of the state itself until EngineConfig gathered them under config, and saved each
+// client as a (client, overdraft limit) pair, or later a (client, overdraft limit, largest
+// deposit, largest withdrawal) tuple. fields added since then are left to their defaults
+fn migrate_unversioned_snapshot(snapshot: &mut serde_json::Value) {
+    let serde_json::Value::Object(fields) = snapshot else {
+        return;
+    };
+
+    if !fields.contains_key("config") {
+        let mut config = serde_json::Map::new();
+        if let Ok(serde_json::Value::Object(defaults)) =
+            serde_json::to_value(EngineConfig::default())
+        {
+            for name in defaults.keys() {
+                if let Some(value) = fields.remove(name.as_str()) {
+                    config.insert(name.clone(), value);
+                }
+            }
+        }
+        fields.insert(String::from("config"), serde_json::Value::Object(config));
+    }
+
+    if let Some(serde_json::Value::Array(clients)) = fields.get_mut("clients") {
+        for saved in clients.iter_mut() {
+            if let serde_json::Value::Array(parts) = saved {
+                let names = [
+                    "account",
+                    "overdraft_limit",
+                    "max_deposit",
+                    "max_withdrawal",
+                ];
+                let named = names
+                    .into_iter()
+                    .map(String::from)
+                    .zip(parts.drain(..))
+                    .collect();
+                *saved = serde_json::Value::Object(named);
+            }
+        }
+    }
+
+    fields.insert(
+        String::from("version"),
+        serde_json::Value::from(SNAPSHOT_VERSION),
+    );
+}
+
+// a timestamp column holds seconds since the epoch, or an ISO 8601 date and time, which is
+// read as the same
+fn optional_timestamp<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct TimestampVisitor;
+
+    impl<'de> de::Visitor<'de> for TimestampVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an optional integer or ISO 8601 timestamp")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Option<u64>, E> {
+            if s.is_empty() {
+                return Ok(None);
+            }
+
+            s.parse()
+                .ok()
+                .or_else(|| timestamp::parse_iso8601(s))
+                .map(Some)
+                .ok_or_else(|| E::custom(format!("invalid timestamp '{}'", s)))
+        }
+
+        fn visit_u64<E: de::Error>(self, u: u64) -> Result<Option<u64>, E> {
+            Ok(Some(u))
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Option<u64>, E> {
+            Ok(None)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Option<u64>, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: de::Deserializer<'de>>(self, d: D) -> Result<Option<u64>, D::Error> {
+            d.deserialize_any(self)
+        }
+    }
+
+    deserializer.deserialize_any(TimestampVisitor)
+}
+
+// csv hands every value over as its inferred type rather than as text, so an empty column
+// arrives as an empty string instead of a missing value, and an id too large or negative for
+// a u16 arrives as whichever integer type holds it. those all fail with the same message
+fn optional_client_id<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct ClientIdVisitor;
+
+    impl ClientIdVisitor {
+        fn in_range<T, E>(id: T) -> Result<Option<u16>, E>
+        where
+            T: Copy + fmt::Display + TryInto<u16>,
+            E: de::Error,
+        {
+            id.try_into().map(Some).map_err(|_| {
+                E::custom(format!(
+                    "client id {} is out of range, it must be from 0 to {}",
+                    id,
+                    u16::MAX
+                ))
+            })
+        }
+    }
+
+    impl<'de> de::Visitor<'de> for ClientIdVisitor {
+        type Value = Option<u16>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an optional client id")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Option<u16>, E> {
+            if s.is_empty() {
+                return Ok(None);
+            }
+
+            match s.parse::<i128>() {
+                Ok(id) => Self::in_range(id),
+                Err(_) => Err(E::custom(format!("invalid client id '{}'", s))),
+            }
+        }
+
+        fn visit_u64<E: de::Error>(self, u: u64) -> Result<Option<u16>, E> {
+            Self::in_range(u)
+        }
+
+        fn visit_i64<E: de::Error>(self, i: i64) -> Result<Option<u16>, E> {
+            Self::in_range(i)
+        }
+
+        fn visit_u128<E: de::Error>(self, u: u128) -> Result<Option<u16>, E> {
+            Self::in_range(u)
+        }
+
+        fn visit_i128<E: de::Error>(self, i: i128) -> Result<Option<u16>, E> {
+            Self::in_range(i)
+        }
+
+        fn visit_f64<E: de::Error>(self, f: f64) -> Result<Option<u16>, E> {
+            Err(E::custom(format!("invalid client id '{}'", f)))
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Option<u16>, E> {
+            Ok(None)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Option<u16>, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: de::Deserializer<'de>>(self, d: D) -> Result<Option<u16>, D::Error> {
+            d.deserialize_any(self)
+        }
+    }
+
+    deserializer.deserialize_any(ClientIdVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn parse_amount(amount: &str) -> Result<Transaction, csv::Error> {
+        let data = format!("type, client, tx, amount\ndeposit, 1, 1, {}\n", amount);
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+
+        reader.deserialize().next().unwrap()
+    }
+
+    #[test]
+    fn non_finite_amounts_rejected() {
+        for amount in ["NaN", "inf", "-inf", "1e400"] {
+            let err = parse_amount(amount).unwrap_err();
+
+            assert!(
+                err.to_string().contains("not a finite number"),
+                "{}",
+                amount
+            );
+        }
+    }
+
+    #[test]
+    fn gigantic_amounts_rejected() {
+        // too long for the ledger. the shorter ones read as 128-bit integers or finite floats
+        let whole = "9".repeat(25);
+        let negative_whole = format!("-{}", whole);
+        let huge = "9".repeat(400);
+        let negative = format!("-{}", huge);
+        let zeros = format!("{}1.5", "0".repeat(400));
+
+        for amount in [whole.as_str(), &negative_whole, "1e25"] {
+            let err = parse_amount(amount).unwrap_err();
+
+            assert!(err.to_string().contains("out of range"), "{}", amount);
+        }
+        for amount in [huge.as_str(), &negative] {
+            let err = parse_amount(amount).unwrap_err();
+
+            assert!(
+                err.to_string().contains("not a finite number"),
+                "{}",
+                amount
+            );
+        }
+        assert_eq!(parse_amount(&zeros).unwrap().amount, amount("1.5"));
+
+        // as long as the amount is sane, a long number elsewhere in the row is fine
+        let data = format!(
+            "type, client, tx, amount, reference
+deposit, 1, 1, 2.5, {}
+",
+            whole
+        );
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let transaction: Transaction = reader.deserialize().next().unwrap().unwrap();
+
+        assert_eq!(transaction.amount, amount("2.5"));
+        assert_eq!(transaction.metadata["reference"], whole);
+    }
+
+    #[test]
+    fn constructors_match_parsed_rows() {
+        let rows = [
+            (
+                "deposit, 1, 1, 2.5,",
+                Transaction::deposit(1, 1, amount("2.5")),
+            ),
+            (
+                "withdrawal, 2, 3, 1.0,",
+                Transaction::withdrawal(2, 3, amount("1.0")),
+            ),
+            ("dispute, 1, 1,,", Transaction::dispute(1, 1)),
+            ("resolve, 1, 1,,", Transaction::resolve(1, 1)),
+            ("chargeback, 1, 1,,", Transaction::chargeback(1, 1)),
+        ];
+
+        for (row, built) in rows {
+            assert_eq!(built.status, TransactionStatus::Open);
+            assert_eq!(built, transaction(row), "{}", row);
+        }
+    }
+
+    #[test]
+    fn finite_and_blank_amounts_parse() {
+        assert_eq!(parse_amount("1.5").unwrap().amount, amount("1.5"));
+        assert_eq!(parse_amount("0.1").unwrap().amount, amount("0.1"));
+        assert_eq!(parse_amount("").unwrap().amount, Amount::ZERO);
+    }
+
+    #[test]
+    fn long_amounts_read_exactly() {
+        // more significant digits than a float keeps
+        for text in ["1234567890.12345678", "90000000000.00000001"] {
+            let transaction = parse_amount(text).unwrap();
+            let config = EngineConfig {
+                precision: 8,
+                ..EngineConfig::default()
+            };
+            let state = processor::process_transaction(State::with_config(config), transaction);
+
+            assert_eq!(state.clients[&1].available.to_string(), text);
+        }
+
+        let json = br#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "90000000000.00000001"},
+            {"type": "deposit", "client": 1, "tx": 2, "amount": 2.5},
+            {"type": "dispute", "client": 1, "tx": 1, "amount": null},
+            {"type": "deposit", "client": 1, "tx": 3, "amount": 1234567890.12345678}
+        ]"#;
+        let transactions = transactions_from_json(json).unwrap();
+
+        assert_eq!(transactions[0].amount, amount("90000000000.00000001"));
+        assert_eq!(transactions[1].amount, amount("2.5"));
+        assert_eq!(transactions[2].amount, Amount::ZERO);
+        assert_eq!(transactions[3].amount, amount("1234567890.12345678"));
+    }
+
+    #[test]
+    fn malformed_amounts_fail_except_on_admin_rows() {
+        let err = parse_amount("abc").unwrap_err();
+
+        assert!(err.to_string().contains("invalid amount 'abc'"));
+        assert_eq!(err.position().unwrap().line(), 2);
+
+        let data = "type, client, tx, amount\n\
+                    dispute, 1, 1, abc\n\
+                    freeze, 1, 2, abc\n\
+                    resolve, 1, 1,\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let rows: Vec<_> = reader.deserialize::<Transaction>().collect();
+
+        assert!(rows[0].is_err());
+        assert_eq!(rows[1].as_ref().unwrap().amount, Amount::ZERO);
+        assert_eq!(rows[2].as_ref().unwrap().amount, Amount::ZERO);
+    }
+
+    #[test]
+    fn extra_columns_captured_as_metadata() {
+        let data = "type, client, tx, amount, timestamp, source, memo\n\
+                    deposit, 1, 1, 2.5, , bank-feed, 007\n\
+                    withdrawal, 1, 2, 1.0, 1700000000, atm, 1.50\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let transactions: Vec<Transaction> = reader.deserialize().map(|r| r.unwrap()).collect();
+
+        assert_eq!(transactions[0].metadata["source"], "bank-feed");
+        assert_eq!(transactions[0].metadata["memo"], "007");
+        assert_eq!(transactions[0].timestamp, None);
+        assert_eq!(transactions[1].metadata["memo"], "1.50");
+        assert_eq!(transactions[1].timestamp, Some(1700000000));
+        assert_eq!(transactions[1].metadata.len(), 2);
+
+        let state = transactions
+            .into_iter()
+            .fold(State::new(), crate::processor::process_transaction);
+        let client = &state.clients[&1];
+
+        assert_eq!(client.available, amount("1.5"));
+        assert_eq!(client.total, amount("1.5"));
+
+        let json = br#"[{"type": "deposit", "client": 1, "tx": 1, "amount": 1, "memo": 7}]"#;
+        let transactions = transactions_from_json(json).unwrap();
+
+        assert_eq!(transactions[0].metadata["memo"], "7");
+    }
+
+    #[test]
+    fn transaction_types_read_in_any_case() {
+        let data = "type, client, tx, amount\nDeposit, 1, 1, 1.0\nWITHDRAWAL, 1, 2, 1.0\n\
+                    UnFreeze, 1, 3,\nfoo, 1, 4, 1.0\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let rows: Vec<Result<Transaction, csv::Error>> = reader.deserialize().collect();
+
+        let types: Vec<TransactionType> = rows[..3]
+            .iter()
+            .map(|row| row.as_ref().unwrap().transaction_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Thaw
+            ]
+        );
+        assert!(rows[3]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("unknown transaction type 'foo'"));
+    }
+
+    #[test]
+    fn unfreeze_reads_as_thaw() {
+        let data = "type, client, tx, amount\nfreeze, 1, 1,\nunfreeze, 1, 2,\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let types: Vec<TransactionType> = reader
+            .deserialize()
+            .map(|r: Result<Transaction, csv::Error>| r.unwrap().transaction_type)
+            .collect();
+
+        assert_eq!(types, vec![TransactionType::Freeze, TransactionType::Thaw]);
+    }
+
+    #[test]
+    fn blank_client_is_none() {
+        let data = "type, client, tx, amount\ndispute, , 1,\ndispute, 0, 1,\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes());
+        let clients: Vec<Option<u16>> = reader
+            .deserialize()
+            .map(|r: Result<Transaction, csv::Error>| r.unwrap().client_id)
+            .collect();
+
+        assert_eq!(clients, vec![None, Some(0)]);
+    }
+
+    #[test]
+    fn warnings_kept_after_recent_rejects_drop_them() {
+        let mut state = State::with_recent_rejects_capacity(1);
+        state.record_reject(4, RejectReason::DuplicateTransaction);
+        state.record_reject(9, RejectReason::HeldUnderflow);
+        state.record_reject(2, RejectReason::InsufficientFunds);
+
+        assert_eq!(state.recent_rejects().count(), 1);
+        assert_eq!(
+            state.warnings().collect::<Vec<_>>(),
+            vec![
+                "skipped transaction 4: transaction id already used",
+                "skipped transaction 9: held funds are less than the referenced amount",
+            ]
+        );
+    }
+
+    #[test]
+    fn default_config_is_strict() {
+        let config = EngineConfig::default();
+
+        assert!(!config.escrow_locked_deposits);
+        assert!(!config.deposits_allowed_when_locked);
+        assert!(!config.allow_admin_transactions);
+        assert_eq!(config.precision, DEFAULT_PRECISION);
+        assert_eq!(config.excess_decimals, ExcessDecimals::Round);
+        assert!(!config.check_dispute_amount);
+        assert_eq!(config.missing_client, MissingClientPolicy::Reject);
+        assert_eq!(config.only_clients, None);
+        assert_eq!(config.max_tx_per_client, None);
+        assert!(!config.defer_early_disputes);
+        assert!(!config.streaming);
+        assert!(!config.check_timestamp_order);
+        assert_eq!(config.max_clients, None);
+        assert_eq!(State::new().config, config);
+    }
+
+    #[test]
+    fn state_processes_with_its_config() {
+        let config = EngineConfig {
+            allow_admin_transactions: true,
+            ..EngineConfig::default()
+        };
+        let mut strict = State::new();
+        let mut admin = State::with_config(config);
+
+        for state in [&mut strict, &mut admin] {
+            state.apply(transaction("deposit, 1, 1, 1.0,"));
+            state.apply(transaction("freeze, 1, 2,,"));
+        }
+
+        assert!(!strict.clients[&1].locked);
+        assert!(admin.clients[&1].locked);
+        assert!(admin.empty_like().config.allow_admin_transactions);
+    }
+
+    fn transaction(row: &str) -> Transaction {
+        let data = format!("type, client, tx, amount, dest\n{}\n", row);
+        ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    // client 1 holds 10, client 2 has its deposit disputed, client 3 was charged back and
+    // locked, and tx 4 is a transfer from client 1 to client 2
+    fn applied_history() -> State {
+        let mut state = State::new();
+        for row in [
+            "deposit, 1, 1, 11.0,",
+            "deposit, 2, 2, 5.0,",
+            "dispute, 2, 2,,",
+            "deposit, 3, 3, 4.0,",
+            "dispute, 3, 3,,",
+            "chargeback, 3, 3,,",
+            "transfer, 1, 4, 1.0, 2",
+        ] {
+            assert!(matches!(
+                state.apply(transaction(row)),
+                ApplyOutcome::Applied(_)
+            ));
+        }
+
+        state
+    }
+
+    #[test]
+    fn apply_reports_each_rejection() {
+        // a change to the state before the row, the row, and why it is rejected
+        type Case = (fn(&mut State), &'static str, RejectReason);
+        fn unchanged(_: &mut State) {}
+
+        let cases: Vec<Case> = vec![
+            (
+                unchanged,
+                "deposit, 1, 1, 11.0,",
+                RejectReason::DuplicateTransaction,
+            ),
+            (
+                unchanged,
+                "withdrawal, 9, 10, 1.0,",
+                RejectReason::UnknownClient,
+            ),
+            (
+                unchanged,
+                "dispute, 1, 99,,",
+                RejectReason::UnknownTransaction,
+            ),
+            (
+                unchanged,
+                "deposit, 3, 11, 1.0,",
+                RejectReason::ClientLocked,
+            ),
+            (
+                unchanged,
+                "withdrawal, 1, 12, 100.0,",
+                RejectReason::InsufficientFunds,
+            ),
+            (unchanged, "dispute, 1, 2,,", RejectReason::ClientMismatch),
+            (unchanged, "dispute, 2, 2,,", RejectReason::AlreadyDisputed),
+            (unchanged, "resolve, 1, 1,,", RejectReason::NotDisputed),
+            (unchanged, "freeze, 1, 13,,", RejectReason::AdminNotAllowed),
+            (
+                |s| s.config.check_dispute_amount = true,
+                "dispute, 1, 1, 3.0,",
+                RejectReason::AmountMismatch,
+            ),
+            (
+                |s| s.config.max_tx_per_client = Some(0),
+                "deposit, 1, 14, 1.0,",
+                RejectReason::RateLimited,
+            ),
+            (
+                unchanged,
+                "deposit, , 15, 1.0,",
+                RejectReason::MissingClient,
+            ),
+            (
+                unchanged,
+                "deposit, 1, 16, -1.0,",
+                RejectReason::NegativeAmount,
+            ),
+            (
+                |s| s.clients.get_mut(&2).unwrap().held = Amount::ZERO,
+                "resolve, 2, 2,,",
+                RejectReason::HeldUnderflow,
+            ),
+            (unchanged, "dispute, 3, 3,,", RejectReason::ChargedBack),
+            (
+                unchanged,
+                "resolve, 2, 2, 5.0,",
+                RejectReason::UnexpectedAmount,
+            ),
+            (
+                |s| {
+                    s.clients.remove(&1);
+                },
+                "dispute, 1, 1,,",
+                RejectReason::MissingOwner,
+            ),
+            (
+                unchanged,
+                "transfer, 1, 17, 1.0,",
+                RejectReason::MissingDestination,
+            ),
+            (
+                unchanged,
+                "transfer, 1, 19, 1.0, 1",
+                RejectReason::SelfTransfer,
+            ),
+            (unchanged, "dispute, 1, 4,,", RejectReason::NotDisputable),
+            (
+                |s| s.config.excess_decimals = ExcessDecimals::Reject,
+                "deposit, 1, 18, 1.00001,",
+                RejectReason::TooManyDecimals,
+            ),
+            (
+                unchanged,
+                "deposit, 1, 1, 1.0,",
+                RejectReason::ConflictingTransaction,
+            ),
+        ];
+
+        for (setup, row, reason) in cases {
+            let mut state = applied_history();
+            setup(&mut state);
+            let clients = state.clients.clone();
+
+            assert_eq!(
+                state.apply(transaction(row)),
+                ApplyOutcome::Rejected(reason),
+                "{}",
+                row
+            );
+            assert_eq!(state.clients, clients, "{}", row);
+        }
+    }
+
+    #[test]
+    fn apply_reports_the_updated_client() {
+        let mut state = applied_history();
+
+        let mut expected = state.clients[&1].clone();
+        expected.available -= amount("2.5");
+        expected.total -= amount("2.5");
+        expected.max_withdrawal = amount("2.5");
+        expected.record_activity(20);
+        assert_eq!(
+            state.apply(transaction("withdrawal, 1, 20, 2.5,")),
+            ApplyOutcome::Applied(expected)
+        );
+
+        state.config.missing_client = MissingClientPolicy::MatchOwner;
+        match state.apply(transaction("resolve, , 2,,")) {
+            ApplyOutcome::Applied(client) => assert_eq!(client.id, 2),
+            outcome => panic!("resolve was not applied: {:?}", outcome),
+        }
+
+        state.config.defer_early_disputes = true;
+        assert_eq!(
+            state.apply(transaction("dispute, 1, 30,,")),
+            ApplyOutcome::Deferred
+        );
+
+        state.config.only_clients = Some(HashSet::from([1, 2]));
+        assert_eq!(
+            state.apply(transaction("deposit, 5, 31, 1.0,")),
+            ApplyOutcome::Filtered
+        );
+    }
+
+    #[test]
+    fn aggregate_sums_every_client() {
+        assert_eq!(State::new().aggregate(), Aggregate::default());
+
+        // ten deposits of 0.1 and two of 0.2, which float sums wouldn't add up exactly
+        let mut transactions: Vec<Transaction> = (1..=10)
+            .map(|id| Transaction::deposit(id as u16 % 3 + 1, id, amount("0.1")))
+            .collect();
+        transactions.extend([
+            Transaction::deposit(4, 11, amount("0.2")),
+            Transaction::deposit(4, 12, amount("0.2")),
+            Transaction::withdrawal(1, 13, amount("0.1")),
+            Transaction::dispute(3, 2),
+            Transaction::dispute(4, 11),
+            Transaction::chargeback(4, 11),
+        ]);
+        let state = processor::process_batch(State::new(), transactions);
+
+        let aggregate = state.aggregate();
+        assert_eq!(
+            aggregate,
+            Aggregate {
+                total: amount("1.1"),
+                available: amount("1.0"),
+                held: amount("0.1"),
+                client_count: 4,
+                locked_count: 1,
+            }
+        );
+        assert_eq!(
+            aggregate.to_string(),
+            "4 clients, 1 locked: available 1.0, held 0.1, total 1.1"
+        );
+    }
+
+    #[test]
+    fn broken_balances_reported_by_verify_invariants() {
+        let mut state = State::new();
+        state = processor::process_transaction(state, Transaction::deposit(1, 1, amount("5.0")));
+        state = processor::process_transaction(state, Transaction::deposit(2, 2, amount("3.0")));
+        state = processor::process_transaction(state, Transaction::dispute(2, 2));
+
+        assert_eq!(state.verify_invariants(), Ok(()));
+
+        state.clients.get_mut(&1).unwrap().total = amount("4.0");
+        state.clients.get_mut(&2).unwrap().held = amount("-1.0");
+
+        let violations = state.verify_invariants().unwrap_err();
+        assert_eq!(
+            violations,
+            vec![
+                InvariantViolation::TotalMismatch {
+                    client_id: 1,
+                    available: amount("5.0"),
+                    held: Amount::ZERO,
+                    total: amount("4.0"),
+                },
+                InvariantViolation::TotalMismatch {
+                    client_id: 2,
+                    available: Amount::ZERO,
+                    held: amount("-1.0"),
+                    total: amount("3.0"),
+                },
+                InvariantViolation::NegativeHeld {
+                    client_id: 2,
+                    held: amount("-1.0"),
+                },
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "client 1: available 5.0 plus held 0.0 is not total 4.0"
+        );
+    }
+
+    #[test]
+    fn ledger_shows_dispute_status() {
+        let state = [
+            Transaction::deposit(1, 1, amount("10.0")),
+            Transaction::deposit(2, 2, amount("5.0")),
+            Transaction::dispute(1, 1),
+        ]
+        .into_iter()
+        .fold(State::new(), processor::process_transaction);
+
+        let mut ledger = Vec::new();
+        state.write_ledger(&mut ledger).unwrap();
+
+        assert_eq!(
+            String::from_utf8(ledger).unwrap(),
+            "type,client,tx,amount,status,timestamp,dest\n\
+             deposit,1,1,10.0,disputed,,\n\
+             deposit,2,2,5.0,open,,\n"
+        );
+    }
+
+    #[test]
+    fn diff_with_only_held_changed() {
+        let before =
+            processor::process_transaction(State::new(), Transaction::deposit(1, 1, amount("5.0")));
+        let mut after =
+            processor::process_transaction(State::new(), Transaction::deposit(1, 1, amount("5.0")));
+        after.clients.get_mut(&1).unwrap().held = amount("1.5");
+
+        assert_eq!(
+            before.diff(&after),
+            vec![ClientDiff {
+                client_id: 1,
+                fields: vec![FieldDiff {
+                    field: "held",
+                    ours: String::from("0.0"),
+                    theirs: String::from("1.5"),
+                }],
+            }]
+        );
+        assert_eq!(
+            after.diff(&before)[0].to_string(),
+            "client 1: held 1.5 -> 0.0"
+        );
+
+        // a client missing from one side reads as new, with no last transaction
+        let empty = State::new();
+        assert_eq!(
+            empty.diff(&before)[0].to_string(),
+            "client 1: available 0.0 -> 5.0, total 0.0 -> 5.0, peak_total 0.0 -> 5.0, \
+             transaction_count 0 -> 1, last_tx_id none -> 1"
+        );
     }
 }
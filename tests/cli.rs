@@ -0,0 +1,69 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+// runs the tool with `input` piped to its stdin
+fn run_with_input(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transaction_tool"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // dropped once written, so the tool sees the end of its input
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?} failed", args);
+
+    output
+}
+
+fn run(args: &[&str]) -> Output {
+    run_with_input(args, b"")
+}
+
+#[test]
+fn progress_stays_out_of_stdout() {
+    let plain = run(&["test-data/test.csv"]);
+    let with_progress = run(&["test-data/test.csv", "--progress"]);
+
+    assert_eq!(with_progress.stdout, plain.stdout);
+    assert!(plain.stderr.is_empty());
+    assert_eq!(
+        String::from_utf8(with_progress.stderr).unwrap(),
+        "processed 10 rows\n"
+    );
+}
+
+#[test]
+fn stats_summarize_the_run_on_stderr() {
+    let plain = run(&["test-data/test.csv"]);
+    let with_stats = run(&["test-data/test.csv", "--stats"]);
+
+    assert_eq!(with_stats.stdout, plain.stdout);
+    assert_eq!(
+        String::from_utf8(with_stats.stderr).unwrap(),
+        "10 rows: 3 deposits, 1 withdrawals, 0 transfers, 2 disputes, 1 resolves, \
+         1 chargebacks and 0 admin rows applied, 2 skipped. 2 clients, 1 locked\n"
+    );
+}
+
+#[test]
+fn piped_input_matches_file_input() {
+    let input = fs::read("test-data/test.csv").unwrap();
+    let from_file = run(&["test-data/test.csv"]);
+
+    // `-` names stdin, and with no path at all input that isn't a terminal is read from it
+    let args: [&[&str]; 2] = [&["-"], &[]];
+    for args in args {
+        let piped = run_with_input(args, &input);
+
+        assert_eq!(piped.stdout, from_file.stdout, "{:?}", args);
+        assert!(piped.stderr.is_empty());
+    }
+}
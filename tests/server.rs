@@ -0,0 +1,121 @@
+#![cfg(feature = "server")]
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+use serde_json::Value;
+
+// kills the server when the test ends, whether it passed or not
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server() -> (ServerProcess, String) {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = format!("127.0.0.1:{}", port);
+
+    let child = Command::new(env!("CARGO_BIN_EXE_transaction_tool"))
+        .args(["--serve", &addr])
+        .spawn()
+        .unwrap();
+    let server = ServerProcess(child);
+
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            return (server, addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    panic!("server did not start on {}", addr);
+}
+
+fn post(addr: &str, content_type: &str, body: &str) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST /process HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        content_type,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn posted_transactions_update_report() {
+    let (_server, addr) = start_server();
+
+    let csv = "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 1.0\n";
+    let (status, report) = post(&addr, "text/csv", csv);
+
+    assert_eq!(status, 200);
+    assert_eq!(report["clients"][0]["client"], 1);
+    assert_eq!(report["clients"][0]["available"], 2.0);
+    assert_eq!(report["clients"][1]["total"], 1.0);
+    assert_eq!(report["skipped"], serde_json::json!([]));
+
+    // state carries over from the previous request
+    let json = r#"[{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.5}]"#;
+    let (status, report) = post(&addr, "application/json", json);
+
+    assert_eq!(status, 200);
+    assert_eq!(report["clients"][0]["available"], 1.5);
+    assert_eq!(report["clients"][0]["total"], 1.5);
+    assert_eq!(report["clients"][1]["total"], 1.0);
+
+    let (status, _) = post(&addr, "application/json", "not json");
+
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn unreadable_csv_rows_skipped_and_reported() {
+    let (_server, addr) = start_server();
+
+    // rows may be ragged, as on the command line, and a row that still can't be read is
+    // skipped rather than failing the request
+    let csv = "type, client, tx, amount\n\
+               deposit, 1, 1, 2.0, extra\n\
+               refund, 1, 2, 1.0\n\
+               deposit, 1, 3, 1.0\n\
+               dispute, 1, 3\n";
+    let (status, report) = post(&addr, "text/csv", csv);
+
+    assert_eq!(status, 200);
+    assert_eq!(report["clients"][0]["available"], 2.0);
+    assert_eq!(report["clients"][0]["held"], 1.0);
+
+    let skipped = report["skipped"].as_array().unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0]["location"], "line 3 of request");
+    assert_eq!(skipped[0]["reason"], "unknown transaction type 'refund'");
+
+    // a body without even a header row can't be read at all
+    let (status, _) = post(&addr, "text/csv", "");
+
+    assert_eq!(status, 400);
+}